@@ -1,106 +1,301 @@
-use ckb_zkp::math::{Field, FromBytes, PairingEngine, ToBytes, Zero};
+use ckb_zkp::math::{Field, PairingEngine, Zero};
 use ckb_zkp::scheme::asvc::{
     update_commit, verify_pos, Commitment, Proof, UpdateKey, VerificationKey,
 };
 use core::ops::{Add, Mul, Sub};
 
-use crate::transaction::{u128_to_fr, Transaction, TxType, ACCOUNT_SIZE};
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap;
+#[cfg(feature = "std")]
+use std::collections::BTreeMap;
+
+use crate::format::{Header, OP_UPKS};
+use crate::signature;
+use crate::transaction::{
+    position, u128_to_fr, vector_size, FullPubKey, Transaction, TxType, TOKEN_SIZE,
+};
 use crate::{vec, String, Vec};
 
 #[derive(Clone)]
 pub struct CellUpks<E: PairingEngine> {
     pub vk: VerificationKey<E>,
     pub omega: E::Fr,
+    /// max accounts of the deployment.
+    pub account_size: u32,
+    /// accounts of a shard, every shard is an aSVC vector with the same upks,
+    /// upks has a key for every token slot of a shard.
+    pub shard_accounts: u32,
     pub upks: Vec<UpdateKey<E>>,
+    /// UDT type hashes of the vault cells, the index is the token id.
+    pub tokens: Vec<[u8; 32]>,
 }
 
 impl<E: PairingEngine> CellUpks<E> {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        self.vk.write(&mut bytes).unwrap();
-        self.omega.write(&mut bytes).unwrap();
-        (self.upks.len() as u32).write(&mut bytes).unwrap();
-        for u in &self.upks {
-            u.write(&mut bytes).unwrap();
-        }
-        bytes
+    /// the size of all shards' vectors.
+    pub fn vector_size(&self) -> usize {
+        vector_size(self.account_size as usize)
     }
 
-    pub fn from_bytes(mut s: &[u8]) -> Result<Self, ()> {
-        let vk = VerificationKey::read(&mut s).map_err(|_| ())?;
-        let omega = E::Fr::read(&mut s).map_err(|_| ())?;
+    /// the size of a shard's aSVC vector.
+    pub fn shard_size(&self) -> usize {
+        vector_size(self.shard_accounts as usize)
+    }
+
+    /// the number of shards.
+    pub fn shards(&self) -> usize {
+        (self.account_size / self.shard_accounts) as usize
+    }
 
-        let n = u32::read(&mut s).map_err(|_| ())?;
-        let mut upks = Vec::new();
-        for _ in 0..n {
-            upks.push(UpdateKey::read(&mut s).map_err(|_| ())?);
+    /// the shard and the position in the shard of a position.
+    pub fn locate(&self, p: u32) -> (u32, u32) {
+        let n = self.shard_size() as u32;
+        (p / n, p % n)
+    }
+
+    /// check the shards are well-formed.
+    pub fn check(&self) -> Result<(), String> {
+        let n = self.shard_size();
+        if self.shard_accounts == 0
+            || self.account_size % self.shard_accounts != 0
+            || !n.is_power_of_two()
+            || self.upks.len() != n
+        {
+            return Err(String::from("BLOCK_VERIFY: Upk length"));
         }
+        Ok(())
+    }
 
-        Ok(Self { vk, omega, upks })
+    /// the upk cell's data, with the versioned header.
+    pub fn to_cell_data(&self) -> Vec<u8> {
+        Header::new::<E>(self.account_size, OP_UPKS).seal(&self.to_packed())
+    }
+
+    /// read from the upk cell's data, reject incompatible header.
+    pub fn from_cell_data(data: &[u8]) -> Result<Self, ()> {
+        let (header, payload) = Header::open(data)?;
+        header.check::<E>()?;
+        if header.op != OP_UPKS {
+            return Err(());
+        }
+        let cell_upks = Self::from_packed(payload)?;
+        if header.account_size != cell_upks.account_size {
+            return Err(());
+        }
+        Ok(cell_upks)
     }
 }
 
+/// The commit cell's data, the block body is in the witness.
 #[derive(Clone, Eq, PartialEq)]
-pub struct Block<E: PairingEngine> {
+pub struct CellCommit<E: PairingEngine> {
     pub block_height: u32,
+    /// every shard's commitment, the index is the shard id.
+    pub commits: Vec<Commitment<E>>,
+    /// registered users count.
+    pub user_count: u32,
+}
+
+impl<E: PairingEngine> CellCommit<E> {
+    /// the commit cell's data, with the versioned header of the account size and the op.
+    pub fn to_cell_data(&self, account_size: u32, op: u8) -> Vec<u8> {
+        Header::new::<E>(account_size, op).seal(&self.to_packed())
+    }
+
+    /// read from the commit cell's data, reject incompatible header and another account size.
+    /// returns the op and the commit.
+    pub fn from_cell_data(data: &[u8], account_size: u32) -> Result<(u8, Self), ()> {
+        let (header, payload) = Header::open(data)?;
+        header.check::<E>()?;
+        if header.op == OP_UPKS || header.account_size != account_size {
+            return Err(());
+        }
+        Ok((header.op, Self::from_packed(payload)?))
+    }
+}
+
+/// A shard changed by the block.
+#[derive(Clone, Eq, PartialEq)]
+pub struct BlockShard<E: PairingEngine> {
+    pub shard: u32,
     pub commit: Commitment<E>,
-    pub proof: Proof<E>,
     pub new_commit: Commitment<E>,
+    /// aggregated proof of the shard's positions to prove,
+    /// none when the shard is only credited.
+    pub proof: Option<Proof<E>>,
+}
+
+#[derive(Clone, Eq, PartialEq)]
+pub struct Block<E: PairingEngine> {
+    pub block_height: u32,
+    /// the operator's account, receives the fees of the block's transfers.
+    pub fee_recipient: u32,
+    /// the shards changed by the block, in shard order.
+    pub shards: Vec<BlockShard<E>>,
     pub txs: Vec<Transaction<E>>,
+    /// optional BLS aggregate sign of all txs, when it is some, txs' sign is empty.
+    pub aggregate_sign: Option<E::G2Affine>,
 }
 
 impl<E: PairingEngine> Block<E> {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        self.block_height.write(&mut bytes).unwrap();
-        self.commit.write(&mut bytes).unwrap();
-        self.new_commit.write(&mut bytes).unwrap();
-        self.proof.write(&mut bytes).unwrap();
-        (self.txs.len() as u32).write(&mut bytes).unwrap();
-        for tx in &self.txs {
-            tx.write(&mut bytes).unwrap();
-        }
-        bytes
+    /// the block body in the L1 tx's witness, with the versioned header of the account size
+    /// and the op.
+    pub fn to_witness(&self, account_size: u32, op: u8) -> Vec<u8> {
+        Header::new::<E>(account_size, op).seal(&self.to_packed())
     }
 
-    pub fn from_bytes(mut s: &[u8]) -> Result<Self, ()> {
-        let block_height = u32::read(&mut s).map_err(|_| ())?;
+    /// read from the witness, reject incompatible header and another account size.
+    /// returns the op and the block.
+    pub fn from_witness(data: &[u8], account_size: u32) -> Result<(u8, Self), ()> {
+        let (header, payload) = Header::open(data)?;
+        header.check::<E>()?;
+        if header.op == OP_UPKS || header.account_size != account_size {
+            return Err(());
+        }
+        Ok((header.op, Self::from_packed(payload)?))
+    }
 
-        let commit = Commitment::read(&mut s).map_err(|_| ())?;
-        let new_commit = Commitment::read(&mut s).map_err(|_| ())?;
-        let proof = Proof::read(&mut s).map_err(|_| ())?;
-        let n = u32::read(&mut s).map_err(|_| ())?;
-        let mut txs = Vec::new();
-        for _ in 0..n {
-            txs.push(Transaction::read(&mut s).map_err(|_| ())?);
+    /// the commit cell after this block, the block's shards are updated,
+    /// and the user count is increased by the registers.
+    /// a shard not in the pre commit cell is an error.
+    pub fn cell_commit(&self, pre: &CellCommit<E>) -> Result<CellCommit<E>, String> {
+        let registers = self
+            .txs
+            .iter()
+            .filter(|tx| match tx.tx_type {
+                TxType::Register(..) => true,
+                _ => false,
+            })
+            .count() as u32;
+
+        let mut commits = pre.commits.clone();
+        for shard in &self.shards {
+            let commit = commits
+                .get_mut(shard.shard as usize)
+                .ok_or(String::from("BLOCK_COMMIT: shard invalid"))?;
+            *commit = shard.new_commit.clone();
         }
 
-        Ok(Self {
-            block_height,
-            commit,
-            new_commit,
-            proof,
-            txs,
+        Ok(CellCommit {
+            block_height: self.block_height,
+            commits,
+            user_count: pre.user_count + registers,
         })
     }
 
     pub fn to_hex(&self) -> String {
-        hex::encode(self.to_bytes())
+        hex::encode(self.to_packed())
     }
 
     pub fn from_hex(s: &str) -> Result<Self, ()> {
         let v: Vec<u8> = hex::decode(s).map_err(|_| ())?;
-        Self::from_bytes(&v[..])
+        Self::from_packed(&v[..])
     }
 
     /// Traverse the transactions in the block, and examine the validity of each transaction.
     ///
-    /// If success, returns a tuple containing the (income, outcome) of all the transactions.
+    /// If success, returns the (income, outcome) of all the transactions for each token.
     /// Income only comes from deposit transactions, while outcome only comes from withdraw transactions.
-    /// The income and outcome reflect the capital change of UDT pool.
-    pub fn verify(&self, cell_upks: &CellUpks<E>) -> Result<(u128, u128), String> {
-        if cell_upks.upks.len() != ACCOUNT_SIZE {
-            return Err(String::from("BLOCK_VERIFY: Upk length"));
+    /// The income and outcome reflect the capital change of the token's UDT pool.
+    pub fn verify(&self, cell_upks: &CellUpks<E>) -> Result<Vec<(u128, u128)>, String> {
+        cell_upks.check()?;
+        self.verify_signatures(cell_upks)?;
+        self.verify_state(cell_upks)
+    }
+
+    /// Aggregate all txs' BLS signs into the block's aggregate_sign, and clear them.
+    pub fn aggregate_signatures(&mut self) -> Result<(), String> {
+        let signs: Vec<Vec<u8>> = self.txs.iter().map(|tx| tx.sign.clone()).collect();
+        let aggregate_sign = signature::bls_aggregate::<E>(&signs)
+            .ok_or(String::from("BLOCK_AGGREGATE: invalid bls signature"))?;
+
+        for tx in self.txs.iter_mut() {
+            tx.sign = Vec::new();
+        }
+        self.aggregate_sign = Some(aggregate_sign);
+
+        Ok(())
+    }
+
+    /// Check every transaction is signed by the pubkey bound into its addr.
+    ///
+    /// addr = H(i, upk_i, pk_i), the upk_i (of token 0) is from the cell_upks, so a tx can not
+    /// use other pubkey to sign for account i.
+    /// If the block has aggregate sign, all signs are checked in a single multi-pairing.
+    pub fn verify_signatures(&self, cell_upks: &CellUpks<E>) -> Result<(), String> {
+        // locate needs a non-empty shard.
+        cell_upks.check()?;
+        for tx in &self.txs {
+            let i = tx.from();
+            if i >= cell_upks.account_size {
+                return Err(String::from("BLOCK_VERIFY: account invalid"));
+            }
+            // the account's addr is bound to its token 0 slot, all shards use the same upks.
+            let (_, p) = cell_upks.locate(position(i, 0));
+
+            let update_key = cell_upks
+                .upks
+                .get(p as usize)
+                .ok_or(String::from("BLOCK_VERIFY: Upk length"))?;
+
+            let fpk = FullPubKey {
+                i,
+                update_key: update_key.clone(),
+                tradition_pubkey: tx.pubkey.clone(),
+            };
+            if fpk.addr() != tx.addr {
+                return Err(String::from("BLOCK_VERIFY: pubkey not match addr"));
+            }
+
+            if self.aggregate_sign.is_none() && !tx.verify() {
+                return Err(String::from("BLOCK_VERIFY: signature invalid"));
+            }
+        }
+
+        if let Some(aggregate_sign) = &self.aggregate_sign {
+            let mut pks_msgs = Vec::new();
+            for tx in &self.txs {
+                if !tx.sign.is_empty() {
+                    return Err(String::from("BLOCK_VERIFY: sign in aggregated block"));
+                }
+                pks_msgs.push((tx.pubkey.clone(), tx.message()));
+            }
+
+            if !signature::bls_verify_aggregate::<E>(&pks_msgs, aggregate_sign) {
+                return Err(String::from("BLOCK_VERIFY: aggregate signature invalid"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the balances, nonces and the position proof of the block, without signatures.
+    pub fn verify_state(&self, cell_upks: &CellUpks<E>) -> Result<Vec<(u128, u128)>, String> {
+        cell_upks.check()?;
+        if cell_upks.tokens.len() != TOKEN_SIZE {
+            return Err(String::from("BLOCK_VERIFY: tokens length"));
+        }
+        for tx in &self.txs {
+            if tx.token() as usize >= TOKEN_SIZE {
+                return Err(String::from("BLOCK_VERIFY: token invalid"));
+            }
+            let accounts = match tx.tx_type {
+                TxType::Transfer(from, to, ..) => [from, to],
+                _ => [tx.from(), tx.from()],
+            };
+            if accounts.iter().any(|a| *a >= cell_upks.account_size) {
+                return Err(String::from("BLOCK_VERIFY: account invalid"));
+            }
+        }
+        if self.fee_recipient >= cell_upks.account_size {
+            return Err(String::from("BLOCK_VERIFY: fee recipient invalid"));
+        }
+        // the block's shards are in order, so no duplicate.
+        for (i, shard) in self.shards.iter().enumerate() {
+            if shard.shard as usize >= cell_upks.shards()
+                || (i > 0 && self.shards[i - 1].shard >= shard.shard)
+            {
+                return Err(String::from("BLOCK_VERIFY: shard invalid"));
+            }
         }
 
         let mut points2prove: Vec<u32> = Vec::new();
@@ -111,8 +306,14 @@ impl<E: PairingEngine> Block<E> {
         // - A specific account's balance should remain identical.
         // - While an account become a transfer-from, his up-to-that-tx balance should be verified suffient.
         // - In one transfer, a fransfer-to account's nonce remain unchanged.
+        // - Every deposit, withdraw and transfer adds one to its account's nonce, so a signed
+        //   tx can't be replayed.
         // - L2 block height should be strictly incremental by one.
         // - Each account only need to execute proof-verifying once.
+        // - Every token of an account is a slot in the vector, the table only has the touched slots.
+        // - Only the shards with positions to prove are verified.
+        // - A transfer's fee is debited from the transfer-from, and credited to the fee recipient
+        //   like a transfer-to.
 
         // map[point](0: delta, 1: balance_change, 2: Option<proof_param>,
         //            3: current_nonce, 4: origin_balance)
@@ -125,47 +326,65 @@ impl<E: PairingEngine> Block<E> {
             pub outcome: u128,
             /// original point value for proving, only calculated once.
             pub point_value: Option<E::Fr>,
+            /// the addr in the proven point value, the later txs of the slot must be of it.
+            pub addr: Option<E::Fr>,
             /// nonce of the current transaction.
             pub cur_nonce: u32,
             /// original balance for proving.
             pub balance: u128,
         }
-        let mut table: Vec<Tmp<E>> = vec![
-            Tmp {
-                delta: None,
-                income: 0,
-                outcome: 0,
-                point_value: None,
-                cur_nonce: 0,
-                balance: 0
-            };
-            ACCOUNT_SIZE
-        ];
+        let empty = Tmp {
+            delta: None,
+            income: 0,
+            outcome: 0,
+            point_value: None,
+            addr: None,
+            cur_nonce: 0,
+            balance: 0,
+        };
+        // the index of the position in the table.
+        let mut slots: BTreeMap<u32, usize> = BTreeMap::new();
+        let mut table: Vec<Tmp<E>> = Vec::new();
+        fn touch<T: Clone>(
+            slots: &mut BTreeMap<u32, usize>,
+            table: &mut Vec<T>,
+            empty: &T,
+            p: u32,
+        ) -> usize {
+            let len = table.len();
+            let i = *slots.entry(p).or_insert(len);
+            if i == len {
+                table.push(empty.clone());
+            }
+            i
+        }
 
         let mul160: E::Fr = E::Fr::from(2).pow(&[160]);
         let mul128: E::Fr = E::Fr::from(2).pow(&[128]);
 
         // aggregate the overall capital changing of the block
         // let mut overall_change: i128 = 0;
-        let mut incomes = 0_u128;
-        let mut outcomes = 0_u128;
+        let mut changes = vec![(0_u128, 0_u128); TOKEN_SIZE];
 
         #[cfg(feature = "std")]
         println!("starting dark magic...(off-chain block.verify)");
         for tx in &self.txs {
             match tx.tx_type {
                 // A block submitted by user only contains Deposit and Withdraw transactions.
-                TxType::Deposit(to, amount) => {
-                    incomes += amount;
+                TxType::Deposit(account, token, amount) => {
+                    changes[token as usize].0 += amount;
+                    let to = position(account, token);
+                    let ti = touch(&mut slots, &mut table, &empty, to);
 
-                    match table[to as usize].delta {
+                    match table[ti].delta {
                         None => {
                             points2prove.push(to);
-                            table[to as usize] = Tmp {
-                                delta: Some(E::Fr::zero().add(&u128_to_fr::<E>(amount))),
+                            table[ti] = Tmp {
+                                delta: Some(mul128.add(&u128_to_fr::<E>(amount))),
                                 income: amount,
                                 outcome: 0,
                                 point_value: Some(tx.point_value()),
+                                addr: Some(tx.addr),
                                 cur_nonce: tx.nonce,
                                 balance: tx.balance,
                             };
@@ -173,59 +392,123 @@ impl<E: PairingEngine> Block<E> {
                             #[cfg(feature = "std")]
                             println!("{} deposit for the 1st time!", to);
                         }
-                        Some(prev_delta) => {
-                            table[to as usize].delta =
-                                Some(prev_delta.add(&u128_to_fr::<E>(amount)));
-                            table[to as usize].income += amount;
+                        Some(prev_delta) => match table[ti].point_value {
+                            None => {
+                                points2prove.push(to);
+                                table[ti] = Tmp {
+                                    delta: Some(
+                                        prev_delta.add(&mul128).add(&u128_to_fr::<E>(amount)),
+                                    ),
+                                    income: table[ti].income + amount,
+                                    outcome: table[ti].outcome,
+                                    point_value: Some(tx.point_value()),
+                                    addr: Some(tx.addr),
+                                    cur_nonce: tx.nonce,
+                                    balance: tx.balance,
+                                };
 
-                            #[cfg(feature = "std")]
-                            println!("{} deposit again!", to);
-                        }
+                                #[cfg(feature = "std")]
+                                println!("{} has presented but deposit for the 1st time!", to);
+                            }
+                            Some(_) => {
+                                if table[ti].addr != Some(tx.addr) {
+                                    return Err(String::from("BLOCK_VERIFY: addr invalid"));
+                                }
+                                if tx.nonce - table[ti].cur_nonce != 1 {
+                                    return Err(String::from("BLOCK_VERIFY: nonce invalid"));
+                                }
+                                table[ti].delta =
+                                    Some(prev_delta.add(&mul128).add(&u128_to_fr::<E>(amount)));
+                                table[ti].income += amount;
+                                table[ti].cur_nonce = tx.nonce;
+
+                                #[cfg(feature = "std")]
+                                println!("{} deposit again!", to);
+                            }
+                        },
                     }
                 }
-                TxType::Withdraw(from, amount) => {
-                    outcomes += amount;
+                TxType::Withdraw(account, token, amount, _) => {
+                    changes[token as usize].1 += amount;
+                    let from = position(account, token);
+                    let fi = touch(&mut slots, &mut table, &empty, from);
 
-                    match table[from as usize].delta {
+                    match table[fi].delta {
                         None => {
                             points2prove.push(from);
-                            table[from as usize] = Tmp {
-                                delta: Some(E::Fr::zero().sub(&u128_to_fr::<E>(amount))),
+                            table[fi] = Tmp {
+                                delta: Some(mul128.sub(&u128_to_fr::<E>(amount))),
                                 income: 0,
                                 outcome: amount,
                                 point_value: Some(tx.point_value()),
+                                addr: Some(tx.addr),
                                 cur_nonce: tx.nonce,
                                 balance: tx.balance,
                             };
                             #[cfg(feature = "std")]
                             println!("{} withdraw for the 1st time!", from);
                         }
-                        Some(prev_delta) => {
-                            table[from as usize].delta =
-                                Some(prev_delta.sub(&u128_to_fr::<E>(amount)));
-                            table[from as usize].outcome += amount;
+                        Some(prev_delta) => match table[fi].point_value {
+                            None => {
+                                points2prove.push(from);
+                                table[fi] = Tmp {
+                                    delta: Some(
+                                        prev_delta.add(&mul128).sub(&u128_to_fr::<E>(amount)),
+                                    ),
+                                    income: table[fi].income,
+                                    outcome: table[fi].outcome + amount,
+                                    point_value: Some(tx.point_value()),
+                                    addr: Some(tx.addr),
+                                    cur_nonce: tx.nonce,
+                                    balance: tx.balance,
+                                };
 
-                            #[cfg(feature = "std")]
-                            println!("{} withdraw again!", from);
-                        }
+                                #[cfg(feature = "std")]
+                                println!("{} has presented but withdraw for the 1st time!", from);
+                            }
+                            Some(_) => {
+                                if table[fi].addr != Some(tx.addr) {
+                                    return Err(String::from("BLOCK_VERIFY: addr invalid"));
+                                }
+                                if tx.nonce - table[fi].cur_nonce != 1 {
+                                    return Err(String::from("BLOCK_VERIFY: nonce invalid"));
+                                }
+                                table[fi].delta =
+                                    Some(prev_delta.add(&mul128).sub(&u128_to_fr::<E>(amount)));
+                                table[fi].outcome += amount;
+                                table[fi].cur_nonce = tx.nonce;
+
+                                #[cfg(feature = "std")]
+                                println!("{} withdraw again!", from);
+                            }
+                        },
                     }
                     // balance sufficiency check
-                    if let None = (table[from as usize].balance + table[from as usize].income)
-                        .checked_sub(table[from as usize].outcome)
+                    if let None =
+                        (table[fi].balance + table[fi].income).checked_sub(table[fi].outcome)
                     {
                         return Err(String::from("BLOCK_VERIFY: balance invalid"));
                     }
                 }
                 // A block submitted by L2 service only contains Transfer and Register transactions.
-                TxType::Transfer(from, to, amount) => {
-                    match table[from as usize].delta {
+                TxType::Transfer(from_account, to_account, token, amount, fee) => {
+                    let from = position(from_account, token);
+                    let to = position(to_account, token);
+                    let fi = touch(&mut slots, &mut table, &empty, from);
+                    // the transfer-from pays the amount and the fee.
+                    let pay = amount
+                        .checked_add(fee)
+                        .ok_or(String::from("BLOCK_VERIFY: fee invalid"))?;
+
+                    match table[fi].delta {
                         None => {
                             points2prove.push(from);
-                            table[from as usize] = Tmp {
-                                delta: Some(mul128.sub(&u128_to_fr::<E>(amount))),
+                            table[fi] = Tmp {
+                                delta: Some(mul128.sub(&u128_to_fr::<E>(pay))),
                                 income: 0,
-                                outcome: amount,
+                                outcome: pay,
                                 point_value: Some(tx.point_value()),
+                                addr: Some(tx.addr),
                                 cur_nonce: tx.nonce,
                                 balance: tx.balance,
                             };
@@ -233,16 +516,15 @@ impl<E: PairingEngine> Block<E> {
                             #[cfg(feature = "std")]
                             println!("{} transfer-from for the 1st time!", from);
                         }
-                        Some(prev_delta) => match table[from as usize].point_value {
+                        Some(prev_delta) => match table[fi].point_value {
                             None => {
                                 points2prove.push(from);
-                                table[from as usize] = Tmp {
-                                    delta: Some(
-                                        prev_delta.add(&mul128).sub(&u128_to_fr::<E>(amount)),
-                                    ),
-                                    income: table[from as usize].income,
-                                    outcome: table[from as usize].outcome + amount,
+                                table[fi] = Tmp {
+                                    delta: Some(prev_delta.add(&mul128).sub(&u128_to_fr::<E>(pay))),
+                                    income: table[fi].income,
+                                    outcome: table[fi].outcome + pay,
                                     point_value: Some(tx.point_value()),
+                                    addr: Some(tx.addr),
                                     cur_nonce: tx.nonce,
                                     balance: tx.balance,
                                 };
@@ -254,18 +536,21 @@ impl<E: PairingEngine> Block<E> {
                                 );
                             }
                             Some(_) => {
-                                if tx.nonce - table[from as usize].cur_nonce != 1 {
+                                if table[fi].addr != Some(tx.addr) {
+                                    return Err(String::from("BLOCK_VERIFY: addr invalid"));
+                                }
+                                if tx.nonce - table[fi].cur_nonce != 1 {
                                     return Err(String::from("BLOCK_VERIFY: nonce invalid"));
                                 }
-                                table[from as usize].delta = Some(
-                                    table[from as usize]
+                                table[fi].delta = Some(
+                                    table[fi]
                                         .delta
                                         .unwrap()
                                         .add(&mul128)
-                                        .sub(&u128_to_fr::<E>(amount)),
+                                        .sub(&u128_to_fr::<E>(pay)),
                                 );
-                                table[from as usize].outcome += amount;
-                                table[from as usize].cur_nonce = tx.nonce;
+                                table[fi].outcome += pay;
+                                table[fi].cur_nonce = tx.nonce;
 
                                 #[cfg(feature = "std")]
                                 println!("{} has presented and transfer-from again!", from);
@@ -273,51 +558,69 @@ impl<E: PairingEngine> Block<E> {
                         },
                     }
                     // transfer-from balance sufficiency check
-                    if let None = (table[from as usize].balance + table[from as usize].income)
-                        .checked_sub(table[from as usize].outcome)
+                    if let None =
+                        (table[fi].balance + table[fi].income).checked_sub(table[fi].outcome)
                     {
                         return Err(String::from("BLOCK_VERIFY: balance invalid"));
                     }
 
-                    match table[to as usize].delta {
-                        None => {
-                            // In a Transfer, the nonce of transfer-to account remains unchanged.
-                            table[to as usize] = Tmp {
-                                delta: Some(u128_to_fr::<E>(amount)),
-                                income: amount,
-                                outcome: 0,
-                                point_value: None,
-                                cur_nonce: 0,
-                                balance: 0,
-                            };
+                    // the transfer-to gets the amount, and the fee recipient gets the fee.
+                    let mut credits = vec![(to, amount)];
+                    if fee > 0 {
+                        credits.push((position(self.fee_recipient, token), fee));
+                    }
+                    for (to, amount) in credits {
+                        let ti = touch(&mut slots, &mut table, &empty, to);
+                        match table[ti].delta {
+                            None => {
+                                // In a Transfer, the nonce of transfer-to account remains unchanged.
+                                table[ti] = Tmp {
+                                    delta: Some(u128_to_fr::<E>(amount)),
+                                    income: amount,
+                                    outcome: 0,
+                                    point_value: None,
+                                    addr: None,
+                                    cur_nonce: 0,
+                                    balance: 0,
+                                };
 
-                            #[cfg(feature = "std")]
-                            println!("{} transfer-to for the 1st time!", to);
-                        }
-                        Some(prev_delta) => {
-                            table[to as usize].delta =
-                                Some(prev_delta.add(&u128_to_fr::<E>(amount)));
-                            table[to as usize].income += amount;
+                                #[cfg(feature = "std")]
+                                println!("{} transfer-to for the 1st time!", to);
+                            }
+                            Some(prev_delta) => {
+                                table[ti].delta = Some(prev_delta.add(&u128_to_fr::<E>(amount)));
+                                table[ti].income += amount;
 
-                            #[cfg(feature = "std")]
-                            println!("{} has presented and transfer-to again!", to);
+                                #[cfg(feature = "std")]
+                                println!("{} has presented and transfer-to again!", to);
+                            }
                         }
                     }
                 }
-                TxType::Register(to) => {
+                TxType::Register(account) => {
                     // A user must be registered to got paid.
-                    // So the Registration should happen on a new user.
-                    table[to as usize] = Tmp {
-                        delta: Some(tx.addr.mul(&mul160).add(&mul128)),
-                        income: 0,
-                        outcome: 0,
-                        point_value: Some(tx.point_value()),
-                        cur_nonce: tx.nonce,
-                        balance: tx.balance,
-                    };
+                    // So the Registration should happen on a new user, for all tokens.
+                    // The slots are proven empty, the balance and nonce are not the tx's.
+                    for token in 0..TOKEN_SIZE as u32 {
+                        let p = position(account, token);
+                        let ti = touch(&mut slots, &mut table, &empty, p);
+                        if table[ti].delta.is_some() {
+                            return Err(String::from("BLOCK_VERIFY: register invalid"));
+                        }
+                        points2prove.push(p);
+                        table[ti] = Tmp {
+                            delta: Some(tx.addr.mul(&mul160).add(&mul128)),
+                            income: 0,
+                            outcome: 0,
+                            point_value: Some(E::Fr::zero()),
+                            addr: Some(tx.addr),
+                            cur_nonce: 1,
+                            balance: 0,
+                        };
+                    }
 
                     #[cfg(feature = "std")]
-                    println!("{} register for the 1st time!", to);
+                    println!("{} register for the 1st time!", account);
                 }
             }
         }
@@ -325,32 +628,353 @@ impl<E: PairingEngine> Block<E> {
         #[cfg(feature = "std")]
         println!("ending dark magic...(off-chain block.verify)");
 
-        let mut point_values = Vec::new();
-        let mut tmp_commit = self.commit.clone();
+        // the new commitments are the commitments updated by every delta of the table,
+        // the transfer-tos and the fees included, other shards must not change.
+        let mut new_commits: BTreeMap<u32, Commitment<E>> = BTreeMap::new();
+        for (point, ti) in &slots {
+            let delta = match table[*ti].delta {
+                Some(delta) => delta,
+                None => continue,
+            };
+            let (shard, p) = cell_upks.locate(*point);
+            let commit = match new_commits.remove(&shard) {
+                Some(commit) => commit,
+                None => self
+                    .shards
+                    .iter()
+                    .find(|s| s.shard == shard)
+                    .ok_or(String::from("BLOCK_VERIFY: shard missing"))?
+                    .commit
+                    .clone(),
+            };
+            let upk = cell_upks
+                .upks
+                .get(p as usize)
+                .ok_or(String::from("BLOCK_VERIFY: position invalid"))?;
+            let commit = update_commit::<E>(
+                &commit,
+                delta,
+                p,
+                upk,
+                cell_upks.omega,
+                cell_upks.shard_size(),
+            )
+            .map_err(|_| String::from("BLOCK_VERIFY: update commit failure"))?;
+            new_commits.insert(shard, commit);
+        }
+        for shard in &self.shards {
+            if new_commits.get(&shard.shard).unwrap_or(&shard.commit) != &shard.new_commit {
+                return Err(String::from("BLOCK_VERIFY: new commit invalid"));
+            }
+        }
 
+        // group the positions to prove by shard.
+        let mut proves: BTreeMap<u32, (Vec<u32>, Vec<E::Fr>)> = BTreeMap::new();
         for point in &points2prove {
-            point_values.push(table[*point as usize].point_value.unwrap());
-            tmp_commit = update_commit(
-                &tmp_commit,
-                table[*point as usize].delta.unwrap(),
-                *point as u32,
-                &cell_upks.upks[*point as usize],
+            let (shard, p) = cell_upks.locate(*point);
+            let prove = proves.entry(shard).or_insert((Vec::new(), Vec::new()));
+            prove.0.push(p);
+            prove.1.push(table[slots[point]].point_value.unwrap());
+        }
+
+        for (shard, (points, point_values)) in proves {
+            let block_shard = self
+                .shards
+                .iter()
+                .find(|s| s.shard == shard)
+                .ok_or(String::from("BLOCK_VERIFY: shard missing"))?;
+            let proof = block_shard
+                .proof
+                .as_ref()
+                .ok_or(String::from("BLOCK_VERIFY: shard proof missing"))?;
+
+            verify_pos::<E>(
+                &cell_upks.vk,
+                &block_shard.commit,
+                point_values,
+                points,
+                proof,
+                cell_upks.omega,
+            )
+            .map_err(|_| String::from("BLOCK_VERIFY: verify pos failure!"))?;
+        }
+
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{HEADER_LEN, OP_POST_BLOCK};
+    use crate::transaction::{u32_to_fr, SecretKey};
+    use ckb_zkp::curve::bn_256::Bn_256;
+    use ckb_zkp::math::fft::EvaluationDomain;
+    use ckb_zkp::scheme::asvc::{commit, key_gen, prove_pos, Parameters};
+    use rand::thread_rng;
+
+    type Fr = <Bn_256 as PairingEngine>::Fr;
+
+    /// 2 shards of 2 accounts.
+    fn setup() -> (Parameters<Bn_256>, CellUpks<Bn_256>) {
+        let params = key_gen::<Bn_256, _>(4, &mut thread_rng()).unwrap();
+        let cell_upks = CellUpks {
+            vk: params.verification_key.clone(),
+            omega: EvaluationDomain::<Fr>::new(4).unwrap().group_gen,
+            account_size: 4,
+            shard_accounts: 2,
+            upks: params.proving_key.update_keys.clone(),
+            tokens: vec![[0u8; 32]; TOKEN_SIZE],
+        };
+        (params, cell_upks)
+    }
+
+    /// the full pubkey of account 2, the first of shard 1.
+    fn account_fpk(cell_upks: &CellUpks<Bn_256>, sk: &SecretKey) -> FullPubKey<Bn_256> {
+        let (_, p) = cell_upks.locate(position(2, 0));
+        FullPubKey {
+            i: 2,
+            update_key: cell_upks.upks[p as usize].clone(),
+            tradition_pubkey: sk.public_key::<Bn_256>(),
+        }
+    }
+
+    /// the value of a registered slot.
+    fn slot(fpk: &FullPubKey<Bn_256>, nonce: u32, balance: u128) -> Fr {
+        let two = u128_to_fr::<Bn_256>(2);
+        fpk.addr() * &two.pow(&[160])
+            + &(two.pow(&[128]) * &u32_to_fr::<Bn_256>(nonce))
+            + &u128_to_fr::<Bn_256>(balance)
+    }
+
+    /// the commit after the register, every token slot of the account is updated.
+    fn register_commit(
+        cell_upks: &CellUpks<Bn_256>,
+        commit: &Commitment<Bn_256>,
+        tx: &Transaction<Bn_256>,
+    ) -> Commitment<Bn_256> {
+        let mut new_commit = commit.clone();
+        for token in 0..TOKEN_SIZE as u32 {
+            let (_, i) = cell_upks.locate(position(tx.from(), token));
+            new_commit = update_commit::<Bn_256>(
+                &new_commit,
+                tx.delta_value().0,
+                i,
+                &cell_upks.upks[i as usize],
                 cell_upks.omega,
-                ACCOUNT_SIZE,
+                cell_upks.shard_size(),
             )
-            .map_err(|_| String::from("BLOCK_VERIFY: update commit failure!"))?;
+            .unwrap();
         }
+        new_commit
+    }
+
+    /// the block registers account 2.
+    fn register_block() -> (CellUpks<Bn_256>, Block<Bn_256>) {
+        let (params, cell_upks) = setup();
+        let zero = commit::<Bn_256>(&params.proving_key, vec![Fr::zero(); 4]).unwrap();
+
+        let sk = SecretKey(vec![5u8; 32]);
+        let (shard, p) = cell_upks.locate(position(2, 0));
+        let fpk = account_fpk(&cell_upks, &sk);
+        // every token slot of the account is proven empty.
+        let points = (0..TOKEN_SIZE as u32)
+            .map(|token| cell_upks.locate(position(2, token)).1)
+            .collect();
+        let proof = prove_pos::<Bn_256>(&params.proving_key, vec![Fr::zero()], points).unwrap();
+        let tx = Transaction::new_register(2, fpk, 0, 0, proof.clone(), &sk);
+
+        let new_commit = register_commit(&cell_upks, &zero, &tx);
+        let block = Block {
+            block_height: 1,
+            fee_recipient: 0,
+            shards: vec![BlockShard {
+                shard,
+                commit: zero,
+                new_commit,
+                proof: Some(proof),
+            }],
+            txs: vec![tx],
+            aggregate_sign: None,
+        };
+        (cell_upks, block)
+    }
+
+    #[test]
+    fn verify_new_commit() {
+        let (cell_upks, block) = register_block();
+        assert!(block.verify(&cell_upks).is_ok());
+
+        let mut forged = block.clone();
+        forged.shards[0].new_commit = forged.shards[0].commit.clone();
+        assert_eq!(
+            forged.verify(&cell_upks).unwrap_err(),
+            "BLOCK_VERIFY: new commit invalid"
+        );
+
+        let mut forged = block;
+        forged.shards.clear();
+        assert_eq!(
+            forged.verify(&cell_upks).unwrap_err(),
+            "BLOCK_VERIFY: shard missing"
+        );
+    }
+
+    #[test]
+    fn reject_other_account_size() {
+        let (cell_upks, block) = register_block();
+        let size = cell_upks.account_size;
+        let witness = block.to_witness(size, OP_POST_BLOCK);
+        assert!(Block::<Bn_256>::from_witness(&witness, size) == Ok((OP_POST_BLOCK, block)));
+        assert!(Block::<Bn_256>::from_witness(&witness, size * 2).is_err());
+
+        let commit = CellCommit::<Bn_256> {
+            block_height: 1,
+            commits: vec![],
+            user_count: 1,
+        };
+        let data = commit.to_cell_data(size, OP_POST_BLOCK);
+        assert!(CellCommit::<Bn_256>::from_cell_data(&data, size) == Ok((OP_POST_BLOCK, commit)));
+        assert!(CellCommit::<Bn_256>::from_cell_data(&data, size * 2).is_err());
+
+        // the upks cell's header has the account size of its payload.
+        let mut forged = cell_upks.clone();
+        forged.account_size = size * 2;
+        let mut data = cell_upks.to_cell_data();
+        data[..HEADER_LEN].copy_from_slice(&forged.to_cell_data()[..HEADER_LEN]);
+        assert!(CellUpks::<Bn_256>::from_cell_data(&data).is_err());
+    }
+
+    #[test]
+    fn reject_malformed_shards() {
+        let (mut cell_upks, block) = register_block();
+        let pre = CellCommit {
+            block_height: 0,
+            commits: vec![block.shards[0].commit.clone()],
+            user_count: 0,
+        };
+        // the pre commit cell has shard 0 only.
+        assert_eq!(
+            block.cell_commit(&pre).err().unwrap(),
+            "BLOCK_COMMIT: shard invalid"
+        );
+
+        cell_upks.shard_accounts = 0;
+        assert_eq!(
+            block.verify(&cell_upks).unwrap_err(),
+            "BLOCK_VERIFY: Upk length"
+        );
+        assert_eq!(
+            block.verify_signatures(&cell_upks).unwrap_err(),
+            "BLOCK_VERIFY: Upk length"
+        );
+    }
+
+    #[test]
+    fn reject_withdraw_of_other_addr() {
+        let (params, cell_upks) = setup();
+        let (sk, other) = (SecretKey(vec![5u8; 32]), SecretKey(vec![6u8; 32]));
+        let fpk = account_fpk(&cell_upks, &sk);
+        let (shard, p) = cell_upks.locate(position(2, 0));
+        let mut values = vec![Fr::zero(); 4];
+        values[p as usize] = slot(&fpk, 1, 10);
+        values[p as usize + 1] = slot(&fpk, 1, 0);
+        let shard_commit = commit::<Bn_256>(&params.proving_key, values.clone()).unwrap();
+        let proof = prove_pos::<Bn_256>(&params.proving_key, values, vec![p]).unwrap();
+
+        let withdraw = |sk: &SecretKey, nonce: u32| {
+            let fpk = account_fpk(&cell_upks, sk);
+            Transaction::new_withdraw(2, 0, 3, [0u8; 32], fpk, nonce, 10, proof.clone(), sk)
+        };
+        let block = |txs: Vec<Transaction<Bn_256>>| {
+            let mut new_commit = shard_commit.clone();
+            for tx in &txs {
+                new_commit = update_commit::<Bn_256>(
+                    &new_commit,
+                    tx.delta_value().0,
+                    p,
+                    &cell_upks.upks[p as usize],
+                    cell_upks.omega,
+                    cell_upks.shard_size(),
+                )
+                .unwrap();
+            }
+            Block {
+                block_height: 1,
+                fee_recipient: 0,
+                shards: vec![BlockShard {
+                    shard,
+                    commit: shard_commit.clone(),
+                    new_commit,
+                    proof: Some(proof.clone()),
+                }],
+                txs,
+                aggregate_sign: None,
+            }
+        };
+
+        let tx = withdraw(&sk, 2);
+        assert_eq!(
+            block(vec![tx.clone(), withdraw(&sk, 3)])
+                .verify_state(&cell_upks)
+                .unwrap()[0],
+            (0, 6)
+        );
+
+        // the second withdraw of the slot is of another key's addr.
+        assert_eq!(
+            block(vec![tx.clone(), withdraw(&other, 3)])
+                .verify_state(&cell_upks)
+                .unwrap_err(),
+            "BLOCK_VERIFY: addr invalid"
+        );
+
+        // a withdraw without its nonce added, or replayed.
+        assert_eq!(
+            block(vec![withdraw(&sk, 1)])
+                .verify_state(&cell_upks)
+                .unwrap_err(),
+            "BLOCK_VERIFY: verify pos failure!"
+        );
+        assert_eq!(
+            block(vec![tx.clone(), tx])
+                .verify_state(&cell_upks)
+                .unwrap_err(),
+            "BLOCK_VERIFY: nonce invalid"
+        );
+    }
 
-        verify_pos::<E>(
-            &cell_upks.vk,
-            &self.commit,
-            point_values,
-            points2prove,
-            &self.proof,
-            cell_upks.omega,
-        )
-        .map_err(|_| String::from("BLOCK_VERIFY: verify pos failure!"))?;
-
-        Ok((incomes, outcomes))
+    #[test]
+    fn reject_register_of_used_slots() {
+        let (cell_upks, block) = register_block();
+
+        let mut twice = block.clone();
+        twice.txs.push(twice.txs[0].clone());
+        assert_eq!(
+            twice.verify_state(&cell_upks).unwrap_err(),
+            "BLOCK_VERIFY: register invalid"
+        );
+
+        // the account is registered, its slots are not empty.
+        let (params, cell_upks) = setup();
+        let (shard, p) = cell_upks.locate(position(2, 0));
+        let fpk = account_fpk(&cell_upks, &SecretKey(vec![5u8; 32]));
+        let mut values = vec![Fr::zero(); 4];
+        values[p as usize] = slot(&fpk, 1, 10);
+        values[p as usize + 1] = slot(&fpk, 1, 0);
+        let registered = commit::<Bn_256>(&params.proving_key, values.clone()).unwrap();
+        let proof = prove_pos::<Bn_256>(&params.proving_key, values, vec![p, p + 1]).unwrap();
+
+        let mut again = block;
+        again.shards = vec![BlockShard {
+            shard,
+            commit: registered.clone(),
+            new_commit: register_commit(&cell_upks, &registered, &again.txs[0]),
+            proof: Some(proof),
+        }];
+        assert_eq!(
+            again.verify_state(&cell_upks).unwrap_err(),
+            "BLOCK_VERIFY: verify pos failure!"
+        );
     }
 }
@@ -0,0 +1,224 @@
+use ckb_zkp::math::{
+    io::Result as IoResult, serialize::*, AffineCurve, FromBytes, PairingEngine, ToBytes,
+};
+use sha2::{Digest, Sha256};
+
+use crate::Vec;
+
+/// magic of all rollup cells' data.
+pub const MAGIC: [u8; 4] = *b"CLVR";
+
+/// current data format version, bump it when the encoding changes.
+///
+/// - 1: the header has the account size.
+/// - 2: the account size is a deployment parameter in `CellUpks`, the header drops it,
+///   so the header has no second copy that could disagree with the pinned upks cell.
+/// - 3: the payloads are Molecule encoded, see `schemas/rollup.mol`.
+/// - 4: the header has the account size again, the data is read only with the account
+///   size of the pinned upks cell.
+pub const FORMAT_VERSION: u8 = 4;
+
+/// magic + version + curve_id + account_size + op.
+pub const HEADER_LEN: usize = 4 + 1 + 4 + 4 + 1;
+
+/// init the L2 state.
+pub const OP_INIT: u8 = 0;
+/// user deposit block.
+pub const OP_DEPOSIT: u8 = 1;
+/// user withdraw block.
+pub const OP_WITHDRAW: u8 = 2;
+/// L2 service post block.
+pub const OP_POST_BLOCK: u8 = 3;
+/// user withdraw by self when the L2 service is offline.
+pub const OP_ESCAPE: u8 = 4;
+/// the cell_upks cell.
+pub const OP_UPKS: u8 = 0xff;
+
+/// curve id is the first 4 bytes of H(g1, g2), so no need a registry of curves.
+pub fn curve_id<E: PairingEngine>() -> u32 {
+    let mut bytes = Vec::new();
+    E::G1Affine::prime_subgroup_generator()
+        .write(&mut bytes)
+        .unwrap();
+    E::G2Affine::prime_subgroup_generator()
+        .write(&mut bytes)
+        .unwrap();
+
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    let hash = hasher.finalize();
+
+    let mut id = [0u8; 4];
+    id.copy_from_slice(&hash[..4]);
+    u32::from_le_bytes(id)
+}
+
+/// upks_hash + commit_type_hash + escape_blocks.
+pub const LOCK_ARGS_LEN: usize = 32 + 32 + 8;
+
+/// The rollup lock's args.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct LockArgs {
+    /// data hash of the cell_upks cell_dep.
+    pub upks_hash: [u8; 32],
+    /// script hash of the commit cell's type id,
+    /// the commit cell in and out must have it.
+    pub commit_type_hash: [u8; 32],
+    /// L1 blocks without commit cell update, after that users can escape.
+    pub escape_blocks: u64,
+}
+
+impl LockArgs {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.upks_hash);
+        bytes.extend_from_slice(&self.commit_type_hash);
+        bytes.extend_from_slice(&self.escape_blocks.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        if bytes.len() != LOCK_ARGS_LEN {
+            return Err(());
+        }
+        let mut upks_hash = [0u8; 32];
+        upks_hash.copy_from_slice(&bytes[..32]);
+        let mut commit_type_hash = [0u8; 32];
+        commit_type_hash.copy_from_slice(&bytes[32..64]);
+        let mut escape_blocks = [0u8; 8];
+        escape_blocks.copy_from_slice(&bytes[64..]);
+
+        Ok(Self {
+            upks_hash,
+            commit_type_hash,
+            escape_blocks: u64::from_le_bytes(escape_blocks),
+        })
+    }
+}
+
+/// The envelope header in front of every rollup cell's data.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Header {
+    pub magic: [u8; 4],
+    pub version: u8,
+    pub curve_id: u32,
+    /// max accounts of the deployment, the same as the upks cell's.
+    pub account_size: u32,
+    pub op: u8,
+}
+
+impl Header {
+    pub fn new<E: PairingEngine>(account_size: u32, op: u8) -> Self {
+        Self {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            curve_id: curve_id::<E>(),
+            account_size,
+            op,
+        }
+    }
+
+    /// check the data is compatible with this build.
+    /// the account size is checked with the upks cell's when the data is read.
+    pub fn check<E: PairingEngine>(&self) -> Result<(), ()> {
+        if self.magic != MAGIC || self.version != FORMAT_VERSION || self.curve_id != curve_id::<E>()
+        {
+            return Err(());
+        }
+        Ok(())
+    }
+
+    /// header + payload.
+    pub fn seal(&self, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes).unwrap();
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    /// split the data to header and payload, the header is not checked.
+    pub fn open(data: &[u8]) -> Result<(Self, &[u8]), ()> {
+        if data.len() < HEADER_LEN {
+            return Err(());
+        }
+        let header = Self::read(&data[..HEADER_LEN]).map_err(|_| ())?;
+        Ok((header, &data[HEADER_LEN..]))
+    }
+}
+
+impl ToBytes for Header {
+    #[inline]
+    fn write<W: Write>(&self, mut writer: W) -> IoResult<()> {
+        writer.write_all(&self.magic)?;
+        self.version.write(&mut writer)?;
+        self.curve_id.write(&mut writer)?;
+        self.account_size.write(&mut writer)?;
+        self.op.write(&mut writer)
+    }
+}
+
+impl FromBytes for Header {
+    #[inline]
+    fn read<R: Read>(mut reader: R) -> IoResult<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+
+        let version = u8::read(&mut reader)?;
+        let curve_id = u32::read(&mut reader)?;
+        let account_size = u32::read(&mut reader)?;
+        let op = u8::read(&mut reader)?;
+
+        Ok(Self {
+            magic,
+            version,
+            curve_id,
+            account_size,
+            op,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::DEFAULT_ACCOUNT_SIZE;
+    use ckb_zkp::curve::bn_256::Bn_256;
+
+    #[test]
+    fn header_round_trip() {
+        let data =
+            Header::new::<Bn_256>(DEFAULT_ACCOUNT_SIZE as u32, OP_POST_BLOCK).seal(&[1, 2, 3]);
+        let (header, payload) = Header::open(&data).unwrap();
+
+        assert_eq!(
+            header,
+            Header::new::<Bn_256>(DEFAULT_ACCOUNT_SIZE as u32, OP_POST_BLOCK)
+        );
+        assert!(header.check::<Bn_256>().is_ok());
+        assert_eq!(payload, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn header_reject_bad_magic() {
+        let mut data = Header::new::<Bn_256>(DEFAULT_ACCOUNT_SIZE as u32, OP_DEPOSIT).seal(&[]);
+        data[0] = b'X';
+
+        let (header, _) = Header::open(&data).unwrap();
+        assert!(header.check::<Bn_256>().is_err());
+    }
+
+    #[test]
+    fn header_reject_bad_version() {
+        let mut header = Header::new::<Bn_256>(DEFAULT_ACCOUNT_SIZE as u32, OP_DEPOSIT);
+        header.version = FORMAT_VERSION + 1;
+
+        let (header, _) = Header::open(&header.seal(&[])).unwrap();
+        assert!(header.check::<Bn_256>().is_err());
+    }
+
+    #[test]
+    fn header_reject_short_data() {
+        let data = Header::new::<Bn_256>(DEFAULT_ACCOUNT_SIZE as u32, OP_DEPOSIT).seal(&[]);
+        assert!(Header::open(&data[..HEADER_LEN - 1]).is_err());
+    }
+}
@@ -10,4 +10,7 @@ use alloc::{string::String, vec, vec::Vec};
 use std::{string::String, vec, vec::Vec};
 
 pub mod block;
+pub mod format;
+pub mod packed;
+pub mod signature;
 pub mod transaction;
@@ -0,0 +1,544 @@
+//! Molecule encoding of the rollup data, follows `schemas/rollup.mol`.
+//!
+//! The encoder and the readers are written against the schema, so the no_std
+//! contract and the node share them without a build-time code generator.
+use ckb_zkp::math::{FromBytes, PairingEngine, ToBytes};
+use ckb_zkp::scheme::asvc::{Commitment, Proof, UpdateKey, VerificationKey};
+
+use crate::block::{Block, BlockShard, CellCommit, CellUpks};
+use crate::transaction::{PublicKey, Transaction, TxType};
+use crate::Vec;
+
+const NUMBER_SIZE: usize = 4;
+
+/// item ids of `union TxType`.
+const DEPOSIT_ID: u32 = 0;
+const WITHDRAW_ID: u32 = 1;
+const REGISTER_ID: u32 = 2;
+const TRANSFER_ID: u32 = 3;
+
+fn pack_u32(u: u32) -> Vec<u8> {
+    u.to_le_bytes().to_vec()
+}
+
+fn pack_u128(u: u128) -> Vec<u8> {
+    u.to_le_bytes().to_vec()
+}
+
+/// `vector Bytes <byte>`: items count, then the bytes.
+fn pack_bytes(v: &[u8]) -> Vec<u8> {
+    let mut bytes = pack_u32(v.len() as u32);
+    bytes.extend_from_slice(v);
+    bytes
+}
+
+fn pack_element<T: ToBytes>(t: &T) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    t.write(&mut bytes).unwrap();
+    pack_bytes(&bytes)
+}
+
+/// an option is empty when it's none.
+fn pack_option(v: Option<Vec<u8>>) -> Vec<u8> {
+    v.unwrap_or_default()
+}
+
+/// fixvec of fixed size items: items count, then the items.
+fn pack_fixvec(items: &[Vec<u8>]) -> Vec<u8> {
+    let mut bytes = pack_u32(items.len() as u32);
+    for item in items {
+        bytes.extend_from_slice(item);
+    }
+    bytes
+}
+
+/// table or dynvec: total size, the offsets of the items, then the items.
+fn pack_dynvec(items: &[Vec<u8>]) -> Vec<u8> {
+    let header_size = NUMBER_SIZE * (items.len() + 1);
+    let total_size = header_size + items.iter().map(|i| i.len()).sum::<usize>();
+
+    let mut bytes = pack_u32(total_size as u32);
+    let mut offset = header_size;
+    for item in items {
+        bytes.extend_from_slice(&pack_u32(offset as u32));
+        offset += item.len();
+    }
+    for item in items {
+        bytes.extend_from_slice(item);
+    }
+    bytes
+}
+
+fn unpack_u32(data: &[u8]) -> Result<u32, ()> {
+    if data.len() != NUMBER_SIZE {
+        return Err(());
+    }
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(data);
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn unpack_u128(data: &[u8]) -> Result<u128, ()> {
+    if data.len() != 16 {
+        return Err(());
+    }
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(data);
+    Ok(u128::from_le_bytes(bytes))
+}
+
+fn unpack_bytes(data: &[u8]) -> Result<&[u8], ()> {
+    if data.len() < NUMBER_SIZE {
+        return Err(());
+    }
+    let len = unpack_u32(&data[..NUMBER_SIZE])? as usize;
+    if data.len() - NUMBER_SIZE != len {
+        return Err(());
+    }
+    Ok(&data[NUMBER_SIZE..])
+}
+
+/// the element is read from all of the bytes, trailing bytes are an error.
+fn unpack_element<T: FromBytes>(data: &[u8]) -> Result<T, ()> {
+    let mut reader = unpack_bytes(data)?;
+    let t = T::read(&mut reader).map_err(|_| ())?;
+    if !reader.is_empty() {
+        return Err(());
+    }
+    Ok(t)
+}
+
+fn unpack_option(data: &[u8]) -> Option<&[u8]> {
+    if data.is_empty() {
+        None
+    } else {
+        Some(data)
+    }
+}
+
+fn unpack_fixvec(data: &[u8], item_size: usize) -> Result<Vec<&[u8]>, ()> {
+    if data.len() < NUMBER_SIZE {
+        return Err(());
+    }
+    let n = unpack_u32(&data[..NUMBER_SIZE])? as usize;
+    let items = &data[NUMBER_SIZE..];
+    if n.checked_mul(item_size) != Some(items.len()) {
+        return Err(());
+    }
+    Ok(items.chunks(item_size).collect())
+}
+
+fn unpack_dynvec(data: &[u8]) -> Result<Vec<&[u8]>, ()> {
+    if data.len() < NUMBER_SIZE {
+        return Err(());
+    }
+    let total_size = unpack_u32(&data[..NUMBER_SIZE])? as usize;
+    if total_size != data.len() {
+        return Err(());
+    }
+    if total_size == NUMBER_SIZE {
+        return Ok(Vec::new());
+    }
+
+    if total_size < NUMBER_SIZE * 2 {
+        return Err(());
+    }
+    let header_size = unpack_u32(&data[NUMBER_SIZE..NUMBER_SIZE * 2])? as usize;
+    if header_size % NUMBER_SIZE != 0 || header_size < NUMBER_SIZE * 2 || header_size > total_size {
+        return Err(());
+    }
+
+    let n = header_size / NUMBER_SIZE - 1;
+    let mut offsets = Vec::with_capacity(n + 1);
+    for i in 0..n {
+        let start = NUMBER_SIZE * (i + 1);
+        offsets.push(unpack_u32(&data[start..start + NUMBER_SIZE])? as usize);
+    }
+    offsets.push(total_size);
+
+    let mut items = Vec::with_capacity(n);
+    for i in 0..n {
+        if (i == 0 && offsets[i] != header_size) || offsets[i] > offsets[i + 1] {
+            return Err(());
+        }
+        items.push(&data[offsets[i]..offsets[i + 1]]);
+    }
+    Ok(items)
+}
+
+fn unpack_table(data: &[u8], fields: usize) -> Result<Vec<&[u8]>, ()> {
+    let items = unpack_dynvec(data)?;
+    if items.len() != fields {
+        return Err(());
+    }
+    Ok(items)
+}
+
+impl TxType {
+    pub fn to_packed(&self) -> Vec<u8> {
+        let (id, item) = match *self {
+            TxType::Deposit(account, token, amount) => (
+                DEPOSIT_ID,
+                [pack_u32(account), pack_u32(token), pack_u128(amount)].concat(),
+            ),
+            TxType::Withdraw(account, token, amount, lock_hash) => (
+                WITHDRAW_ID,
+                [
+                    pack_u32(account),
+                    pack_u32(token),
+                    pack_u128(amount),
+                    lock_hash.to_vec(),
+                ]
+                .concat(),
+            ),
+            TxType::Register(account) => (REGISTER_ID, pack_u32(account)),
+            TxType::Transfer(from, to, token, amount, fee) => (
+                TRANSFER_ID,
+                [
+                    pack_u32(from),
+                    pack_u32(to),
+                    pack_u32(token),
+                    pack_u128(amount),
+                    pack_u128(fee),
+                ]
+                .concat(),
+            ),
+        };
+
+        [pack_u32(id), item].concat()
+    }
+
+    pub fn from_packed(data: &[u8]) -> Result<Self, ()> {
+        if data.len() < NUMBER_SIZE {
+            return Err(());
+        }
+        let (id, s) = data.split_at(NUMBER_SIZE);
+
+        match unpack_u32(id)? {
+            DEPOSIT_ID if s.len() == 24 => Ok(TxType::Deposit(
+                unpack_u32(&s[0..4])?,
+                unpack_u32(&s[4..8])?,
+                unpack_u128(&s[8..24])?,
+            )),
+            WITHDRAW_ID if s.len() == 56 => {
+                let mut lock_hash = [0u8; 32];
+                lock_hash.copy_from_slice(&s[24..56]);
+                Ok(TxType::Withdraw(
+                    unpack_u32(&s[0..4])?,
+                    unpack_u32(&s[4..8])?,
+                    unpack_u128(&s[8..24])?,
+                    lock_hash,
+                ))
+            }
+            REGISTER_ID => Ok(TxType::Register(unpack_u32(s)?)),
+            TRANSFER_ID if s.len() == 44 => Ok(TxType::Transfer(
+                unpack_u32(&s[0..4])?,
+                unpack_u32(&s[4..8])?,
+                unpack_u32(&s[8..12])?,
+                unpack_u128(&s[12..28])?,
+                unpack_u128(&s[28..44])?,
+            )),
+            _ => Err(()),
+        }
+    }
+}
+
+impl<E: PairingEngine> Transaction<E> {
+    pub fn to_packed(&self) -> Vec<u8> {
+        pack_dynvec(&[
+            self.tx_type.to_packed(),
+            pack_element(&self.proof),
+            pack_element(&self.addr),
+            pack_u32(self.nonce),
+            pack_u128(self.balance),
+            pack_bytes(&self.pubkey.0),
+            pack_bytes(&self.sign),
+        ])
+    }
+
+    pub fn from_packed(data: &[u8]) -> Result<Self, ()> {
+        let fields = unpack_table(data, 7)?;
+
+        Ok(Self {
+            tx_type: TxType::from_packed(fields[0])?,
+            proof: unpack_element::<Proof<E>>(fields[1])?,
+            addr: unpack_element::<E::Fr>(fields[2])?,
+            nonce: unpack_u32(fields[3])?,
+            balance: unpack_u128(fields[4])?,
+            pubkey: PublicKey(unpack_bytes(fields[5])?.to_vec()),
+            sign: unpack_bytes(fields[6])?.to_vec(),
+        })
+    }
+}
+
+impl<E: PairingEngine> BlockShard<E> {
+    pub fn to_packed(&self) -> Vec<u8> {
+        pack_dynvec(&[
+            pack_u32(self.shard),
+            pack_element(&self.commit),
+            pack_element(&self.new_commit),
+            pack_option(self.proof.as_ref().map(|p| pack_element(p))),
+        ])
+    }
+
+    pub fn from_packed(data: &[u8]) -> Result<Self, ()> {
+        let fields = unpack_table(data, 4)?;
+        let proof = match unpack_option(fields[3]) {
+            Some(p) => Some(unpack_element::<Proof<E>>(p)?),
+            None => None,
+        };
+
+        Ok(Self {
+            shard: unpack_u32(fields[0])?,
+            commit: unpack_element::<Commitment<E>>(fields[1])?,
+            new_commit: unpack_element::<Commitment<E>>(fields[2])?,
+            proof,
+        })
+    }
+}
+
+impl<E: PairingEngine> Block<E> {
+    pub fn to_packed(&self) -> Vec<u8> {
+        let shards: Vec<Vec<u8>> = self.shards.iter().map(|s| s.to_packed()).collect();
+        let txs: Vec<Vec<u8>> = self.txs.iter().map(|tx| tx.to_packed()).collect();
+
+        pack_dynvec(&[
+            pack_u32(self.block_height),
+            pack_u32(self.fee_recipient),
+            pack_dynvec(&shards),
+            pack_dynvec(&txs),
+            pack_option(self.aggregate_sign.as_ref().map(|s| pack_element(s))),
+        ])
+    }
+
+    pub fn from_packed(data: &[u8]) -> Result<Self, ()> {
+        let fields = unpack_table(data, 5)?;
+
+        let mut shards = Vec::new();
+        for shard in unpack_dynvec(fields[2])? {
+            shards.push(BlockShard::from_packed(shard)?);
+        }
+        let mut txs = Vec::new();
+        for tx in unpack_dynvec(fields[3])? {
+            txs.push(Transaction::from_packed(tx)?);
+        }
+        let aggregate_sign = match unpack_option(fields[4]) {
+            Some(s) => Some(unpack_element::<E::G2Affine>(s)?),
+            None => None,
+        };
+
+        Ok(Self {
+            block_height: unpack_u32(fields[0])?,
+            fee_recipient: unpack_u32(fields[1])?,
+            shards,
+            txs,
+            aggregate_sign,
+        })
+    }
+}
+
+impl<E: PairingEngine> CellUpks<E> {
+    pub fn to_packed(&self) -> Vec<u8> {
+        let upks: Vec<Vec<u8>> = self.upks.iter().map(|u| pack_element(u)).collect();
+        let tokens: Vec<Vec<u8>> = self.tokens.iter().map(|t| t.to_vec()).collect();
+
+        pack_dynvec(&[
+            pack_element(&self.vk),
+            pack_element(&self.omega),
+            pack_u32(self.account_size),
+            pack_u32(self.shard_accounts),
+            pack_dynvec(&upks),
+            pack_fixvec(&tokens),
+        ])
+    }
+
+    pub fn from_packed(data: &[u8]) -> Result<Self, ()> {
+        let fields = unpack_table(data, 6)?;
+
+        let mut upks = Vec::new();
+        for upk in unpack_dynvec(fields[4])? {
+            upks.push(unpack_element::<UpdateKey<E>>(upk)?);
+        }
+        let mut tokens = Vec::new();
+        for token in unpack_fixvec(fields[5], 32)? {
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(token);
+            tokens.push(hash);
+        }
+
+        Ok(Self {
+            vk: unpack_element::<VerificationKey<E>>(fields[0])?,
+            omega: unpack_element::<E::Fr>(fields[1])?,
+            account_size: unpack_u32(fields[2])?,
+            shard_accounts: unpack_u32(fields[3])?,
+            upks,
+            tokens,
+        })
+    }
+}
+
+impl<E: PairingEngine> CellCommit<E> {
+    pub fn to_packed(&self) -> Vec<u8> {
+        let commits: Vec<Vec<u8>> = self.commits.iter().map(|c| pack_element(c)).collect();
+
+        pack_dynvec(&[
+            pack_u32(self.block_height),
+            pack_dynvec(&commits),
+            pack_u32(self.user_count),
+        ])
+    }
+
+    pub fn from_packed(data: &[u8]) -> Result<Self, ()> {
+        let fields = unpack_table(data, 3)?;
+
+        let mut commits = Vec::new();
+        for commit in unpack_dynvec(fields[1])? {
+            commits.push(unpack_element::<Commitment<E>>(commit)?);
+        }
+
+        Ok(Self {
+            block_height: unpack_u32(fields[0])?,
+            commits,
+            user_count: unpack_u32(fields[2])?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_zkp::curve::bn_256::Bn_256;
+    use ckb_zkp::math::{One, Zero};
+    use ckb_zkp::scheme::asvc::{commit, key_gen, prove_pos, Parameters};
+    use rand::thread_rng;
+
+    use crate::signature::{bls_aggregate, bls_sign};
+    use crate::transaction::{FullPubKey, SecretKey};
+
+    type Fr = <Bn_256 as PairingEngine>::Fr;
+
+    fn block(params: &Parameters<Bn_256>) -> Block<Bn_256> {
+        let pk = &params.proving_key;
+        let sk = SecretKey(vec![3u8; 32]);
+        let proof = prove_pos::<Bn_256>(pk, vec![Fr::zero()], vec![1]).unwrap();
+        let fpk = FullPubKey {
+            i: 0,
+            update_key: pk.update_keys[0].clone(),
+            tradition_pubkey: sk.public_key::<Bn_256>(),
+        };
+        let transfer = Transaction::new_transfer(0, 1, 1, 10, 1, fpk, 3, 100, proof.clone(), &sk);
+        let zero = commit::<Bn_256>(pk, vec![Fr::zero(); 4]).unwrap();
+        let one = commit::<Bn_256>(pk, vec![Fr::from(1); 4]).unwrap();
+
+        Block {
+            block_height: 7,
+            fee_recipient: 1,
+            shards: vec![
+                BlockShard {
+                    shard: 0,
+                    commit: zero.clone(),
+                    new_commit: one.clone(),
+                    proof: Some(proof),
+                },
+                BlockShard {
+                    shard: 1,
+                    commit: one,
+                    new_commit: zero,
+                    proof: None,
+                },
+            ],
+            txs: vec![transfer],
+            aggregate_sign: bls_aggregate::<Bn_256>(&[bls_sign::<Bn_256>(&sk, b"block")]),
+        }
+    }
+
+    #[test]
+    fn block_round_trip() {
+        let params = key_gen::<Bn_256, _>(4, &mut thread_rng()).unwrap();
+        let block = block(&params);
+        assert!(block.aggregate_sign.is_some());
+
+        let read = Block::<Bn_256>::from_packed(&block.to_packed()).unwrap();
+        assert!(read == block);
+        assert!(read.txs[0].verify());
+
+        let withdraw = TxType::Withdraw(1, 1, 5, [7u8; 32]);
+        assert_eq!(
+            TxType::from_packed(&withdraw.to_packed()).unwrap(),
+            withdraw
+        );
+
+        let mut empty = block;
+        empty.shards.clear();
+        empty.txs.clear();
+        empty.aggregate_sign = None;
+        assert!(Block::<Bn_256>::from_packed(&empty.to_packed()).unwrap() == empty);
+    }
+
+    #[test]
+    fn cell_data_round_trip() {
+        let params = key_gen::<Bn_256, _>(4, &mut thread_rng()).unwrap();
+        let upks = CellUpks::<Bn_256> {
+            vk: params.verification_key.clone(),
+            omega: Fr::from(5),
+            account_size: 4,
+            shard_accounts: 2,
+            upks: params.proving_key.update_keys.clone(),
+            tokens: vec![[1u8; 32], [2u8; 32]],
+        };
+        let bytes = upks.to_packed();
+        let read = CellUpks::<Bn_256>::from_packed(&bytes).unwrap();
+        assert_eq!(read.to_packed(), bytes);
+        assert_eq!(read.tokens, upks.tokens);
+        assert_eq!(read.account_size, 4);
+
+        let cell = CellCommit::<Bn_256> {
+            block_height: 3,
+            commits: block(&params)
+                .shards
+                .iter()
+                .map(|s| s.commit.clone())
+                .collect(),
+            user_count: 2,
+        };
+        assert!(CellCommit::<Bn_256>::from_packed(&cell.to_packed()).unwrap() == cell);
+    }
+
+    #[test]
+    fn reject_malformed_data() {
+        let params = key_gen::<Bn_256, _>(4, &mut thread_rng()).unwrap();
+        let bytes = block(&params).to_packed();
+
+        // truncated.
+        assert!(Block::<Bn_256>::from_packed(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Block::<Bn_256>::from_packed(&bytes[..3]).is_err());
+        // trailing bytes.
+        let mut long = bytes.clone();
+        long.push(0);
+        assert!(Block::<Bn_256>::from_packed(&long).is_err());
+        // the first offset is not after the header.
+        let mut forged = bytes.clone();
+        forged[4..8].copy_from_slice(&8u32.to_le_bytes());
+        assert!(Block::<Bn_256>::from_packed(&forged).is_err());
+        // a table with a missing field.
+        let short = pack_dynvec(&[pack_u32(1), pack_u32(2)]);
+        assert!(Block::<Bn_256>::from_packed(&short).is_err());
+        // an unknown union item, and a known one with a wrong size.
+        let tx = [pack_u32(9), pack_u32(1)].concat();
+        assert!(TxType::from_packed(&tx).is_err());
+        let tx = [pack_u32(DEPOSIT_ID), pack_u32(1)].concat();
+        assert!(TxType::from_packed(&tx).is_err());
+    }
+
+    #[test]
+    fn reject_element_trailing_bytes() {
+        let addr = Fr::one();
+        let element = pack_element(&addr);
+        assert!(unpack_element::<Fr>(&element) == Ok(addr));
+
+        let mut bytes = Vec::new();
+        addr.write(&mut bytes).unwrap();
+        bytes.push(0);
+        assert!(unpack_element::<Fr>(&pack_bytes(&bytes)).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use ckb_zkp::{
+    gadgets::mimc,
+    math::{AffineCurve, FromBytes, One, PairingEngine, ProjectiveCurve, ToBytes, Zero},
+};
+use core::ops::Neg;
+use sha2::{Digest, Sha256};
+
+use crate::transaction::{PublicKey, SecretKey};
+use crate::Vec;
+
+/// hash any bytes to the scalar field, same as the account's addr.
+pub fn hash_to_fr<E: PairingEngine>(bytes: &[u8]) -> E::Fr {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    mimc::hash(&hasher.finalize()[..])
+}
+
+/// domain of the BLS key's scalar, so the BLS key is never the Schnorr key.
+const BLS_KEY_DOMAIN: &[u8] = b"clover-bls-key";
+
+impl SecretKey {
+    /// secret scalar of the Schnorr key, derived from the key's bytes.
+    pub fn scalar<E: PairingEngine>(&self) -> E::Fr {
+        hash_to_fr::<E>(&self.0)
+    }
+
+    /// secret scalar of the BLS key, derived from the key's bytes in its own domain,
+    /// so a secret is never used by both schemes.
+    pub fn bls_scalar<E: PairingEngine>(&self) -> E::Fr {
+        let mut bytes = BLS_KEY_DOMAIN.to_vec();
+        bytes.extend_from_slice(&self.0);
+        hash_to_fr::<E>(&bytes)
+    }
+
+    /// pk = (g1^sk, g1^bls_sk), the Schnorr key and the BLS key.
+    pub fn public_key<E: PairingEngine>(&self) -> PublicKey {
+        let g1 = E::G1Affine::prime_subgroup_generator();
+        let pk = g1.mul(self.scalar::<E>()).into_affine();
+        let bls_pk = g1.mul(self.bls_scalar::<E>()).into_affine();
+
+        let mut bytes = Vec::new();
+        pk.write(&mut bytes).unwrap();
+        bls_pk.write(&mut bytes).unwrap();
+        PublicKey(bytes)
+    }
+}
+
+impl PublicKey {
+    /// the Schnorr key's G1 point, None if the bytes is not a valid key.
+    pub fn point<E: PairingEngine>(&self) -> Option<E::G1Affine> {
+        self.points::<E>().map(|(pk, _)| pk)
+    }
+
+    /// the BLS key's G1 point, None if the bytes is not a valid key.
+    pub fn bls_point<E: PairingEngine>(&self) -> Option<E::G1Affine> {
+        self.points::<E>().map(|(_, bls_pk)| bls_pk)
+    }
+
+    fn points<E: PairingEngine>(&self) -> Option<(E::G1Affine, E::G1Affine)> {
+        let mut reader = &self.0[..];
+        let pk = E::G1Affine::read(&mut reader).ok()?;
+        let bls_pk = E::G1Affine::read(&mut reader).ok()?;
+        if !reader.is_empty() || pk.is_zero() || bls_pk.is_zero() {
+            return None;
+        }
+        Some((pk, bls_pk))
+    }
+}
+
+/// Schnorr signature over G1, serialized as (R, s).
+///
+/// The nonce is derived from the secret key and message, so signing needs no rng
+/// and works in no_std.
+pub fn sign<E: PairingEngine>(sk: &SecretKey, msg: &[u8]) -> Vec<u8> {
+    let x = sk.scalar::<E>();
+    let pk = sk.public_key::<E>();
+
+    let mut nonce_bytes = sk.0.clone();
+    nonce_bytes.extend_from_slice(msg);
+    let k = hash_to_fr::<E>(&nonce_bytes);
+    let r = E::G1Affine::prime_subgroup_generator().mul(k).into_affine();
+
+    let e = challenge::<E>(&r, &pk, msg);
+    let s = k + &(e * &x);
+
+    let mut bytes = Vec::new();
+    r.write(&mut bytes).unwrap();
+    s.write(&mut bytes).unwrap();
+    bytes
+}
+
+/// verify g1^s == R + pk^e.
+pub fn verify<E: PairingEngine>(pk: &PublicKey, msg: &[u8], sig: &[u8]) -> bool {
+    let pk_point = match pk.point::<E>() {
+        Some(p) => p,
+        None => return false,
+    };
+
+    let mut reader = sig;
+    let r = match E::G1Affine::read(&mut reader) {
+        Ok(r) => r,
+        Err(_) => return false,
+    };
+    let s = match E::Fr::read(&mut reader) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    if !reader.is_empty() {
+        return false;
+    }
+
+    let e = challenge::<E>(&r, pk, msg);
+
+    let left = E::G1Affine::prime_subgroup_generator().mul(s);
+    let right = r.into_projective() + &pk_point.mul(e);
+
+    left == right
+}
+
+/// e = H(R, pk, msg).
+fn challenge<E: PairingEngine>(r: &E::G1Affine, pk: &PublicKey, msg: &[u8]) -> E::Fr {
+    let mut bytes = Vec::new();
+    r.write(&mut bytes).unwrap();
+    bytes.extend_from_slice(&pk.0);
+    bytes.extend_from_slice(msg);
+    hash_to_fr::<E>(&bytes)
+}
+
+/// hash (pk, msg) to G2 by try-and-increment, the pk is included so the same
+/// message signed by different users is never aggregated on the same point.
+pub fn hash_to_g2<E: PairingEngine>(pk: &PublicKey, msg: &[u8]) -> E::G2Affine {
+    let mut ctr = 0u32;
+    loop {
+        let mut bytes = Vec::new();
+        for part in 0u8..2 {
+            let mut hasher = Sha256::new();
+            hasher.update(&pk.0);
+            hasher.update(msg);
+            hasher.update(&ctr.to_le_bytes());
+            hasher.update(&[part]);
+            bytes.extend_from_slice(&hasher.finalize()[..]);
+        }
+
+        if let Some(p) = E::G2Affine::from_random_bytes(&bytes) {
+            let p = p.mul_by_cofactor();
+            if !p.is_zero() {
+                return p;
+            }
+        }
+        ctr += 1;
+    }
+}
+
+/// BLS signature sig = H(pk, msg)^bls_sk in G2, the BLS key is in G1 next to the Schnorr key.
+pub fn bls_sign<E: PairingEngine>(sk: &SecretKey, msg: &[u8]) -> Vec<u8> {
+    let pk = sk.public_key::<E>();
+    let sig = hash_to_g2::<E>(&pk, msg)
+        .mul(sk.bls_scalar::<E>())
+        .into_affine();
+
+    let mut bytes = Vec::new();
+    sig.write(&mut bytes).unwrap();
+    bytes
+}
+
+/// aggregate BLS signatures by adding them in G2.
+pub fn bls_aggregate<E: PairingEngine>(sigs: &[Vec<u8>]) -> Option<E::G2Affine> {
+    let mut agg = E::G2Projective::zero();
+    for sig in sigs {
+        let mut reader = &sig[..];
+        let point = E::G2Affine::read(&mut reader).ok()?;
+        if !reader.is_empty() {
+            return None;
+        }
+        agg += &point.into_projective();
+    }
+    Some(agg.into_affine())
+}
+
+/// verify e(g1, agg) == prod e(bls_pk_i, H(pk_i, msg_i)) by a single multi-pairing.
+pub fn bls_verify_aggregate<E: PairingEngine>(
+    pks_msgs: &[(PublicKey, Vec<u8>)],
+    agg: &E::G2Affine,
+) -> bool {
+    let mut pairs: Vec<(E::G1Prepared, E::G2Prepared)> = Vec::new();
+    pairs.push((
+        E::G1Affine::prime_subgroup_generator().neg().into(),
+        agg.clone().into(),
+    ));
+
+    for (pk, msg) in pks_msgs {
+        let pk_point = match pk.bls_point::<E>() {
+            Some(p) => p,
+            None => return false,
+        };
+        pairs.push((pk_point.into(), hash_to_g2::<E>(pk, msg).into()));
+    }
+
+    E::product_of_pairings(&pairs) == E::Fqk::one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_zkp::curve::bn_256::Bn_256;
+
+    #[test]
+    fn schnorr_sign_verify() {
+        let sk = SecretKey(vec![1u8; 32]);
+        let pk = sk.public_key::<Bn_256>();
+
+        let sig = sign::<Bn_256>(&sk, b"transfer 10");
+        assert!(verify::<Bn_256>(&pk, b"transfer 10", &sig));
+    }
+
+    #[test]
+    fn separate_bls_key() {
+        let sk = SecretKey(vec![1u8; 32]);
+        let pk = sk.public_key::<Bn_256>();
+        assert!(pk.point::<Bn_256>() != pk.bls_point::<Bn_256>());
+        // a pubkey without the BLS key is invalid.
+        let schnorr_only = PublicKey(pk.0[..pk.0.len() / 2].to_vec());
+        assert!(schnorr_only.point::<Bn_256>().is_none());
+    }
+
+    #[test]
+    fn schnorr_reject_tampered() {
+        let sk = SecretKey(vec![1u8; 32]);
+        let pk = sk.public_key::<Bn_256>();
+        let sig = sign::<Bn_256>(&sk, b"transfer 10");
+
+        assert!(!verify::<Bn_256>(&pk, b"transfer 99", &sig));
+        let other = SecretKey(vec![2u8; 32]).public_key::<Bn_256>();
+        assert!(!verify::<Bn_256>(&other, b"transfer 10", &sig));
+
+        let mut bad = sig.clone();
+        let last = bad.len() - 1;
+        bad[last] ^= 1;
+        assert!(!verify::<Bn_256>(&pk, b"transfer 10", &bad));
+        let short = &sig[..sig.len() - 1];
+        assert!(!verify::<Bn_256>(&pk, b"transfer 10", short));
+    }
+
+    fn signers(n: u8) -> Vec<(SecretKey, PublicKey, Vec<u8>)> {
+        (0..n)
+            .map(|i| {
+                let sk = SecretKey(vec![i + 1; 32]);
+                let pk = sk.public_key::<Bn_256>();
+                (sk, pk, vec![i; 8])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bls_aggregate_verify() {
+        let signers = signers(4);
+        let sigs: Vec<Vec<u8>> = signers
+            .iter()
+            .map(|(sk, _, msg)| bls_sign::<Bn_256>(sk, msg))
+            .collect();
+        let pks_msgs: Vec<(PublicKey, Vec<u8>)> = signers
+            .iter()
+            .map(|(_, pk, msg)| (pk.clone(), msg.clone()))
+            .collect();
+
+        let agg = bls_aggregate::<Bn_256>(&sigs).unwrap();
+        assert!(bls_verify_aggregate::<Bn_256>(&pks_msgs, &agg));
+    }
+
+    #[test]
+    fn bls_aggregate_reject_bad_sign() {
+        let signers = signers(4);
+        let mut sigs: Vec<Vec<u8>> = signers
+            .iter()
+            .map(|(sk, _, msg)| bls_sign::<Bn_256>(sk, msg))
+            .collect();
+        let pks_msgs: Vec<(PublicKey, Vec<u8>)> = signers
+            .iter()
+            .map(|(_, pk, msg)| (pk.clone(), msg.clone()))
+            .collect();
+
+        // signer 2 signs another message.
+        sigs[2] = bls_sign::<Bn_256>(&signers[2].0, b"other");
+        let agg = bls_aggregate::<Bn_256>(&sigs).unwrap();
+        assert!(!bls_verify_aggregate::<Bn_256>(&pks_msgs, &agg));
+
+        // the Schnorr key can't sign for the BLS key.
+        let (sk, pk, msg) = &signers[2];
+        let sig = hash_to_g2::<Bn_256>(pk, msg)
+            .mul(sk.scalar::<Bn_256>())
+            .into_affine();
+        sigs[2] = Vec::new();
+        sig.write(&mut sigs[2]).unwrap();
+        let agg = bls_aggregate::<Bn_256>(&sigs).unwrap();
+        assert!(!bls_verify_aggregate::<Bn_256>(&pks_msgs, &agg));
+    }
+}
@@ -1,17 +1,33 @@
 use ckb_zkp::{
     gadgets::mimc,
     math::{
-        io::Result as IoResult, serialize::*, BigInteger, Field, FromBytes, PairingEngine,
-        PrimeField, ToBytes, Zero,
+        io::{ErrorKind, Result as IoResult},
+        serialize::*,
+        BigInteger, Field, FromBytes, PairingEngine, PrimeField, ToBytes, Zero,
     },
     scheme::asvc::{Proof, UpdateKey},
 };
 use core::ops::Neg;
 use sha2::{Digest, Sha256};
 
+use crate::signature;
 use crate::{String, Vec};
 
-pub const ACCOUNT_SIZE: usize = 128;
+/// default accounts of a deployment, the deployed size is in `CellUpks::account_size`.
+pub const DEFAULT_ACCOUNT_SIZE: usize = 128;
+
+/// max UDT tokens L2 supports, the token id is the index of `CellUpks::tokens`.
+pub const TOKEN_SIZE: usize = 2;
+
+/// every account has a slot for each token in the vector.
+pub fn vector_size(account_size: usize) -> usize {
+    account_size * TOKEN_SIZE
+}
+
+/// the position of account's token slot in the vector.
+pub fn position(account: u32, token: u32) -> u32 {
+    account * TOKEN_SIZE as u32 + token
+}
 
 pub type TxHash = Vec<u8>;
 
@@ -64,7 +80,7 @@ impl<E: PairingEngine> FullPubKey<E> {
         self.i.write(&mut bytes).unwrap();
         self.update_key.ai.write(&mut bytes).unwrap();
         self.update_key.ui.write(&mut bytes).unwrap();
-        self.tradition_pubkey.0.write(&mut bytes).unwrap();
+        self.tradition_pubkey.write(&mut bytes).unwrap();
 
         let mut hasher = Sha256::new();
         hasher.update(bytes);
@@ -74,27 +90,28 @@ impl<E: PairingEngine> FullPubKey<E> {
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub enum TxType {
-    /// to_account, amount.
-    Deposit(u32, u128),
-    /// from_account, amount.
-    Withdraw(u32, u128),
-    /// from_account, to_account, amount
-    Transfer(u32, u32, u128),
+    /// to_account, token, amount.
+    Deposit(u32, u32, u128),
+    /// from_account, token, amount, the lock hash of the recipient's UDT cell.
+    Withdraw(u32, u32, u128, [u8; 32]),
+    /// from_account, to_account, token, amount, fee.
+    /// the fee (same token) is paid to the block's fee recipient.
+    Transfer(u32, u32, u32, u128, u128),
     /// registe a account.
     Register(u32),
 }
 
 impl TxType {
-    pub fn new_transfer(from: u32, to: u32, amount: u128) -> Self {
-        TxType::Transfer(from, to, amount)
+    pub fn new_transfer(from: u32, to: u32, token: u32, amount: u128, fee: u128) -> Self {
+        TxType::Transfer(from, to, token, amount, fee)
     }
 
-    pub fn new_deposit(to: u32, amount: u128) -> Self {
-        TxType::Deposit(to, amount)
+    pub fn new_deposit(to: u32, token: u32, amount: u128) -> Self {
+        TxType::Deposit(to, token, amount)
     }
 
-    pub fn new_withdraw(from: u32, amount: u128) -> Self {
-        TxType::Withdraw(from, amount)
+    pub fn new_withdraw(from: u32, token: u32, amount: u128, lock_hash: [u8; 32]) -> Self {
+        TxType::Withdraw(from, token, amount, lock_hash)
     }
 
     pub fn new_register(account: u32) -> Self {
@@ -110,9 +127,9 @@ pub struct Transaction<E: PairingEngine> {
     pub proof: Proof<E>,
     /// account's hash.
     pub addr: E::Fr,
-    /// tx's nonce
+    /// the nonce of the token slot after the tx.
     pub nonce: u32,
-    /// from_account's balance.
+    /// from_account's balance of the token.
     pub balance: u128,
     /// sender's pubkey.
     pub pubkey: PublicKey,
@@ -154,6 +171,7 @@ impl<E: PairingEngine> Transaction<E> {
 
         // mock
         self.from().write(&mut bytes).unwrap();
+        self.token().write(&mut bytes).unwrap();
         self.addr.write(&mut bytes).unwrap();
         self.nonce.write(&mut bytes).unwrap();
 
@@ -171,18 +189,36 @@ impl<E: PairingEngine> Transaction<E> {
         }
     }
 
+    /// the token of the tx, register is token 0.
+    pub fn token(&self) -> u32 {
+        match self.tx_type {
+            TxType::Deposit(_, token, _)
+            | TxType::Withdraw(_, token, ..)
+            | TxType::Transfer(_, _, token, ..) => token,
+            TxType::Register(..) => 0,
+        }
+    }
+
+    /// the fee of the tx, only transfer has fee.
+    pub fn fee(&self) -> u128 {
+        match self.tx_type {
+            TxType::Transfer(.., fee) => fee,
+            _ => 0,
+        }
+    }
+
+    /// the position of the from account's token slot.
+    pub fn from_position(&self) -> u32 {
+        position(self.from(), self.token())
+    }
+
     #[rustfmt::skip]
     pub fn point_value(&self) -> E::Fr {
         let _mul_160: E::Fr = E::Fr::from(2).pow(&[160]);
         let _mul_128: E::Fr = E::Fr::from(2).pow(&[128]);
 
         match self.tx_type {
-            TxType::Deposit(..) | TxType::Withdraw(..) => {
-                self.addr * &_mul_160
-                    + &(_mul_128 * &u32_to_fr::<E>(self.nonce))
-                    + &u128_to_fr::<E>(self.balance)
-            }
-            TxType::Transfer(..) => {
+            TxType::Deposit(..) | TxType::Withdraw(..) | TxType::Transfer(..) => {
                 self.addr * &_mul_160
                     + &(_mul_128 * &u32_to_fr::<E>(self.nonce - 1))
                     + &u128_to_fr::<E>(self.balance)
@@ -195,25 +231,28 @@ impl<E: PairingEngine> Transaction<E> {
         }
     }
 
+    /// the changes of the from and the to slots' values, the same as the block verify's.
+    /// every tx adds one to the from's nonce, so its signed message can't be replayed.
     #[rustfmt::skip]
     pub fn delta_value(&self) -> (E::Fr, E::Fr) {
         let _mul_160: E::Fr = E::Fr::from(2).pow(&[160]);
+        let _mul_128: E::Fr = E::Fr::from(2).pow(&[128]);
         let zero = E::Fr::zero();
 
         match self.tx_type {
-            TxType::Deposit(_from, amount) => {
-                (u128_to_fr::<E>(amount), zero)
+            TxType::Deposit(_from, _token, amount) => {
+                (u128_to_fr::<E>(amount) + &_mul_128, zero)
             }
-            TxType::Withdraw(_from, amount) => {
-                (u128_to_fr::<E>(amount).neg(), zero)
+            TxType::Withdraw(_from, _token, amount, _) => {
+                (u128_to_fr::<E>(amount).neg() + &_mul_128, zero)
             }
-            TxType::Transfer(_from, _to, amount) => {
+            TxType::Transfer(_from, _to, _token, amount, fee) => {
                 let amount_fr = u128_to_fr::<E>(amount);
-                (amount_fr.neg() + &_mul_160,
+                (amount_fr.neg() - &u128_to_fr::<E>(fee) + &_mul_128,
                  amount_fr)
             }
             TxType::Register(..) => {
-                (self.addr * &_mul_160, zero)
+                (self.addr * &_mul_160 + &_mul_128, zero)
             }
         }
     }
@@ -226,19 +265,22 @@ impl<E: PairingEngine> Transaction<E> {
     pub fn new_transfer(
         from: u32,
         to: u32,
+        token: u32,
         amount: u128,
+        fee: u128,
         fpk: FullPubKey<E>,
         nonce: u32,
         balance: u128,
         proof: Proof<E>,
         sk: &SecretKey,
     ) -> Self {
-        let tx_type = TxType::new_transfer(from, to, amount);
+        let tx_type = TxType::new_transfer(from, to, token, amount, fee);
         Self::new(tx_type, fpk, nonce, balance, proof, sk)
     }
 
     pub fn new_deposit(
         to: u32,
+        token: u32,
         amount: u128,
         fpk: FullPubKey<E>,
         nonce: u32,
@@ -246,20 +288,23 @@ impl<E: PairingEngine> Transaction<E> {
         proof: Proof<E>,
         sk: &SecretKey,
     ) -> Self {
-        let tx_type = TxType::new_deposit(to, amount);
+        let tx_type = TxType::new_deposit(to, token, amount);
         Self::new(tx_type, fpk, nonce, balance, proof, sk)
     }
 
+    /// new withdraw transaction, the withdrawn UDT goes to a cell of the lock hash.
     pub fn new_withdraw(
         from: u32,
+        token: u32,
         amount: u128,
+        lock_hash: [u8; 32],
         fpk: FullPubKey<E>,
         nonce: u32,
         balance: u128,
         proof: Proof<E>,
         sk: &SecretKey,
     ) -> Self {
-        let tx_type = TxType::new_withdraw(from, amount);
+        let tx_type = TxType::new_withdraw(from, token, amount, lock_hash);
         Self::new(tx_type, fpk, nonce, balance, proof, sk)
     }
 
@@ -275,13 +320,29 @@ impl<E: PairingEngine> Transaction<E> {
         Self::new(tx_type, fpk, nonce, balance, proof, sk)
     }
 
-    /// verify sign
+    /// the signed message: tx_type, nonce, balance and addr.
+    /// a withdraw's tx_type has the recipient's lock hash, so it can't be redirected.
+    pub fn message(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.tx_type.write(&mut bytes).unwrap();
+        self.nonce.write(&mut bytes).unwrap();
+        self.balance.write(&mut bytes).unwrap();
+        self.addr.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// verify sign by the sender's pubkey.
     pub fn verify(&self) -> bool {
-        true
+        signature::verify::<E>(&self.pubkey, &self.message(), &self.sign)
     }
 
-    pub fn sign(&mut self, _sk: &SecretKey) {
-        // TODO
+    pub fn sign(&mut self, sk: &SecretKey) {
+        self.sign = signature::sign::<E>(sk, &self.message());
+    }
+
+    /// replace the sign with a BLS sign, which will be aggregated in the block.
+    pub fn sign_bls(&mut self, sk: &SecretKey) {
+        self.sign = signature::bls_sign::<E>(sk, &self.message());
     }
 }
 
@@ -297,25 +358,30 @@ impl ToBytes for TxType {
     #[inline]
     fn write<W: Write>(&self, mut writer: W) -> IoResult<()> {
         match self {
-            TxType::Deposit(from, amount) => {
+            TxType::Deposit(from, token, amount) => {
                 0u8.write(&mut writer)?;
                 from.write(&mut writer)?;
+                token.write(&mut writer)?;
                 amount.write(&mut writer)?;
             }
-            TxType::Withdraw(from, amount) => {
+            TxType::Withdraw(from, token, amount, lock_hash) => {
                 1u8.write(&mut writer)?;
                 from.write(&mut writer)?;
+                token.write(&mut writer)?;
                 amount.write(&mut writer)?;
+                writer.write_all(lock_hash)?;
             }
             TxType::Register(from) => {
                 2u8.write(&mut writer)?;
                 from.write(&mut writer)?;
             }
-            TxType::Transfer(from, to, amount) => {
+            TxType::Transfer(from, to, token, amount, fee) => {
                 3u8.write(&mut writer)?;
                 from.write(&mut writer)?;
                 to.write(&mut writer)?;
+                token.write(&mut writer)?;
                 amount.write(&mut writer)?;
+                fee.write(&mut writer)?;
             }
         }
 
@@ -331,15 +397,19 @@ impl FromBytes for TxType {
         match tx_type {
             0u8 => {
                 let from = u32::read(&mut reader)?;
+                let token = u32::read(&mut reader)?;
                 let amount = u128::read(&mut reader)?;
 
-                Ok(TxType::Deposit(from, amount))
+                Ok(TxType::Deposit(from, token, amount))
             }
             1u8 => {
                 let from = u32::read(&mut reader)?;
+                let token = u32::read(&mut reader)?;
                 let amount = u128::read(&mut reader)?;
+                let mut lock_hash = [0u8; 32];
+                reader.read_exact(&mut lock_hash)?;
 
-                Ok(TxType::Withdraw(from, amount))
+                Ok(TxType::Withdraw(from, token, amount, lock_hash))
             }
             2u8 => {
                 let from = u32::read(&mut reader)?;
@@ -349,26 +419,67 @@ impl FromBytes for TxType {
             3u8 => {
                 let from = u32::read(&mut reader)?;
                 let to = u32::read(&mut reader)?;
+                let token = u32::read(&mut reader)?;
                 let amount = u128::read(&mut reader)?;
+                let fee = u128::read(&mut reader)?;
 
-                Ok(TxType::Transfer(from, to, amount))
+                Ok(TxType::Transfer(from, to, token, amount, fee))
             }
-            _ => panic!("Invalid tx"),
+            _ => Err(ErrorKind::InvalidData.into()),
         }
     }
 }
 
+/// read the length prefixed bytes, the buffer grows with the read data,
+/// so a forged length can't allocate more than the input has.
+fn read_bytes<R: Read>(mut reader: R, len: usize) -> IoResult<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut chunk = [0u8; 256];
+    while bytes.len() < len {
+        let n = core::cmp::min(len - bytes.len(), chunk.len());
+        reader.read_exact(&mut chunk[..n])?;
+        bytes.extend_from_slice(&chunk[..n]);
+    }
+    Ok(bytes)
+}
+
 impl ToBytes for PublicKey {
     #[inline]
-    fn write<W: Write>(&self, _writer: W) -> IoResult<()> {
-        Ok(())
+    fn write<W: Write>(&self, mut writer: W) -> IoResult<()> {
+        (self.0.len() as u32).write(&mut writer)?;
+        writer.write_all(&self.0)
     }
 }
 
 impl FromBytes for PublicKey {
     #[inline]
-    fn read<R: Read>(_reader: R) -> IoResult<Self> {
-        Ok(Self(Vec::new()))
+    fn read<R: Read>(mut reader: R) -> IoResult<Self> {
+        let len = u32::read(&mut reader)?;
+        Ok(Self(read_bytes(&mut reader, len as usize)?))
+    }
+}
+
+impl<E: PairingEngine> ToBytes for FullPubKey<E> {
+    #[inline]
+    fn write<W: Write>(&self, mut writer: W) -> IoResult<()> {
+        self.i.write(&mut writer)?;
+        self.update_key.write(&mut writer)?;
+        self.tradition_pubkey.write(&mut writer)
+    }
+}
+
+impl<E: PairingEngine> FromBytes for FullPubKey<E> {
+    #[inline]
+    fn read<R: Read>(mut reader: R) -> IoResult<Self> {
+        let i = u32::read(&mut reader)?;
+        let update_key = UpdateKey::read(&mut reader)?;
+        let tradition_pubkey = PublicKey::read(&mut reader)?;
+
+        Ok(Self {
+            i,
+            update_key,
+            tradition_pubkey,
+        })
     }
 }
 
@@ -380,9 +491,9 @@ impl<E: PairingEngine> ToBytes for Transaction<E> {
         self.addr.write(&mut writer)?;
         self.nonce.write(&mut writer)?;
         self.balance.write(&mut writer)?;
-        self.pubkey.write(&mut writer)
-
-        //self.sign.write(&mut writer)?
+        self.pubkey.write(&mut writer)?;
+        (self.sign.len() as u32).write(&mut writer)?;
+        writer.write_all(&self.sign)
     }
 }
 
@@ -395,6 +506,8 @@ impl<E: PairingEngine> FromBytes for Transaction<E> {
         let nonce = u32::read(&mut reader)?;
         let balance = u128::read(&mut reader)?;
         let pubkey = PublicKey::read(&mut reader)?;
+        let sign_len = u32::read(&mut reader)?;
+        let sign = read_bytes(&mut reader, sign_len as usize)?;
 
         Ok(Self {
             tx_type,
@@ -403,7 +516,60 @@ impl<E: PairingEngine> FromBytes for Transaction<E> {
             nonce,
             balance,
             pubkey,
-            sign: Vec::new(),
+            sign,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_zkp::curve::bn_256::Bn_256;
+    use ckb_zkp::scheme::asvc::{key_gen, prove_pos};
+    use rand::thread_rng;
+
+    type Fr = <Bn_256 as PairingEngine>::Fr;
+
+    #[test]
+    fn public_key_round_trip() {
+        let pk = SecretKey(vec![1u8; 32]).public_key::<Bn_256>();
+
+        let mut bytes = Vec::new();
+        pk.write(&mut bytes).unwrap();
+        assert!(PublicKey::read(&bytes[..]).unwrap() == pk);
+    }
+
+    #[test]
+    fn transaction_round_trip() {
+        let params = key_gen::<Bn_256, _>(4, &mut thread_rng()).unwrap();
+        let proof = prove_pos::<Bn_256>(&params.proving_key, vec![Fr::zero()], vec![1]).unwrap();
+        let sk = SecretKey(vec![2u8; 32]);
+        let fpk = FullPubKey {
+            i: 0,
+            update_key: params.proving_key.update_keys[0].clone(),
+            tradition_pubkey: sk.public_key::<Bn_256>(),
+        };
+        let tx = Transaction::new_transfer(0, 1, 1, 10, 1, fpk, 3, 100, proof, &sk);
+
+        let mut bytes = Vec::new();
+        tx.write(&mut bytes).unwrap();
+        let read = Transaction::<Bn_256>::read(&bytes[..]).unwrap();
+        assert!(read == tx);
+        assert_eq!(read.tx_type, TxType::Transfer(0, 1, 1, 10, 1));
+        assert!(read.verify());
+    }
+
+    #[test]
+    fn reject_unknown_tx_type() {
+        let mut bytes = vec![9u8];
+        bytes.extend_from_slice(&[0u8; 40]);
+        assert!(TxType::read(&bytes[..]).is_err());
+    }
+
+    #[test]
+    fn reject_forged_length() {
+        let mut bytes = u32::max_value().to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[1, 2, 3]);
+        assert!(PublicKey::read(&bytes[..]).is_err());
+    }
+}
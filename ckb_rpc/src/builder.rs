@@ -0,0 +1,922 @@
+//! the rollup's L1 txs, built from typed values without a node.
+
+use ckb_zkp::math::PairingEngine;
+use std::fmt;
+
+use asvc_rollup::block::{Block, CellCommit, CellUpks};
+use asvc_rollup::format::{LockArgs, OP_DEPOSIT, OP_ESCAPE, OP_INIT, OP_POST_BLOCK, OP_WITHDRAW};
+
+use ckb_tool::ckb_crypto::secp::Privkey;
+use ckb_tool::ckb_hash::new_blake2b;
+use ckb_tool::ckb_types::{
+    bytes::Bytes,
+    core::{Capacity, TransactionBuilder, TransactionView},
+    packed::*,
+    prelude::*,
+    H256,
+};
+
+use crate::{decode, CkbRpcError};
+
+/// shannons per 1000 bytes of the tx, CKB's default min fee rate.
+pub const DEFAULT_FEE_RATE: u64 = 1000;
+
+/// a recoverable secp256k1 signature, in the lock of the funding inputs' witness.
+pub const SIGNATURE_SIZE: usize = 65;
+
+/// the live commit cell and its data.
+#[derive(Clone)]
+pub struct CommitCell<E: PairingEngine> {
+    pub out_point: OutPoint,
+    pub commit: CellCommit<E>,
+}
+
+/// a live UDT cell, the amount is its data.
+#[derive(Clone, Debug)]
+pub struct UdtCell {
+    pub out_point: OutPoint,
+    pub udt_type: Script,
+    pub amount: u128,
+}
+
+/// a live CKB cell of the funding lock, no type and no data.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FundingCell {
+    pub out_point: OutPoint,
+    pub capacity: u64,
+}
+
+/// the secp256k1 key of the funding lock (CKB's sighash all lock), and the lock's
+/// cell dep (the secp256k1 dep group of the chain).
+#[derive(Clone)]
+pub struct FundingSigner {
+    pub key: Privkey,
+    pub cell_dep: CellDep,
+}
+
+impl FundingSigner {
+    /// from the hex of the 32 bytes key, and the hex of the cell dep's molecule bytes.
+    pub fn from_hex(key: &str, cell_dep: &str) -> Result<Self, CkbRpcError> {
+        let key = hex::decode(key).map_err(|e| CkbRpcError::Decode(e.to_string()))?;
+        if key.len() != 32 {
+            return Err(CkbRpcError::Decode(String::from("the key is not 32 bytes")));
+        }
+        let key = Privkey::from_slice(&key);
+        key.pubkey()
+            .map_err(|e| CkbRpcError::Decode(e.to_string()))?;
+
+        Ok(Self {
+            key,
+            cell_dep: decode(cell_dep)?,
+        })
+    }
+}
+
+// the key is never printed.
+impl fmt::Debug for FundingSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FundingSigner")
+            .field("cell_dep", &self.cell_dep)
+            .finish()
+    }
+}
+
+/// the rollup's scripts & cell deps, builds the tx of every op.
+/// an output's capacity is the occupied capacity of the cell and its data.
+#[derive(Clone, Debug)]
+pub struct RollupTxBuilder {
+    pub rollup_lock: Script,
+    pub rollup_dep: CellDep,
+    pub commit_type: Script,
+    pub commit_type_dep: CellDep,
+    /// the upks cell, a cell dep of the txs after init.
+    pub upks_cell: OutPoint,
+    /// max accounts of the upks cell, in the header of the commit cell and the block.
+    pub account_size: u32,
+}
+
+impl RollupTxBuilder {
+    pub fn new(
+        rollup_lock: Script,
+        rollup_dep: CellDep,
+        commit_type: Script,
+        commit_type_dep: CellDep,
+        upks_cell: OutPoint,
+        account_size: u32,
+    ) -> Self {
+        Self {
+            rollup_lock,
+            rollup_dep,
+            commit_type,
+            commit_type_dep,
+            upks_cell,
+            account_size,
+        }
+    }
+
+    /// from the hex of the molecule bytes, as the rollup stores them.
+    pub fn from_hex(
+        rollup_lock: &str,
+        rollup_dep: &str,
+        commit_type: &str,
+        commit_type_dep: &str,
+        upks_cell: &str,
+        account_size: u32,
+    ) -> Result<Self, CkbRpcError> {
+        Ok(Self::new(
+            decode(rollup_lock)?,
+            decode(rollup_dep)?,
+            decode(commit_type)?,
+            decode(commit_type_dep)?,
+            decode(upks_cell)?,
+            account_size,
+        ))
+    }
+
+    /// init state of L2, the upks cell is created here and used as a cell_dep later,
+    /// the rollup lock's args is pinned to the upks' data hash, the commit type and escape blocks.
+    /// the seed cell is consumed to make the commit cell's type id.
+    /// every token has an empty UDT pool with its UDT type.
+    /// returns the builder of the pinned scripts, and the tx. the upks cell's out point
+    /// is known when the tx is funded, it is the last output before the change.
+    pub fn init<E: PairingEngine>(
+        &self,
+        seed: OutPoint,
+        udt_types: &[Script],
+        commit: &CellCommit<E>,
+        upks: &CellUpks<E>,
+        escape_blocks: u64,
+    ) -> Result<(Self, TransactionView), CkbRpcError> {
+        let upks_data = upks.to_cell_data();
+        let mut upks_hash = [0u8; 32];
+        upks_hash.copy_from_slice(CellOutput::calc_data_hash(&upks_data).as_slice());
+
+        let seed_input = CellInput::new_builder().previous_output(seed).build();
+        let commit_type = self
+            .commit_type
+            .clone()
+            .as_builder()
+            .args(Bytes::from(type_id(&seed_input, 0).to_vec()).pack())
+            .build();
+        let mut commit_type_hash = [0u8; 32];
+        commit_type_hash.copy_from_slice(commit_type.calc_script_hash().as_slice());
+
+        let lock_args = LockArgs {
+            upks_hash,
+            commit_type_hash,
+            escape_blocks,
+        };
+        let rollup_lock = self
+            .rollup_lock
+            .clone()
+            .as_builder()
+            .args(Bytes::from(lock_args.to_bytes()).pack())
+            .build();
+
+        let mut cells = vec![cell(
+            &rollup_lock,
+            Some(&commit_type),
+            commit.to_cell_data(upks.account_size, OP_INIT),
+        )?];
+        for udt_type in udt_types {
+            cells.push(udt_cell(&rollup_lock, udt_type, 0)?);
+        }
+        cells.push(cell(&rollup_lock, None, upks_data)?);
+
+        let (outputs, outputs_data): (Vec<_>, Vec<_>) = cells.into_iter().unzip();
+        let tx = TransactionBuilder::default()
+            .input(seed_input)
+            .outputs(outputs)
+            .outputs_data(outputs_data.pack())
+            .cell_dep(self.rollup_dep.clone())
+            .cell_dep(self.commit_type_dep.clone())
+            .build();
+
+        let builder = Self {
+            rollup_lock,
+            commit_type,
+            account_size: upks.account_size,
+            ..self.clone()
+        };
+
+        Ok((builder, tx))
+    }
+
+    /// a block of transfers & registers.
+    pub fn post_block<E: PairingEngine>(
+        &self,
+        commit: &CommitCell<E>,
+        block: &Block<E>,
+    ) -> Result<TransactionView, CkbRpcError> {
+        self.commit_tx(OP_POST_BLOCK, 0, commit, block, vec![], vec![])
+    }
+
+    /// a deposit block, the amount moves from the wallet to the token's pool.
+    pub fn deposit<E: PairingEngine>(
+        &self,
+        commit: &CommitCell<E>,
+        block: &Block<E>,
+        vault: &UdtCell,
+        wallet: &UdtCell,
+        wallet_lock: &Script,
+        amount: u128,
+    ) -> Result<TransactionView, CkbRpcError> {
+        let inputs = vec![input(&vault.out_point, 0), input(&wallet.out_point, 0)];
+        let pooled = vault
+            .amount
+            .checked_add(amount)
+            .ok_or_else(|| CkbRpcError::Overflow(String::from("vault amount")))?;
+        let cells = vec![
+            udt_cell(&self.rollup_lock, &vault.udt_type, pooled)?,
+            udt_cell(wallet_lock, &wallet.udt_type, take(wallet, amount)?)?,
+        ];
+
+        self.commit_tx(OP_DEPOSIT, 0, commit, block, inputs, cells)
+    }
+
+    /// a withdraw block, the amount moves from the token's pool to a new cell of the lock.
+    /// it is an escape when the since of the commit cell input is some.
+    pub fn withdraw<E: PairingEngine>(
+        &self,
+        commit: &CommitCell<E>,
+        block: &Block<E>,
+        vault: &UdtCell,
+        lock: &Script,
+        amount: u128,
+        escape_since: Option<u64>,
+    ) -> Result<TransactionView, CkbRpcError> {
+        let (op, since) = match escape_since {
+            Some(since) => (OP_ESCAPE, since),
+            None => (OP_WITHDRAW, 0),
+        };
+        let inputs = vec![input(&vault.out_point, 0)];
+        let cells = vec![
+            udt_cell(&self.rollup_lock, &vault.udt_type, take(vault, amount)?)?,
+            udt_cell(lock, &vault.udt_type, amount)?,
+        ];
+
+        self.commit_tx(op, since, commit, block, inputs, cells)
+    }
+
+    /// the commit cell is updated by the block, the block is in the witness.
+    /// the other inputs & outputs are after the commit cell's.
+    fn commit_tx<E: PairingEngine>(
+        &self,
+        op: u8,
+        since: u64,
+        commit: &CommitCell<E>,
+        block: &Block<E>,
+        inputs: Vec<CellInput>,
+        cells: Vec<(CellOutput, Bytes)>,
+    ) -> Result<TransactionView, CkbRpcError> {
+        let commit_cell = cell(
+            &self.rollup_lock,
+            Some(&self.commit_type),
+            block
+                .cell_commit(&commit.commit)
+                .map_err(CkbRpcError::InvalidBlock)?
+                .to_cell_data(self.account_size, op),
+        )?;
+        let (outputs, outputs_data): (Vec<_>, Vec<_>) =
+            Some(commit_cell).into_iter().chain(cells).unzip();
+        let upks_dep = CellDep::new_builder()
+            .out_point(self.upks_cell.clone())
+            .build();
+
+        Ok(TransactionBuilder::default()
+            .input(input(&commit.out_point, since))
+            .inputs(inputs)
+            .outputs(outputs)
+            .outputs_data(outputs_data.pack())
+            .cell_dep(self.rollup_dep.clone())
+            .cell_dep(self.commit_type_dep.clone())
+            .cell_dep(upks_dep)
+            .witness(block_witness(block.to_witness(self.account_size, op)).pack())
+            .build())
+    }
+}
+
+/// fund the tx: the funding cells are added as inputs until they pay the outputs'
+/// capacity and the fee at the fee rate, the rest is the change cell of the funding
+/// lock, always the last output. `inputs_capacity` is of the tx's own inputs, `own` are
+/// the indexes of its inputs of the funding lock.
+/// the first witness of the funding lock's group (the own inputs, then the funding
+/// inputs) has a zero signature, so the fee is of the signed tx, see `sign`.
+pub fn balance(
+    tx: TransactionView,
+    inputs_capacity: u64,
+    own: &[usize],
+    funding: &[FundingCell],
+    funding_lock: &Script,
+    fee_rate: u64,
+) -> Result<TransactionView, CkbRpcError> {
+    let outputs_capacity = tx
+        .outputs_capacity()
+        .map_err(|_| CkbRpcError::Overflow(String::from("outputs capacity")))?
+        .as_u64();
+    let (change, change_data) = cell(funding_lock, None, vec![])?;
+    let change_occupied: u64 = change.capacity().unpack();
+
+    let fund = |inputs: &[CellInput], change: CellOutput| -> Result<_, CkbRpcError> {
+        let mut witnesses: Vec<_> = tx.witnesses().into_iter().collect();
+        let first = tx.inputs().len();
+        if !inputs.is_empty() {
+            while witnesses.len() < first {
+                witnesses.push(Bytes::new().pack());
+            }
+            witnesses.insert(first, Bytes::new().pack());
+        }
+        let group = match own.first() {
+            Some(&i) => Some(i),
+            None if !inputs.is_empty() => Some(first),
+            None => None,
+        };
+        if let Some(i) = group {
+            while witnesses.len() <= i {
+                witnesses.push(Bytes::new().pack());
+            }
+            witnesses[i] =
+                signed_witness(&witnesses[i].raw_data(), vec![0; SIGNATURE_SIZE])?.pack();
+        }
+        Ok(tx
+            .as_advanced_builder()
+            .inputs(inputs.to_vec())
+            .output(change)
+            .output_data(change_data.pack())
+            .set_witnesses(witnesses)
+            .build())
+    };
+
+    let mut available = inputs_capacity;
+    let mut inputs = vec![];
+    let mut funding = funding.iter();
+    loop {
+        // the change's capacity is not known yet, it does not change the size.
+        let funded = fund(&inputs, change.clone())?;
+        let required = outputs_capacity
+            .checked_add(change_occupied)
+            .and_then(|required| required.checked_add(tx_fee(&funded, fee_rate)))
+            .ok_or_else(|| CkbRpcError::Overflow(String::from("outputs capacity")))?;
+        if available >= required {
+            let change = change
+                .as_builder()
+                .capacity((available - required + change_occupied).pack())
+                .build();
+            return fund(&inputs, change);
+        }
+
+        match funding.next() {
+            Some(cell) => {
+                available += cell.capacity;
+                inputs.push(input(&cell.out_point, 0));
+            }
+            None => {
+                return Err(CkbRpcError::InsufficientCapacity {
+                    required,
+                    available,
+                })
+            }
+        }
+    }
+}
+
+/// sign the group of the funding lock's inputs by CKB's sighash all: blake2b of the
+/// tx hash, the group's witnesses and the witnesses after the inputs, each after its
+/// length. the zero signature of `balance` in the first group witness is replaced,
+/// the tx's size is not changed.
+pub fn sign(
+    tx: TransactionView,
+    group: &[usize],
+    key: &Privkey,
+) -> Result<TransactionView, CkbRpcError> {
+    let mut witnesses: Vec<Bytes> = tx
+        .witnesses()
+        .into_iter()
+        .map(|witness| witness.raw_data())
+        .collect();
+    let first = match group.first() {
+        Some(&first) if first < witnesses.len() => first,
+        _ => return Err(CkbRpcError::Sign(String::from("no funding witness"))),
+    };
+
+    let mut hasher = new_blake2b();
+    hasher.update(tx.hash().as_slice());
+    let mut update = |witness: &Bytes| {
+        hasher.update(&(witness.len() as u64).to_le_bytes());
+        hasher.update(witness);
+    };
+    // the group's witnesses end at the first missing one, as the lock loads them.
+    for &i in group {
+        match witnesses.get(i) {
+            Some(witness) => update(witness),
+            None => break,
+        }
+    }
+    for witness in witnesses.iter().skip(tx.inputs().len()) {
+        update(witness);
+    }
+    let mut message = [0u8; 32];
+    hasher.finalize(&mut message);
+
+    let signature = key
+        .sign_recoverable(&H256::from(message))
+        .map_err(|e| CkbRpcError::Sign(e.to_string()))?;
+    witnesses[first] = signed_witness(&witnesses[first], signature.serialize())?;
+    Ok(tx
+        .as_advanced_builder()
+        .set_witnesses(
+            witnesses
+                .into_iter()
+                .map(|witness| witness.pack())
+                .collect(),
+        )
+        .build())
+}
+
+/// the fee of the tx's size in a block at the fee rate, rounded up.
+pub fn tx_fee(tx: &TransactionView, fee_rate: u64) -> u64 {
+    let size = tx.data().serialized_size_in_block() as u64;
+    (size * fee_rate + 999) / 1000
+}
+
+fn input(out_point: &OutPoint, since: u64) -> CellInput {
+    CellInput::new_builder()
+        .previous_output(out_point.clone())
+        .since(since.pack())
+        .build()
+}
+
+/// the output with the occupied capacity of the cell and its data.
+fn cell(
+    lock: &Script,
+    type_: Option<&Script>,
+    data: Vec<u8>,
+) -> Result<(CellOutput, Bytes), CkbRpcError> {
+    let output = CellOutput::new_builder()
+        .lock(lock.clone())
+        .type_(type_.cloned().pack())
+        .build();
+    let capacity = Capacity::bytes(data.len())
+        .and_then(|size| output.occupied_capacity(size))
+        .map_err(|_| CkbRpcError::Overflow(String::from("cell capacity")))?;
+
+    Ok((
+        output.as_builder().capacity(capacity.pack()).build(),
+        data.into(),
+    ))
+}
+
+/// the UDT amount is 16 bytes little endian.
+fn udt_cell(
+    lock: &Script,
+    udt_type: &Script,
+    amount: u128,
+) -> Result<(CellOutput, Bytes), CkbRpcError> {
+    cell(lock, Some(udt_type), amount.to_le_bytes().to_vec())
+}
+
+/// the UDT cell's amount after the amount is taken out.
+fn take(udt: &UdtCell, amount: u128) -> Result<u128, CkbRpcError> {
+    udt.amount
+        .checked_sub(amount)
+        .ok_or(CkbRpcError::InsufficientAmount {
+            required: amount,
+            available: udt.amount,
+        })
+}
+
+/// type id = blake2b(first input || output index), same as CKB's type id.
+fn type_id(first_input: &CellInput, index: u64) -> [u8; 32] {
+    let mut hasher = new_blake2b();
+    hasher.update(first_input.as_slice());
+    hasher.update(&index.to_le_bytes());
+    let mut id = [0u8; 32];
+    hasher.finalize(&mut id);
+    id
+}
+
+/// the witness with the signature in its lock, an empty witness is a new WitnessArgs.
+fn signed_witness(witness: &[u8], signature: Vec<u8>) -> Result<Bytes, CkbRpcError> {
+    let witness_args = if witness.is_empty() {
+        WitnessArgs::default()
+    } else {
+        WitnessArgs::from_slice(witness).map_err(|e| CkbRpcError::Sign(format!("{:?}", e)))?
+    };
+    Ok(witness_args
+        .as_builder()
+        .lock(Some(Bytes::from(signature)).pack())
+        .build()
+        .as_bytes())
+}
+
+/// the L2 block is in the lock of the commit cell input's witness.
+fn block_witness(block: Vec<u8>) -> Bytes {
+    WitnessArgs::new_builder()
+        .lock(Some(Bytes::from(block)).pack())
+        .build()
+        .as_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asvc_rollup::format::Header;
+    use ckb_tool::ckb_crypto::secp::Signature;
+    use ckb_zkp::curve::bn_256::Bn_256;
+    use ckb_zkp::math::Zero;
+    use ckb_zkp::scheme::asvc::key_gen;
+    use rand::thread_rng;
+
+    type Fr = <Bn_256 as PairingEngine>::Fr;
+
+    fn script(code_hash: u8, args: &[u8]) -> Script {
+        Script::new_builder()
+            .code_hash(Byte32::new_unchecked(Bytes::from(vec![code_hash; 32])))
+            .hash_type(Byte::new(1))
+            .args(Bytes::from(args.to_vec()).pack())
+            .build()
+    }
+
+    fn out_point(tx_hash: u8, index: u32) -> OutPoint {
+        OutPoint::new_builder()
+            .tx_hash(Byte32::new_unchecked(Bytes::from(vec![tx_hash; 32])))
+            .index(index.pack())
+            .build()
+    }
+
+    /// the builder, its commit cell without shards, and an empty block on it.
+    fn empty_rollup() -> (RollupTxBuilder, CommitCell<Bn_256>, Block<Bn_256>) {
+        let code = |tx_hash| {
+            CellDep::new_builder()
+                .out_point(out_point(tx_hash, 0))
+                .build()
+        };
+        let builder = RollupTxBuilder::new(
+            script(0xaa, &[0xbb; 40]),
+            code(0xf1),
+            script(0xcc, &[0xdd; 32]),
+            code(0xf2),
+            out_point(0xa1, 3),
+            2,
+        );
+        let commit = CommitCell {
+            out_point: out_point(0xa2, 0),
+            commit: CellCommit {
+                block_height: 0,
+                commits: vec![],
+                user_count: 0,
+            },
+        };
+        let block = Block {
+            block_height: 1,
+            fee_recipient: 0,
+            shards: vec![],
+            txs: vec![],
+            aggregate_sign: None,
+        };
+        (builder, commit, block)
+    }
+
+    fn vault(amount: u128) -> UdtCell {
+        UdtCell {
+            out_point: out_point(0xa3, 1),
+            udt_type: script(0xee, &[1]),
+            amount,
+        }
+    }
+
+    fn amount(tx: &TransactionView, i: usize) -> u128 {
+        let data = tx.outputs_data().get(i).unwrap().raw_data();
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&data);
+        u128::from_le_bytes(bytes)
+    }
+
+    fn op(tx: &TransactionView, i: usize) -> u8 {
+        let data = tx.outputs_data().get(i).unwrap().raw_data();
+        Header::open(&data).unwrap().0.op
+    }
+
+    fn capacity(tx: &TransactionView, i: usize) -> u64 {
+        tx.outputs().get(i).unwrap().capacity().unpack()
+    }
+
+    /// 8 bytes capacity, the scripts (code hash, hash type, args) and the data.
+    fn occupied(tx: &TransactionView, i: usize) -> u64 {
+        let output = tx.outputs().get(i).unwrap();
+        let script = |s: Script| 33 + s.args().raw_data().len();
+        let size = 8
+            + script(output.lock())
+            + output.type_().to_opt().map(script).unwrap_or(0)
+            + tx.outputs_data().get(i).unwrap().raw_data().len();
+        size as u64 * 100_000_000
+    }
+
+    #[test]
+    fn init_pins_scripts() {
+        let params = key_gen::<Bn_256, _>(4, &mut thread_rng()).unwrap();
+        let upks = CellUpks {
+            vk: params.verification_key,
+            omega: Fr::zero(),
+            account_size: 2,
+            shard_accounts: 2,
+            upks: params.proving_key.update_keys,
+            tokens: vec![[1u8; 32], [2u8; 32]],
+        };
+        let udt_types = vec![script(0xee, &[1]), script(0xee, &[2])];
+
+        let (builder, commit, _) = empty_rollup();
+        let (pinned, tx) = builder
+            .init(out_point(0xa0, 0), &udt_types, &commit.commit, &upks, 100)
+            .unwrap();
+
+        // commit, vaults, upks.
+        assert_eq!(tx.outputs().len(), 4);
+        assert_eq!(op(&tx, 0), OP_INIT);
+        assert_eq!(amount(&tx, 1), 0);
+        assert_eq!(
+            tx.outputs_data().get(3).unwrap().raw_data().to_vec(),
+            upks.to_cell_data()
+        );
+        for i in 0..4 {
+            assert_eq!(capacity(&tx, i), occupied(&tx, i));
+        }
+
+        let args: Bytes = pinned.rollup_lock.args().unpack();
+        let lock_args = LockArgs::from_bytes(&args).unwrap();
+        assert_eq!(lock_args.escape_blocks, 100);
+        assert_eq!(
+            &lock_args.upks_hash[..],
+            CellOutput::calc_data_hash(&upks.to_cell_data()).as_slice()
+        );
+        assert_eq!(
+            &lock_args.commit_type_hash[..],
+            pinned.commit_type.calc_script_hash().as_slice()
+        );
+        let args: Bytes = pinned.commit_type.args().unpack();
+        assert_eq!(&args[..], &type_id(&tx.inputs().get(0).unwrap(), 0)[..]);
+        for i in 0..4 {
+            assert_eq!(tx.outputs().get(i).unwrap().lock(), pinned.rollup_lock);
+        }
+        assert_eq!(tx.cell_deps().len(), 2);
+        assert_eq!(pinned.account_size, upks.account_size);
+    }
+
+    #[test]
+    fn post_block_updates_commit() {
+        let (builder, commit, block) = empty_rollup();
+        let tx = builder.post_block(&commit, &block).unwrap();
+
+        assert_eq!(tx.inputs().len(), 1);
+        assert_eq!(
+            tx.inputs().get(0).unwrap().previous_output(),
+            commit.out_point
+        );
+        assert_eq!(
+            tx.outputs_data().get(0).unwrap().raw_data().to_vec(),
+            block
+                .cell_commit(&commit.commit)
+                .unwrap()
+                .to_cell_data(builder.account_size, OP_POST_BLOCK)
+        );
+        assert_eq!(capacity(&tx, 0), occupied(&tx, 0));
+
+        // the upks cell is a cell dep, the block is in the witness.
+        let upks_dep = tx.cell_deps().get(2).unwrap();
+        assert_eq!(upks_dep.out_point(), builder.upks_cell);
+        let witness = WitnessArgs::from_slice(&tx.witnesses().get(0).unwrap().raw_data()).unwrap();
+        let lock: Bytes = witness.lock().to_opt().unwrap().unpack();
+        assert_eq!(
+            lock.to_vec(),
+            block.to_witness(builder.account_size, OP_POST_BLOCK)
+        );
+    }
+
+    #[test]
+    fn deposit_moves_udt_to_pool() {
+        let wallet = UdtCell {
+            out_point: out_point(0xa4, 0),
+            ..vault(100)
+        };
+        let wallet_lock = script(0x11, &[]);
+        let (builder, commit, block) = empty_rollup();
+        let tx = builder
+            .deposit(&commit, &block, &vault(10), &wallet, &wallet_lock, 30)
+            .unwrap();
+
+        let inputs: Vec<_> = tx
+            .inputs()
+            .into_iter()
+            .map(|i| i.previous_output())
+            .collect();
+        assert_eq!(
+            inputs,
+            vec![out_point(0xa2, 0), out_point(0xa3, 1), out_point(0xa4, 0)]
+        );
+        assert_eq!(op(&tx, 0), OP_DEPOSIT);
+        assert_eq!(amount(&tx, 1), 40);
+        assert_eq!(amount(&tx, 2), 70);
+        assert_eq!(tx.outputs().get(1).unwrap().lock(), builder.rollup_lock);
+        assert_eq!(tx.outputs().get(2).unwrap().lock(), wallet_lock);
+        for i in 0..3 {
+            assert_eq!(capacity(&tx, i), occupied(&tx, i));
+        }
+    }
+
+    #[test]
+    fn withdraw_and_escape() {
+        let lock = script(0x11, &[]);
+        let (builder, commit, block) = empty_rollup();
+        let tx = builder
+            .withdraw(&commit, &block, &vault(100), &lock, 30, None)
+            .unwrap();
+        let since: u64 = tx.inputs().get(0).unwrap().since().unpack();
+        assert_eq!(since, 0);
+        assert_eq!(op(&tx, 0), OP_WITHDRAW);
+        assert_eq!(amount(&tx, 1), 70);
+        assert_eq!(amount(&tx, 2), 30);
+        assert_eq!(tx.outputs().get(2).unwrap().lock(), lock);
+
+        let tx = builder
+            .withdraw(&commit, &block, &vault(100), &lock, 30, Some(5))
+            .unwrap();
+        let since: u64 = tx.inputs().get(0).unwrap().since().unpack();
+        assert_eq!(since, 5);
+        assert_eq!(op(&tx, 0), OP_ESCAPE);
+
+        // the pool on L1 has less than the amount.
+        match builder.withdraw(&commit, &block, &vault(20), &lock, 30, None) {
+            Err(CkbRpcError::InsufficientAmount {
+                required,
+                available,
+            }) => assert_eq!((required, available), (30, 20)),
+            res => panic!("unexpected: {:?}", res.map(|tx| tx.hash())),
+        }
+    }
+
+    fn funding(tx_hash: u8, ckb: u64) -> FundingCell {
+        FundingCell {
+            out_point: out_point(tx_hash, 0),
+            capacity: ckb * 100_000_000,
+        }
+    }
+
+    #[test]
+    fn balance_funds_fee_and_change() {
+        let (builder, commit, block) = empty_rollup();
+        let tx = builder.post_block(&commit, &block).unwrap();
+        let commit_capacity = capacity(&tx, 0);
+        let lock = script(0x11, &[]);
+        // the change cell occupies 41 CKB.
+        let cells = vec![funding(0xb1, 30), funding(0xb2, 30), funding(0xb3, 30)];
+
+        // the commit cell input pays its output, the first two funding cells are enough.
+        let funded = balance(tx, commit_capacity, &[], &cells, &lock, DEFAULT_FEE_RATE).unwrap();
+        assert_eq!(funded.inputs().len(), 3);
+        assert_eq!(
+            funded.inputs().get(2).unwrap().previous_output(),
+            out_point(0xb2, 0)
+        );
+        assert_eq!(funded.outputs().len(), 2);
+        let change = funded.outputs().get(1).unwrap();
+        assert_eq!(change.lock(), lock);
+        assert!(change.type_().to_opt().is_none());
+
+        let fee = commit_capacity + 60 * 100_000_000 - capacity(&funded, 0) - capacity(&funded, 1);
+        assert_eq!(fee, tx_fee(&funded, DEFAULT_FEE_RATE));
+        assert!(fee > 0);
+        assert!(capacity(&funded, 1) >= occupied(&funded, 1));
+    }
+
+    #[test]
+    fn sign_in_the_funded_size() {
+        let (builder, commit, block) = empty_rollup();
+        let tx = builder.post_block(&commit, &block).unwrap();
+        let commit_capacity = capacity(&tx, 0);
+        let lock = script(0x11, &[]);
+        let funded = balance(
+            tx,
+            commit_capacity,
+            &[],
+            &[funding(0xb1, 100)],
+            &lock,
+            DEFAULT_FEE_RATE,
+        )
+        .unwrap();
+        // the block's witness, then the funding input's.
+        assert_eq!(funded.witnesses().len(), 2);
+
+        let signed = sign(funded.clone(), &[1], &Privkey::from_slice(&[1u8; 32])).unwrap();
+        assert_eq!(signed.hash(), funded.hash());
+        assert_eq!(
+            signed.witnesses().get(0).unwrap().raw_data(),
+            funded.witnesses().get(0).unwrap().raw_data()
+        );
+        assert_eq!(
+            tx_fee(&signed, DEFAULT_FEE_RATE),
+            tx_fee(&funded, DEFAULT_FEE_RATE)
+        );
+        let witness =
+            WitnessArgs::from_slice(&signed.witnesses().get(1).unwrap().raw_data()).unwrap();
+        let signature: Bytes = witness.lock().to_opt().unwrap().unpack();
+        assert_eq!(signature.len(), SIGNATURE_SIZE);
+        assert_ne!(signature.to_vec(), vec![0; SIGNATURE_SIZE]);
+    }
+
+    #[test]
+    fn sign_own_inputs_in_the_group() {
+        let params = key_gen::<Bn_256, _>(4, &mut thread_rng()).unwrap();
+        let upks = CellUpks {
+            vk: params.verification_key,
+            omega: Fr::zero(),
+            account_size: 2,
+            shard_accounts: 2,
+            upks: params.proving_key.update_keys,
+            tokens: vec![[1u8; 32]],
+        };
+        let (builder, commit, _) = empty_rollup();
+        // the seed cell is of the funding lock.
+        let seed = funding(0xb0, 10);
+        let (_, tx) = builder
+            .init(
+                seed.out_point.clone(),
+                &[script(0xee, &[1])],
+                &commit.commit,
+                &upks,
+                100,
+            )
+            .unwrap();
+        let lock = script(0x11, &[]);
+        let funded = balance(
+            tx,
+            seed.capacity,
+            &[0],
+            &[funding(0xb1, 10_000)],
+            &lock,
+            DEFAULT_FEE_RATE,
+        )
+        .unwrap();
+        // the seed's witness has the zero signature, the funding input's is empty.
+        assert_eq!(funded.inputs().len(), 2);
+        assert_eq!(funded.witnesses().len(), 2);
+        let placeholder = funded.witnesses().get(0).unwrap().raw_data();
+        assert_eq!(
+            placeholder,
+            signed_witness(&[], vec![0; SIGNATURE_SIZE]).unwrap()
+        );
+        assert!(funded.witnesses().get(1).unwrap().raw_data().is_empty());
+
+        let key = Privkey::from_slice(&[1u8; 32]);
+        let signed = sign(funded.clone(), &[0, 1], &key).unwrap();
+        assert_eq!(signed.hash(), funded.hash());
+        assert_eq!(
+            tx_fee(&signed, DEFAULT_FEE_RATE),
+            tx_fee(&funded, DEFAULT_FEE_RATE)
+        );
+
+        // the message is of the tx hash and both witnesses of the group.
+        let mut hasher = new_blake2b();
+        hasher.update(funded.hash().as_slice());
+        for witness in &[placeholder, Bytes::new()] {
+            hasher.update(&(witness.len() as u64).to_le_bytes());
+            hasher.update(witness);
+        }
+        let mut message = [0u8; 32];
+        hasher.finalize(&mut message);
+
+        let witness =
+            WitnessArgs::from_slice(&signed.witnesses().get(0).unwrap().raw_data()).unwrap();
+        let signature: Bytes = witness.lock().to_opt().unwrap().unpack();
+        let pubkey = Signature::from_slice(&signature)
+            .unwrap()
+            .recover(&H256::from(message))
+            .unwrap();
+        assert_eq!(pubkey, key.pubkey().unwrap());
+    }
+
+    #[test]
+    fn balance_insufficient_capacity() {
+        let (builder, commit, block) = empty_rollup();
+        let tx = builder.post_block(&commit, &block).unwrap();
+        let commit_capacity = capacity(&tx, 0);
+        let lock = script(0x11, &[]);
+
+        match balance(
+            tx,
+            commit_capacity,
+            &[],
+            &[funding(0xb1, 1)],
+            &lock,
+            DEFAULT_FEE_RATE,
+        ) {
+            Err(CkbRpcError::InsufficientCapacity {
+                required,
+                available,
+            }) => {
+                assert_eq!(available, commit_capacity + 100_000_000);
+                assert!(required > available);
+            }
+            res => panic!("unexpected: {:?}", res.map(|tx| tx.hash())),
+        }
+    }
+}
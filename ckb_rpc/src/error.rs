@@ -0,0 +1,93 @@
+use std::fmt;
+
+/// CKB's `TransactionFailedToResolve`, an input or cell dep is dead or unknown.
+const TX_FAILED_TO_RESOLVE: i64 = -301;
+/// CKB's `TransactionFailedToVerify`, a script returns an error.
+const TX_FAILED_TO_VERIFY: i64 = -302;
+/// CKB's tx pool rejections are -1102 ..= -1199.
+const POOL_REJECTED: std::ops::RangeInclusive<i64> = -1199..=-1102;
+
+/// the failure of a ckb_rpc call.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CkbRpcError {
+    /// the node is not reachable, or the request timeout.
+    Transport(String),
+    /// the node returns a JSON-RPC error object.
+    Rpc { code: i64, message: String },
+    /// the response is not in the expected shape.
+    Malformed(String),
+    /// a hex or molecule value can not be decoded.
+    Decode(String),
+    /// the cell (or the rollup's init tx) is not found on L1.
+    CellNotFound(String),
+    /// the node rejects the tx in verification or by the tx pool.
+    Rejected(String),
+    /// the funding cells can not pay the outputs' capacity and the fee, in shannons.
+    InsufficientCapacity { required: u64, available: u64 },
+    /// the L2 block can not update the commit cell.
+    InvalidBlock(String),
+    /// the UDT cell has less than the amount.
+    InsufficientAmount { required: u128, available: u128 },
+    /// the capacity or the UDT amount of a cell overflows.
+    Overflow(String),
+    /// the funding inputs can not be signed.
+    Sign(String),
+}
+
+impl CkbRpcError {
+    /// the error of `send_transaction`, resolving & verifying failures are typed.
+    pub(crate) fn on_send(self) -> Self {
+        match self {
+            CkbRpcError::Rpc { code, message } if code == TX_FAILED_TO_RESOLVE => {
+                CkbRpcError::CellNotFound(message)
+            }
+            CkbRpcError::Rpc { code, message }
+                if code == TX_FAILED_TO_VERIFY || POOL_REJECTED.contains(&code) =>
+            {
+                CkbRpcError::Rejected(message)
+            }
+            error => error,
+        }
+    }
+}
+
+impl fmt::Display for CkbRpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CkbRpcError::Transport(e) => write!(f, "CKB node is unreachable: {}", e),
+            CkbRpcError::Rpc { code, message } => {
+                write!(f, "CKB node error {}: {}", code, message)
+            }
+            CkbRpcError::Malformed(e) => write!(f, "malformed CKB response: {}", e),
+            CkbRpcError::Decode(e) => write!(f, "invalid hex or molecule: {}", e),
+            CkbRpcError::CellNotFound(e) => write!(f, "cell not found on L1: {}", e),
+            CkbRpcError::Rejected(e) => write!(f, "tx rejected by CKB: {}", e),
+            CkbRpcError::InsufficientCapacity {
+                required,
+                available,
+            } => write!(
+                f,
+                "funding cells are not enough: {} shannons required, {} available",
+                required, available
+            ),
+            CkbRpcError::InvalidBlock(e) => write!(f, "invalid L2 block: {}", e),
+            CkbRpcError::InsufficientAmount {
+                required,
+                available,
+            } => write!(
+                f,
+                "UDT cell is not enough: {} required, {} available",
+                required, available
+            ),
+            CkbRpcError::Overflow(e) => write!(f, "{} overflow", e),
+            CkbRpcError::Sign(e) => write!(f, "can not sign the funding inputs: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CkbRpcError {}
+
+/// the response is not in the expected shape at the field.
+pub(crate) fn malformed(field: &str) -> CkbRpcError {
+    CkbRpcError::Malformed(format!("invalid {}", field))
+}
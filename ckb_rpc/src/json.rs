@@ -0,0 +1,229 @@
+//! the standard CKB JSON-RPC formats, converted from & to the molecule types.
+//! numbers are 0x-prefixed hex, bytes are 0x-prefixed hex.
+
+use serde_json::{json, Value};
+
+use ckb_tool::ckb_types::{bytes::Bytes, packed::*, prelude::*};
+
+use crate::error::{malformed, CkbRpcError};
+use crate::{Cell, L1Tx};
+
+pub fn hex_u64(value: &Value) -> Result<u64, CkbRpcError> {
+    let s = value.as_str().ok_or_else(|| malformed("number"))?;
+    if !s.starts_with("0x") {
+        return Err(malformed("number"));
+    }
+    u64::from_str_radix(&s[2..], 16).map_err(|_| malformed("number"))
+}
+
+pub fn hex_bytes(value: &Value) -> Result<Vec<u8>, CkbRpcError> {
+    let s = value.as_str().ok_or_else(|| malformed("bytes"))?;
+    if !s.starts_with("0x") {
+        return Err(malformed("bytes"));
+    }
+    hex::decode(&s[2..]).map_err(|e| CkbRpcError::Decode(e.to_string()))
+}
+
+pub fn byte32(value: &Value) -> Result<Byte32, CkbRpcError> {
+    let bytes = hex_bytes(value)?;
+    if bytes.len() != 32 {
+        return Err(malformed("hash"));
+    }
+    Ok(Byte32::new_unchecked(bytes.into()))
+}
+
+pub fn number(n: u64) -> Value {
+    json!(format!("{:#x}", n))
+}
+
+fn bytes(b: &[u8]) -> Value {
+    json!(format!("0x{}", hex::encode(b)))
+}
+
+pub fn script(value: &Value) -> Result<Script, CkbRpcError> {
+    let hash_type = match value["hash_type"].as_str() {
+        Some("data") => 0u8,
+        Some("type") => 1u8,
+        Some("data1") => 2u8,
+        _ => return Err(malformed("hash_type")),
+    };
+    let args: Bytes = hex_bytes(&value["args"])?.into();
+
+    Ok(Script::new_builder()
+        .code_hash(byte32(&value["code_hash"])?)
+        .hash_type(Byte::new(hash_type))
+        .args(args.pack())
+        .build())
+}
+
+pub fn script_json(script: &Script) -> Value {
+    let hash_type = match script.hash_type().as_slice()[0] {
+        0 => "data",
+        1 => "type",
+        _ => "data1",
+    };
+    let args: Bytes = script.args().unpack();
+
+    json!({
+        "code_hash": bytes(script.code_hash().as_slice()),
+        "hash_type": hash_type,
+        "args": bytes(&args),
+    })
+}
+
+pub fn out_point(value: &Value) -> Result<OutPoint, CkbRpcError> {
+    Ok(OutPoint::new_builder()
+        .tx_hash(byte32(&value["tx_hash"])?)
+        .index((hex_u64(&value["index"])? as u32).pack())
+        .build())
+}
+
+pub fn out_point_json(out_point: &OutPoint) -> Value {
+    let index: u32 = out_point.index().unpack();
+    json!({
+        "tx_hash": bytes(out_point.tx_hash().as_slice()),
+        "index": number(index as u64),
+    })
+}
+
+/// the cell of the output & data, the type is empty when none.
+pub fn cell(output: &Value, data: &Value, out_point: &OutPoint) -> Result<Cell, CkbRpcError> {
+    let type_ = match &output["type"] {
+        Value::Null => String::new(),
+        type_ => hex::encode(script(type_)?.as_slice()),
+    };
+
+    Ok(Cell {
+        lock: hex::encode(script(&output["lock"])?.as_slice()),
+        out_point: hex::encode(out_point.as_slice()),
+        data: hex_bytes(data)?,
+        type_,
+    })
+}
+
+/// the tx in a block or from `get_transaction`.
+pub fn l1_tx(tx: &Value) -> Result<L1Tx, CkbRpcError> {
+    let tx_hash = byte32(&tx["hash"])?;
+    let outputs = tx["outputs"]
+        .as_array()
+        .ok_or_else(|| malformed("outputs"))?;
+    let outputs_data = tx["outputs_data"]
+        .as_array()
+        .ok_or_else(|| malformed("outputs_data"))?;
+    if outputs.len() != outputs_data.len() {
+        return Err(malformed("outputs_data"));
+    }
+
+    let mut cells = vec![];
+    for (i, (output, data)) in outputs.iter().zip(outputs_data).enumerate() {
+        let out_point = OutPoint::new_builder()
+            .tx_hash(tx_hash.clone())
+            .index((i as u32).pack())
+            .build();
+        cells.push(cell(output, data, &out_point)?);
+    }
+
+    let mut witnesses = vec![];
+    for witness in tx["witnesses"]
+        .as_array()
+        .ok_or_else(|| malformed("witnesses"))?
+    {
+        witnesses.push(hex_bytes(witness)?);
+    }
+
+    let mut cell_deps = vec![];
+    for dep in tx["cell_deps"]
+        .as_array()
+        .ok_or_else(|| malformed("cell_deps"))?
+    {
+        let dep_type = match dep["dep_type"].as_str() {
+            Some("code") => 0u8,
+            Some("dep_group") => 1u8,
+            _ => return Err(malformed("dep_type")),
+        };
+        let dep = CellDep::new_builder()
+            .out_point(out_point(&dep["out_point"])?)
+            .dep_type(Byte::new(dep_type))
+            .build();
+        cell_deps.push(hex::encode(dep.as_slice()));
+    }
+
+    Ok(L1Tx {
+        outputs: cells,
+        witnesses,
+        cell_deps,
+    })
+}
+
+/// the tx of `send_transaction`.
+pub fn tx_json(tx: &Transaction) -> Value {
+    let raw = tx.raw();
+    let version: u32 = raw.version().unpack();
+
+    let cell_deps: Vec<Value> = raw
+        .cell_deps()
+        .into_iter()
+        .map(|dep| {
+            let dep_type = match dep.dep_type().as_slice()[0] {
+                0 => "code",
+                _ => "dep_group",
+            };
+            json!({
+                "out_point": out_point_json(&dep.out_point()),
+                "dep_type": dep_type,
+            })
+        })
+        .collect();
+    let header_deps: Vec<Value> = raw
+        .header_deps()
+        .into_iter()
+        .map(|hash| bytes(hash.as_slice()))
+        .collect();
+    let inputs: Vec<Value> = raw
+        .inputs()
+        .into_iter()
+        .map(|input| {
+            let since: u64 = input.since().unpack();
+            json!({
+                "since": number(since),
+                "previous_output": out_point_json(&input.previous_output()),
+            })
+        })
+        .collect();
+    let outputs: Vec<Value> = raw
+        .outputs()
+        .into_iter()
+        .map(|output| {
+            let capacity: u64 = output.capacity().unpack();
+            let type_ = match output.type_().to_opt() {
+                Some(type_) => script_json(&type_),
+                None => Value::Null,
+            };
+            json!({
+                "capacity": number(capacity),
+                "lock": script_json(&output.lock()),
+                "type": type_,
+            })
+        })
+        .collect();
+    let outputs_data: Vec<Value> = raw
+        .outputs_data()
+        .into_iter()
+        .map(|data| bytes(&data.raw_data()))
+        .collect();
+    let witnesses: Vec<Value> = tx
+        .witnesses()
+        .into_iter()
+        .map(|witness| bytes(&witness.raw_data()))
+        .collect();
+
+    json!({
+        "version": number(version as u64),
+        "cell_deps": cell_deps,
+        "header_deps": header_deps,
+        "inputs": inputs,
+        "outputs": outputs,
+        "outputs_data": outputs_data,
+        "witnesses": witnesses,
+    })
+}
@@ -1,13 +1,31 @@
+use async_std::{future::timeout, task};
 use serde_json::{json, Value};
+use std::time::Duration;
 
-use ckb_tool::ckb_types::{
-    bytes::Bytes,
-    core::{Capacity, TransactionBuilder},
-    packed::*,
-    prelude::*,
+use asvc_rollup::format::{Header, LockArgs, OP_DEPOSIT, OP_ESCAPE, OP_INIT, OP_UPKS, OP_WITHDRAW};
+
+use ckb_tool::ckb_types::{bytes::Bytes, core::TransactionView, packed::*, prelude::*};
+
+mod builder;
+mod error;
+mod json;
+
+pub use builder::{
+    balance, sign, tx_fee, CommitCell, FundingCell, FundingSigner, RollupTxBuilder, UdtCell,
+    DEFAULT_FEE_RATE, SIGNATURE_SIZE,
 };
+pub use error::CkbRpcError;
+
+use error::malformed;
+
+/// default CKB node, the mock chain listens here.
+pub const DEFAULT_NODE_URL: &'static str = "http://127.0.0.1:8114";
 
-const NODE_RPC_ADDR: &'static str = "http://127.0.0.1:8114";
+/// since flag: relative & block number metric.
+const SINCE_RELATIVE_BLOCK: u64 = 0x8000_0000_0000_0000;
+
+/// cells of an indexer `get_cells` page.
+const INDEXER_PAGE: u64 = 100;
 
 fn jsonrpc(method: &str, params: Value) -> Value {
     json!(
@@ -20,507 +38,759 @@ fn jsonrpc(method: &str, params: Value) -> Value {
     )
 }
 
-pub async fn deploy_contract(name: &str) -> Result<(String, String, String, String), ()> {
-    match surf::post(format!("{}/deploy", NODE_RPC_ADDR))
-        .body_json(&json!({ "contract": name }))
-        .map_err(|_e| ())?
-        .await
-    {
-        Ok(mut res) => match res.body_json::<Value>().await {
-            Ok(mut value) => {
-                let result = value["result"].take();
-                let rollup_lock = result[0].as_str().ok_or(())?;
-                let rollup_lock_dep = result[1].as_str().ok_or(())?;
-                let udt_lock = result[2].as_str().ok_or(())?;
-                let my_udt = result[3].as_str().ok_or(())?;
-
-                Ok((
-                    rollup_lock.to_owned(),
-                    rollup_lock_dep.to_owned(),
-                    udt_lock.to_owned(),
-                    my_udt.to_owned(),
-                ))
-            }
-            Err(err) => {
-                println!("JSONRPC err: {:?}", err);
-                Err(())
-            }
-        },
-        Err(err) => {
-            println!("RPC deploy contract error: {:?}", err);
-            Err(())
+/// the RPC dialect of the node.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Dialect {
+    /// mock-ckb: decimal numbers, a block is its txs, and `send_transaction`
+    /// returns the created out points.
+    Mock,
+    /// the standard CKB JSON-RPC, and the indexer's `get_cells`.
+    Ckb,
+}
+
+/// a L1 cell, scripts & out points are hex of the molecule bytes.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Cell {
+    pub lock: String,
+    pub out_point: String,
+    pub data: Vec<u8>,
+    /// empty when none.
+    pub type_: String,
+}
+
+/// the deployed contracts and the deployer's wallet, hex of the molecule bytes.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Deployment {
+    pub rollup_lock: String,
+    pub rollup_dep: String,
+    pub udt_lock: String,
+    /// the wallet's UDT cells, the index is the token id.
+    pub my_udts: Vec<String>,
+    pub commit_type: String,
+    pub commit_type_dep: String,
+    /// the cell consumed by the init tx, for the commit cell's type id.
+    pub seed_cell: String,
+    /// the index is the token id.
+    pub udt_types: Vec<String>,
+}
+
+/// a L1 tx, the cell deps are hex of the molecule bytes.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct L1Tx {
+    pub outputs: Vec<Cell>,
+    pub witnesses: Vec<Vec<u8>>,
+    pub cell_deps: Vec<String>,
+}
+
+/// the client of a CKB node's RPC.
+#[derive(Clone, Debug)]
+pub struct CkbClient {
+    /// the node's RPC url.
+    pub url: String,
+    /// the indexer's RPC url, the node's url when the node has the indexer.
+    pub indexer_url: String,
+    pub dialect: Dialect,
+    /// timeout of a request.
+    pub timeout: Duration,
+    /// retries of a failed read, sending a tx is never retried.
+    pub retries: u32,
+    /// wait between the retries.
+    pub retry_interval: Duration,
+    /// shannons per 1000 bytes, the fee of the funded txs.
+    pub fee_rate: u64,
+    /// signs the funding inputs, none when the funding lock needs no signature
+    /// (the mock chain's).
+    pub signer: Option<FundingSigner>,
+}
+
+impl Default for CkbClient {
+    fn default() -> Self {
+        Self {
+            url: DEFAULT_NODE_URL.to_owned(),
+            indexer_url: DEFAULT_NODE_URL.to_owned(),
+            dialect: Dialect::Mock,
+            timeout: Duration::from_secs(30),
+            retries: 3,
+            retry_interval: Duration::from_secs(1),
+            fee_rate: DEFAULT_FEE_RATE,
+            signer: None,
         }
     }
 }
 
-pub async fn listen_blocks(
-    block_height: u64,
-    rollup_hash: &String,
-) -> Result<Vec<(Vec<(Vec<u8>, String, String, Option<(String, u128)>)>, u64)>, ()> {
-    let rollup_lock = Script::new_unchecked(hex::decode(rollup_hash).unwrap().into());
-
-    //get_tip_block_number
-    let now_height = match surf::post(NODE_RPC_ADDR)
-        .body_json(&jsonrpc("get_tip_block_number", json!(vec![""])))
-        .map_err(|_e| ())?
-        .await
-    {
-        Ok(mut res) => match res.body_json::<Value>().await {
-            Ok(mut value) => {
-                let result = value["result"].take();
-                result.as_u64().ok_or(())?
+impl CkbClient {
+    /// the mock-ckb node at the url.
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_owned(),
+            indexer_url: url.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// a CKB node at the url, speaks the standard JSON-RPC.
+    pub fn ckb(url: &str) -> Self {
+        Self {
+            dialect: Dialect::Ckb,
+            ..Self::new(url)
+        }
+    }
+
+    /// post the body to the url once, in the timeout.
+    async fn post(&self, url: &str, body: &Value) -> Result<Value, CkbRpcError> {
+        let req = surf::post(url)
+            .body_json(body)
+            .map_err(|e| CkbRpcError::Malformed(e.to_string()))?;
+        let res = async {
+            let mut res = req
+                .await
+                .map_err(|e| CkbRpcError::Transport(format!("{}: {}", url, e)))?;
+            res.body_json::<Value>()
+                .await
+                .map_err(|e| CkbRpcError::Malformed(e.to_string()))
+        };
+
+        timeout(self.timeout, res)
+            .await
+            .map_err(|_| CkbRpcError::Transport(format!("{}: timeout", url)))?
+    }
+
+    /// JSON-RPC read call, retried when the node is unreachable. returns the result.
+    async fn call_at(&self, url: &str, method: &str, params: Value) -> Result<Value, CkbRpcError> {
+        let body = jsonrpc(method, params);
+        let mut retries = 0;
+        loop {
+            match self.post(url, &body).await.and_then(result) {
+                Err(CkbRpcError::Transport(_)) if retries < self.retries => {
+                    retries += 1;
+                    task::sleep(self.retry_interval).await;
+                }
+                res => return res,
             }
-            Err(err) => {
-                println!("JSONRPC listen block height err: {:?}", err);
-                return Err(());
+        }
+    }
+
+    async fn call(&self, method: &str, params: Value) -> Result<Value, CkbRpcError> {
+        self.call_at(&self.url, method, params).await
+    }
+
+    /// the block number param.
+    fn number(&self, number: u64) -> Value {
+        match self.dialect {
+            Dialect::Mock => json!(format!("{}", number)),
+            Dialect::Ckb => json::number(number),
+        }
+    }
+
+    pub async fn deploy_contract(
+        &self,
+        name: &str,
+        type_name: &str,
+    ) -> Result<Deployment, CkbRpcError> {
+        let value = self
+            .post(
+                &format!("{}/deploy", self.url),
+                &json!({ "contract": name, "type_contract": type_name }),
+            )
+            .await?;
+
+        let result = result(value)?;
+        let string = |i: usize, field: &str| {
+            result[i]
+                .as_str()
+                .map(|s| s.to_owned())
+                .ok_or_else(|| malformed(field))
+        };
+
+        Ok(Deployment {
+            rollup_lock: string(0, "rollup_lock")?,
+            rollup_dep: string(1, "rollup_lock_dep")?,
+            udt_lock: string(2, "udt_lock")?,
+            my_udts: str_array(&result[3])?,
+            commit_type: string(4, "commit_type")?,
+            commit_type_dep: string(5, "commit_type_dep")?,
+            seed_cell: string(6, "seed_cell")?,
+            udt_types: str_array(&result[7])?,
+        })
+    }
+
+    /// the rollup's init tx, found by the commit cell's type (type id, so only one).
+    /// returns the L1 height, the rollup lock, its outputs and the cell deps.
+    /// the L2 blocks start from the height.
+    pub async fn listen_genesis(
+        &self,
+        commit_type: &String,
+    ) -> Result<(u64, String, Vec<Cell>, Vec<String>), CkbRpcError> {
+        if self.dialect == Dialect::Ckb {
+            return self.find_genesis(commit_type).await;
+        }
+
+        let now_height = self.next_block_number().await?;
+
+        for i in 0..now_height {
+            let (_, transactions) = self.get_block(i).await?;
+            for tx in transactions {
+                if let Some((rollup_lock, outputs, cell_deps)) = genesis(tx, commit_type) {
+                    return Ok((i, rollup_lock, outputs, cell_deps));
+                }
             }
-        },
-        Err(err) => {
-            println!("Listening query err: {:?}", err);
-            return Err(());
         }
-    };
 
-    println!("now_height: {:?}", now_height);
+        Err(CkbRpcError::CellNotFound(String::from(
+            "the rollup's init tx",
+        )))
+    }
+
+    /// the init tx by the indexer, not scan the whole chain: the commit cell has the
+    /// rollup lock, and the upks cell of the rollup lock is never spent, it is in the init tx.
+    async fn find_genesis(
+        &self,
+        commit_type: &String,
+    ) -> Result<(u64, String, Vec<Cell>, Vec<String>), CkbRpcError> {
+        let commit_cells = self.get_cells(commit_type, "type").await?;
+        let (commit_cell, _) = commit_cells
+            .get(0)
+            .ok_or_else(|| CkbRpcError::CellNotFound(String::from("the commit cell")))?;
+
+        for (cell, height) in self.get_cells(&commit_cell.lock, "lock").await? {
+            match Header::open(&cell.data) {
+                Ok((header, _)) if header.op == OP_UPKS && cell.type_.is_empty() => {}
+                _ => continue,
+            }
 
-    if now_height <= block_height {
-        return Ok(vec![]);
+            let out_point = decode::<OutPoint>(&cell.out_point)?;
+            let result = self
+                .call(
+                    "get_transaction",
+                    json!(vec![format!(
+                        "0x{}",
+                        hex::encode(out_point.tx_hash().as_slice())
+                    )]),
+                )
+                .await?;
+            let tx = json::l1_tx(&result["transaction"])?;
+            if let Some((rollup_lock, outputs, cell_deps)) = genesis(tx, commit_type) {
+                return Ok((height, rollup_lock, outputs, cell_deps));
+            }
+        }
+
+        Err(CkbRpcError::CellNotFound(String::from(
+            "the rollup's init tx",
+        )))
     }
 
-    let mut blocks = vec![];
+    /// the number of the next L1 block, the blocks before it are on L1.
+    async fn next_block_number(&self) -> Result<u64, CkbRpcError> {
+        let result = self
+            .call("get_tip_block_number", json!(Vec::<String>::new()))
+            .await?;
+        match self.dialect {
+            Dialect::Mock => result.as_u64().ok_or_else(|| malformed("tip block number")),
+            Dialect::Ckb => json::hex_u64(&result).map(|tip| tip + 1),
+        }
+    }
 
-    for i in block_height..now_height {
-        println!("START GOT BLOCK: {}", i);
+    /// the L1 block's hash & txs.
+    async fn get_block(&self, number: u64) -> Result<(String, Vec<L1Tx>), CkbRpcError> {
+        match self.dialect {
+            Dialect::Mock => {
+                // the block is changed by a reorg when getting its txs, get it in next listen.
+                let hash = self
+                    .get_block_hash(number)
+                    .await?
+                    .ok_or_else(|| malformed("block hash"))?;
+                let result = self
+                    .call("get_block", json!(vec![self.number(number)]))
+                    .await?;
+                if self.get_block_hash(number).await? != Some(hash.clone()) {
+                    return Err(CkbRpcError::Malformed(format!(
+                        "block {} is changed by a reorg",
+                        number
+                    )));
+                }
 
-        let mut tmp_block = vec![];
+                let mut txs = vec![];
+                for tx in result.as_array().ok_or_else(|| malformed("block"))? {
+                    txs.push(mock_tx(tx)?);
+                }
+                Ok((hash, txs))
+            }
+            Dialect::Ckb => {
+                // get the block by the header's hash, so the txs are of the hash.
+                let header = self
+                    .call("get_header_by_number", json!(vec![self.number(number)]))
+                    .await?;
+                let hash = header["hash"]
+                    .as_str()
+                    .ok_or_else(|| malformed("header hash"))?
+                    .to_owned();
+                let block = self.call("get_block", json!(vec![hash.clone()])).await?;
+
+                let mut txs = vec![];
+                for tx in block["transactions"]
+                    .as_array()
+                    .ok_or_else(|| malformed("transactions"))?
+                {
+                    txs.push(json::l1_tx(tx)?);
+                }
+                Ok((hash, txs))
+            }
+        }
+    }
 
-        // get block info
-        if let Ok(mut res) = surf::post(NODE_RPC_ADDR)
-            .body_json(&jsonrpc("get_block", json!(vec![format!("{}", i)])))
-            .map_err(|_e| ())?
-            .await
+    /// the hash of the L1 block at the height, none when the chain is lower.
+    pub async fn get_block_hash(&self, number: u64) -> Result<Option<String>, CkbRpcError> {
+        match self
+            .call("get_block_hash", json!(vec![self.number(number)]))
+            .await?
         {
-            let result = res.body_json::<Value>().await.map_err(|_| ())?;
-            let transactions = result["result"].as_array().ok_or(())?;
-
-            for mock_tx in transactions {
-                let cells = mock_tx.as_array().unwrap();
-
-                let first_cell = cells[0].as_array().unwrap();
-
-                let first_lock = Script::new_unchecked(
-                    hex::decode(first_cell[0].as_str().unwrap()).unwrap().into(),
-                );
-                let first_point = first_cell[1].as_str().unwrap().to_owned();
-                let first_data = hex::decode(first_cell[2].as_str().unwrap()).unwrap();
-
-                if first_lock == rollup_lock {
-                    let mut block_data = first_data;
-                    let commit_cell_point = first_point;
-                    let upk_cell_point =
-                        cells[1].as_array().unwrap()[1].as_str().unwrap().to_owned();
-
-                    let udt_cell = match block_data.remove(0) {
-                        1u8 | 2u8 => {
-                            let udt_cell = cells[2].as_array().unwrap();
-
-                            let mut u128_bytes = [0u8; 16];
-                            u128_bytes.copy_from_slice(
-                                &hex::decode(udt_cell[2].as_str().unwrap()).unwrap(),
-                            );
-                            let amount = u128::from_le_bytes(u128_bytes);
-                            let udt_cell_point = udt_cell[1].as_str().unwrap().to_owned();
-                            Some((udt_cell_point, amount))
-                        }
-                        _ => None,
-                    };
+            Value::String(hash) => Ok(Some(hash)),
+            Value::Null => Ok(None),
+            _ => Err(malformed("block hash")),
+        }
+    }
 
-                    tmp_block.push((block_data, commit_cell_point, upk_cell_point, udt_cell));
-                }
+    /// the live cell of the out point, none when it is spent or unknown.
+    pub async fn get_live_cell(&self, out_point: &String) -> Result<Option<Cell>, CkbRpcError> {
+        if self.dialect == Dialect::Mock {
+            return Err(unsupported("get_live_cell"));
+        }
+
+        let point = decode::<OutPoint>(out_point)?;
+        let result = self
+            .call("get_live_cell", json!([json::out_point_json(&point), true]))
+            .await?;
+        if result["status"].as_str() != Some("live") {
+            return Ok(None);
+        }
+
+        let cell = &result["cell"];
+        json::cell(&cell["output"], &cell["data"]["content"], &point).map(Some)
+    }
+
+    /// the live cells of the script (lock or type) from the indexer, with their L1 heights.
+    /// the script is the hex of its molecule bytes, as the rollup stores it.
+    pub async fn get_cells(
+        &self,
+        script: &String,
+        script_type: &str,
+    ) -> Result<Vec<(Cell, u64)>, CkbRpcError> {
+        if self.dialect == Dialect::Mock {
+            return Err(unsupported("get_cells"));
+        }
+
+        let script = decode::<Script>(script)?;
+        let mut cells = vec![];
+        for object in self.indexer_cells(&script, script_type).await? {
+            let out_point = json::out_point(&object["out_point"])?;
+            let cell = json::cell(&object["output"], &object["output_data"], &out_point)?;
+            cells.push((cell, json::hex_u64(&object["block_number"])?));
+        }
+
+        Ok(cells)
+    }
+
+    /// the indexer's cell objects of the script, all the pages.
+    async fn indexer_cells(
+        &self,
+        script: &Script,
+        script_type: &str,
+    ) -> Result<Vec<Value>, CkbRpcError> {
+        let search_key = json!({
+            "script": json::script_json(script),
+            "script_type": script_type,
+        });
+
+        let mut objects = vec![];
+        let mut cursor = Value::Null;
+        loop {
+            let mut result = self
+                .call_at(
+                    &self.indexer_url,
+                    "get_cells",
+                    json!([search_key, "asc", json::number(INDEXER_PAGE), cursor]),
+                )
+                .await?;
+            let page = match result["objects"].take() {
+                Value::Array(page) => page,
+                _ => return Err(malformed("objects")),
+            };
+            if page.is_empty() {
+                break;
             }
-        } else {
-            println!("ERROR------------");
-            break;
+
+            objects.extend(page);
+            cursor = result["last_cursor"].clone();
         }
-        blocks.push((tmp_block, i));
+
+        Ok(objects)
     }
 
-    Ok(blocks)
-}
+    /// the capacity of the live cell, `CellNotFound` when it is spent or unknown.
+    pub async fn live_capacity(&self, out_point: &OutPoint) -> Result<u64, CkbRpcError> {
+        let not_found = || CkbRpcError::CellNotFound(hex::encode(out_point.as_slice()));
+        match self.dialect {
+            Dialect::Mock => {
+                let result = self
+                    .call("get_live_cell", json!([hex::encode(out_point.as_slice())]))
+                    .await?;
+                match result {
+                    Value::Null => Err(not_found()),
+                    capacity => capacity.as_u64().ok_or_else(|| malformed("capacity")),
+                }
+            }
+            Dialect::Ckb => {
+                let result = self
+                    .call(
+                        "get_live_cell",
+                        json!([json::out_point_json(out_point), false]),
+                    )
+                    .await?;
+                if result["status"].as_str() != Some("live") {
+                    return Err(not_found());
+                }
+                json::hex_u64(&result["cell"]["output"]["capacity"])
+            }
+        }
+    }
 
-pub async fn _listen_true_blocks(block_height: u64) -> Result<(Vec<Vec<u8>>, u64), ()> {
-    //get_tip_block_number
-    let now_height = match surf::post(NODE_RPC_ADDR)
-        .body_json(&jsonrpc("get_tip_block_number", Default::default()))
-        .map_err(|_e| ())?
-        .await
-    {
-        Ok(mut res) => match res.body_json::<Value>().await {
-            Ok(mut value) => {
-                let result = value["result"].take();
-                let hex_num = result.as_str().ok_or(())?;
-                u64::from_str_radix(&hex_num[2..], 16).map_err(|_| ())?
+    /// the live cells of the lock with no type and no data, they fund the txs.
+    pub async fn funding_cells(&self, lock: &Script) -> Result<Vec<FundingCell>, CkbRpcError> {
+        let mut cells = vec![];
+        match self.dialect {
+            Dialect::Mock => {
+                let result = self
+                    .call("get_cells", json!([hex::encode(lock.as_slice())]))
+                    .await?;
+                for cell in result.as_array().ok_or_else(|| malformed("cells"))? {
+                    if cell["type"] != "" || cell["data"] != "" {
+                        continue;
+                    }
+                    cells.push(FundingCell {
+                        out_point: decode(
+                            cell["out_point"]
+                                .as_str()
+                                .ok_or_else(|| malformed("out_point"))?,
+                        )?,
+                        capacity: cell["capacity"]
+                            .as_u64()
+                            .ok_or_else(|| malformed("capacity"))?,
+                    });
+                }
             }
-            Err(err) => {
-                println!("Listening err: {:?}", err);
-                return Err(());
+            Dialect::Ckb => {
+                for object in self.indexer_cells(lock, "lock").await? {
+                    if !object["output"]["type"].is_null() || object["output_data"] != "0x" {
+                        continue;
+                    }
+                    cells.push(FundingCell {
+                        out_point: json::out_point(&object["out_point"])?,
+                        capacity: json::hex_u64(&object["output"]["capacity"])?,
+                    });
+                }
             }
-        },
-        Err(err) => {
-            println!("Listening query err: {:?}", err);
-            return Err(());
         }
-    };
 
-    println!("now_height: {:?}", now_height);
+        Ok(cells)
+    }
 
-    if now_height <= block_height {
-        return Ok((vec![], block_height));
+    /// fund the tx by the live cells of the funding lock at the client's fee rate,
+    /// the change goes back to the lock as the last output. the tx's own inputs of the
+    /// lock and the funding inputs are signed by the client's signer, and the lock's
+    /// cell dep is added.
+    pub async fn fund_transaction(
+        &self,
+        tx: TransactionView,
+        funding_lock: &Script,
+    ) -> Result<TransactionView, CkbRpcError> {
+        let tx = match &self.signer {
+            Some(signer) => tx
+                .as_advanced_builder()
+                .cell_dep(signer.cell_dep.clone())
+                .build(),
+            None => tx,
+        };
+
+        let mut inputs_capacity = 0;
+        for input in tx.inputs() {
+            inputs_capacity += self.live_capacity(&input.previous_output()).await?;
+        }
+
+        // a funding cell may be an input already, e.g. the seed cell of init, it is
+        // signed in the same group as the funding inputs.
+        let inputs: Vec<OutPoint> = tx.input_pts_iter().collect();
+        let (own, funding): (Vec<FundingCell>, Vec<FundingCell>) = self
+            .funding_cells(funding_lock)
+            .await?
+            .into_iter()
+            .partition(|cell| inputs.contains(&cell.out_point));
+        let mut group: Vec<usize> = inputs
+            .iter()
+            .enumerate()
+            .filter(|(_, out_point)| own.iter().any(|cell| &cell.out_point == *out_point))
+            .map(|(i, _)| i)
+            .collect();
+
+        let first = tx.inputs().len();
+        let tx = balance(
+            tx,
+            inputs_capacity,
+            &group,
+            &funding,
+            funding_lock,
+            self.fee_rate,
+        )?;
+        group.extend(first..tx.inputs().len());
+        match &self.signer {
+            Some(signer) if !group.is_empty() => sign(tx, &group, &signer.key),
+            _ => Ok(tx),
+        }
     }
 
-    let blocks = vec![];
-    let _change_block_height = block_height;
-
-    for i in block_height..now_height {
-        // get block hash
-        if let Ok(mut res) = surf::post(NODE_RPC_ADDR)
-            .body_json(&jsonrpc(
-                "get_header_by_number",
-                json!(vec![&format!("{:#x}", i)]),
-            ))
-            .map_err(|_e| ())?
-            .await
-        {
-            let result = res.body_json::<Value>().await.map_err(|_| ())?;
-            let hash = result["result"]["hash"].as_str().ok_or(())?;
+    /// the rollup's live cells on L1: the commit cell, the vaults and the upks cell.
+    pub async fn rollup_cells(
+        &self,
+        rollup_lock: &String,
+    ) -> Result<Vec<(Cell, u64)>, CkbRpcError> {
+        self.get_cells(rollup_lock, "lock").await
+    }
 
-            // get block info
-            if let Ok(mut res) = surf::post(NODE_RPC_ADDR)
-                .body_json(&jsonrpc("get_block", json!(vec![hash])))
-                .map_err(|_e| ())?
-                .await
-            {
-                let result = res.body_json::<Value>().await.map_err(|_| ())?;
-                let transactions = result["result"]["transactions"].as_array().ok_or(())?;
+    /// the rollup's L2 blocks in the L1 blocks from the height, with the L1 block's height & hash.
+    pub async fn listen_blocks(
+        &self,
+        block_height: u64,
+        rollup_lock: &String,
+        commit_type: &String,
+    ) -> Result<Vec<(Vec<(Vec<u8>, String, Option<(String, u128)>)>, u64, String)>, CkbRpcError>
+    {
+        let now_height = self.next_block_number().await?;
+        if now_height <= block_height {
+            return Ok(vec![]);
+        }
+
+        let mut blocks = vec![];
 
-                for _tx in transactions {
-                    //println!("{:?}", tx);
-                    // TODO CHECK Tx is to our contract.
+        for i in block_height..now_height {
+            let mut tmp_block = vec![];
+
+            // a failed block is read again from its height in the next round.
+            let (hash, transactions) = self.get_block(i).await?;
+
+            for tx in &transactions {
+                let first = match tx.outputs.get(0) {
+                    Some(cell) => cell,
+                    None => continue,
+                };
+
+                // only the commit cell with the type id is the real one.
+                if &first.lock != rollup_lock || &first.type_ != commit_type {
+                    continue;
                 }
-            } else {
-                break;
+
+                let op = match Header::open(&first.data) {
+                    Ok((header, _)) => header.op,
+                    Err(_) => continue,
+                };
+
+                // the L2 block is in the commit cell input's witness, init has no block.
+                let block_data = match tx.witnesses.get(0) {
+                    Some(witness) => {
+                        let witness_args = match WitnessArgs::from_slice(witness) {
+                            Ok(witness_args) => witness_args,
+                            Err(_) => continue,
+                        };
+                        match witness_args.lock().to_opt() {
+                            Some(lock) => lock.raw_data().to_vec(),
+                            None => continue,
+                        }
+                    }
+                    None => continue,
+                };
+
+                let udt_cell = match op {
+                    OP_DEPOSIT | OP_WITHDRAW | OP_ESCAPE => {
+                        let udt_cell = match tx.outputs.get(1) {
+                            Some(cell) if cell.data.len() == 16 => cell,
+                            _ => continue,
+                        };
+
+                        let mut u128_bytes = [0u8; 16];
+                        u128_bytes.copy_from_slice(&udt_cell.data);
+                        let amount = u128::from_le_bytes(u128_bytes);
+                        Some((udt_cell.out_point.clone(), amount))
+                    }
+                    _ => None,
+                };
+
+                tmp_block.push((block_data, first.out_point.clone(), udt_cell));
             }
-        } else {
-            break;
+
+            blocks.push((tmp_block, i, hash));
         }
+
+        Ok(blocks)
     }
 
-    Ok((blocks, block_height))
+    /// send the tx once, a failed tx is built again by the caller.
+    /// returns the out points of the tx's outputs, and the tx hash.
+    pub async fn send_transaction(
+        &self,
+        tx: TransactionView,
+    ) -> Result<(Vec<String>, String), CkbRpcError> {
+        let tx_hash = hex::encode(tx.hash().as_slice());
+        let out_points = match self.dialect {
+            Dialect::Mock => {
+                let s = hex::encode(tx.pack().as_slice());
+
+                // NODE RPC send_transaction
+                let value = self
+                    .post(&self.url, &jsonrpc("send_transaction", json!([s])))
+                    .await
+                    .and_then(result)
+                    .map_err(CkbRpcError::on_send)?;
+                str_array(&value)?
+            }
+            Dialect::Ckb => {
+                // the rollup lock is not a well known script.
+                let params = json!([json::tx_json(&tx.data()), "passthrough"]);
+                let value = self
+                    .post(&self.url, &jsonrpc("send_transaction", params))
+                    .await
+                    .and_then(result)
+                    .map_err(CkbRpcError::on_send)?;
+                let hash = json::byte32(&value)?;
+
+                (0..tx.outputs().len())
+                    .map(|i| {
+                        let out_point = OutPoint::new_builder()
+                            .tx_hash(hash.clone())
+                            .index((i as u32).pack())
+                            .build();
+                        hex::encode(out_point.as_slice())
+                    })
+                    .collect()
+            }
+        };
+
+        Ok((out_points, tx_hash))
+    }
 }
 
-/// init state of L2
-pub async fn init_state(
-    rollup_hash: String,
-    rollup_dep_hash: String,
-    mut commit: Vec<u8>,
-    upks: Vec<u8>,
-) -> Result<(String, String, String, String), ()> {
-    let input_ckb = Capacity::bytes(1000).unwrap().as_u64();
-    let rollup_lock = Script::new_unchecked(hex::decode(rollup_hash).unwrap().into());
-    let rollup_dep = CellDep::new_unchecked(hex::decode(rollup_dep_hash).unwrap().into());
-
-    println!("start init state...");
-    let init_output_commit = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock.clone())
-        .build();
-    let init_upk = CellOutput::new_builder()
-        .capacity(Capacity::bytes(3000).unwrap().as_u64().pack())
-        .lock(rollup_lock.clone())
-        .build();
-    let init_udt = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock.clone())
-        .build();
-
-    let mut true_commit = vec![0u8];
-    true_commit.extend_from_slice(&mut commit[..]);
-
-    let init_outputs_data: Vec<Bytes> = vec![
-        true_commit.into(),
-        upks.into(),
-        0u128.to_le_bytes().to_vec().into(),
-    ];
-
-    let tx = TransactionBuilder::default()
-        .inputs(vec![])
-        .outputs(vec![
-            init_output_commit.clone(),
-            init_upk.clone(),
-            init_udt.clone(),
-        ])
-        .outputs_data(init_outputs_data.pack())
-        .cell_dep(rollup_dep)
-        .build();
-
-    let tx_hash = tx.hash();
-
-    let mut results = send_tx(tx.pack()).await?;
-
-    Ok((
-        results.remove(0),
-        results.remove(0),
-        results.remove(0),
-        hex::encode(tx_hash.as_slice()),
-    ))
+/// the mock-ckb tx: outputs are [lock, out point, data, type], others are hex.
+fn mock_tx(tx: &Value) -> Result<L1Tx, CkbRpcError> {
+    let mut outputs = vec![];
+    for cell in tx["outputs"]
+        .as_array()
+        .ok_or_else(|| malformed("outputs"))?
+    {
+        let cell = str_array(cell)?;
+        if cell.len() != 4 {
+            return Err(malformed("outputs"));
+        }
+        outputs.push(Cell {
+            lock: cell[0].clone(),
+            out_point: cell[1].clone(),
+            data: hex_decode(&cell[2])?,
+            type_: cell[3].clone(),
+        });
+    }
+
+    let mut witnesses = vec![];
+    for witness in str_array(&tx["witnesses"])? {
+        witnesses.push(hex_decode(&witness)?);
+    }
+
+    Ok(L1Tx {
+        outputs,
+        witnesses,
+        cell_deps: str_array(&tx["cell_deps"])?,
+    })
 }
 
-pub async fn send_deposit(
-    rollup_hash: &String,
-    rollup_dep_hash: &String,
-    success_hash: &String,
-    my_udt_hash: &String,
-    pre_commit_hash: &String,
-    pre_upk_hash: &String,
-    pre_udt_hash: &String,
-    mut commit: Vec<u8>,
-    upks: Vec<u8>,
-    udt_amount: u128,
-    my_udt_amount: u128,
-) -> Result<(String, String, String, String, String), ()> {
-    let rollup_lock = Script::new_unchecked(hex::decode(rollup_hash).unwrap().into());
-    let rollup_dep = CellDep::new_unchecked(hex::decode(rollup_dep_hash).unwrap().into());
-    let success_lock = Script::new_unchecked(hex::decode(success_hash).unwrap().into());
-    let my_udt = OutPoint::new_unchecked(hex::decode(my_udt_hash).unwrap().into());
-
-    let pre_commit = OutPoint::new_unchecked(hex::decode(pre_commit_hash).unwrap().into());
-    let pre_upk = OutPoint::new_unchecked(hex::decode(pre_upk_hash).unwrap().into());
-    let pre_udt = OutPoint::new_unchecked(hex::decode(pre_udt_hash).unwrap().into());
-
-    let input_ckb = Capacity::bytes(1000).unwrap().as_u64();
-
-    let udt_input = CellInput::new_builder().previous_output(my_udt).build();
-
-    let deposit_commit_input = CellInput::new_builder().previous_output(pre_commit).build();
-    let deposit_upk_input = CellInput::new_builder().previous_output(pre_upk).build();
-    let deposit_udt_input = CellInput::new_builder().previous_output(pre_udt).build();
-
-    let commit_cell = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock.clone())
-        .build();
-    let upk_cell = CellOutput::new_builder()
-        .capacity(Capacity::bytes(3000).unwrap().as_u64().pack())
-        .lock(rollup_lock.clone())
-        .build();
-    let udt_cell = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock)
-        .build();
-    let my_udt = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(success_lock)
-        .build();
-
-    let mut true_commit = vec![1u8];
-    true_commit.extend_from_slice(&mut commit[..]);
-
-    let deposit_outputs_data: Vec<Bytes> = vec![
-        true_commit.into(),
-        upks.into(),
-        udt_amount.to_le_bytes().to_vec().into(),
-        my_udt_amount.to_le_bytes().to_vec().into(),
-    ];
-
-    let tx = TransactionBuilder::default()
-        .inputs(vec![
-            deposit_commit_input,
-            deposit_upk_input,
-            deposit_udt_input,
-            udt_input,
-        ])
-        .outputs(vec![commit_cell, upk_cell, udt_cell, my_udt])
-        .outputs_data(deposit_outputs_data.pack())
-        .cell_dep(rollup_dep)
-        .build();
-
-    let tx_hash = tx.hash();
-
-    let mut results = send_tx(tx.pack()).await?;
-
-    Ok((
-        results.remove(0),
-        results.remove(0),
-        results.remove(0),
-        results.remove(0),
-        hex::encode(tx_hash.as_slice()),
-    ))
+/// the rollup lock, the outputs of the rollup lock & cell deps of the init tx,
+/// the change of the funding lock is not the rollup's.
+fn genesis(tx: L1Tx, commit_type: &String) -> Option<(String, Vec<Cell>, Vec<String>)> {
+    let first = tx.outputs.get(0)?;
+    if &first.type_ != commit_type {
+        return None;
+    }
+    match Header::open(&first.data) {
+        Ok((header, _)) if header.op == OP_INIT => {}
+        _ => return None,
+    }
+
+    let rollup_lock = first.lock.clone();
+    let outputs = tx
+        .outputs
+        .into_iter()
+        .filter(|cell| cell.lock == rollup_lock)
+        .collect();
+    Some((rollup_lock, outputs, tx.cell_deps))
 }
 
-pub async fn send_withdraw(
-    rollup_hash: &String,
-    rollup_dep_hash: &String,
-    success_hash: &String,
-    pre_commit_hash: &String,
-    pre_upk_hash: &String,
-    pre_udt_hash: &String,
-    mut commit: Vec<u8>,
-    upks: Vec<u8>,
-    udt_amount: u128,
-    amount: u128,
-) -> Result<(String, String, String, String), ()> {
-    let rollup_lock = Script::new_unchecked(hex::decode(rollup_hash).unwrap().into());
-    let rollup_dep = CellDep::new_unchecked(hex::decode(rollup_dep_hash).unwrap().into());
-    let success_lock = Script::new_unchecked(hex::decode(success_hash).unwrap().into());
-
-    let pre_commit = OutPoint::new_unchecked(hex::decode(pre_commit_hash).unwrap().into());
-    let pre_upk = OutPoint::new_unchecked(hex::decode(pre_upk_hash).unwrap().into());
-    let pre_udt = OutPoint::new_unchecked(hex::decode(pre_udt_hash).unwrap().into());
-
-    let input_ckb = Capacity::bytes(1000).unwrap().as_u64();
-
-    let withdraw_commit_input = CellInput::new_builder().previous_output(pre_commit).build();
-    let withdraw_upk_input = CellInput::new_builder().previous_output(pre_upk).build();
-    let withdraw_udt_input = CellInput::new_builder().previous_output(pre_udt).build();
-
-    let commit_cell = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock.clone())
-        .build();
-    let upk_cell = CellOutput::new_builder()
-        .capacity(Capacity::bytes(3000).unwrap().as_u64().pack())
-        .lock(rollup_lock.clone())
-        .build();
-    let udt_cell = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock)
-        .build();
-    let my_udt = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(success_lock)
-        .build();
-
-    let mut true_commit = vec![2u8];
-    true_commit.extend_from_slice(&mut commit[..]);
-
-    let withdraw_outputs_data: Vec<Bytes> = vec![
-        true_commit.into(),
-        upks.into(),
-        udt_amount.to_le_bytes().to_vec().into(),
-        amount.to_le_bytes().to_vec().into(),
-    ];
-
-    let tx = TransactionBuilder::default()
-        .inputs(vec![
-            withdraw_commit_input,
-            withdraw_upk_input,
-            withdraw_udt_input,
-        ])
-        .outputs(vec![commit_cell, upk_cell, udt_cell, my_udt])
-        .outputs_data(withdraw_outputs_data.pack())
-        .cell_dep(rollup_dep)
-        .build();
-
-    let tx_hash = tx.hash();
-
-    let mut results = send_tx(tx.pack()).await?;
-
-    Ok((
-        results.remove(0),
-        results.remove(0),
-        results.remove(0),
-        hex::encode(tx_hash.as_slice()),
-    ))
+/// the UDT pools (my udt cells and UDT types) are arrays, the index is the token id.
+fn str_array(value: &Value) -> Result<Vec<String>, CkbRpcError> {
+    value
+        .as_array()
+        .ok_or_else(|| malformed("array"))?
+        .iter()
+        .map(|v| {
+            v.as_str()
+                .map(|s| s.to_owned())
+                .ok_or_else(|| malformed("string"))
+        })
+        .collect()
 }
 
-pub async fn send_block(
-    rollup_hash: &String,
-    rollup_dep_hash: &String,
-    pre_commit_hash: &String,
-    pre_upk_hash: &String,
-    mut commit: Vec<u8>,
-    upks: Vec<u8>,
-) -> Result<(String, String, String), ()> {
-    let rollup_lock = Script::new_unchecked(hex::decode(rollup_hash).unwrap().into());
-    let rollup_dep = CellDep::new_unchecked(hex::decode(rollup_dep_hash).unwrap().into());
-
-    let pre_commit = OutPoint::new_unchecked(hex::decode(pre_commit_hash).unwrap().into());
-    let pre_upk = OutPoint::new_unchecked(hex::decode(pre_upk_hash).unwrap().into());
-
-    let input_ckb = Capacity::bytes(1000).unwrap().as_u64();
-
-    let commit_input = CellInput::new_builder().previous_output(pre_commit).build();
-    let upk_input = CellInput::new_builder().previous_output(pre_upk).build();
-
-    let commit_cell = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock.clone())
-        .build();
-    let upk_cell = CellOutput::new_builder()
-        .capacity(Capacity::bytes(3000).unwrap().as_u64().pack())
-        .lock(rollup_lock.clone())
-        .build();
-
-    let mut true_commit = vec![3u8];
-    true_commit.extend_from_slice(&mut commit[..]);
-
-    let outputs_data: Vec<Bytes> = vec![true_commit.into(), upks.into()];
-
-    let tx = TransactionBuilder::default()
-        .inputs(vec![commit_input, upk_input])
-        .outputs(vec![commit_cell, upk_cell])
-        .outputs_data(outputs_data.pack())
-        .cell_dep(rollup_dep)
-        .build();
-
-    let tx_hash = tx.hash();
-    println!("Send transaction: {}", hex::encode(tx_hash.as_slice()));
-
-    let mut results = send_tx(tx.pack()).await?;
-
-    Ok((
-        results.remove(0),
-        results.remove(0),
-        hex::encode(tx_hash.as_slice()),
-    ))
+/// the JSON-RPC response's result, or its error object.
+fn result(mut value: Value) -> Result<Value, CkbRpcError> {
+    match value["error"].take() {
+        Value::Null => Ok(value["result"].take()),
+        error => Err(CkbRpcError::Rpc {
+            code: error["code"]
+                .as_i64()
+                .ok_or_else(|| malformed("error code"))?,
+            message: error["message"].as_str().unwrap_or_default().to_owned(),
+        }),
+    }
 }
 
-async fn send_tx(tx: TransactionView) -> Result<Vec<String>, ()> {
-    let s = hex::encode(tx.as_slice());
+/// the method is not in mock-ckb, same as the JSON-RPC "method not found".
+fn unsupported(method: &str) -> CkbRpcError {
+    CkbRpcError::Rpc {
+        code: -32601,
+        message: format!("{} is not supported by mock-ckb", method),
+    }
+}
 
-    // Build a CKB Transaction
-    let rpc_call = json!(
-        {
-            "id": 0,
-            "jsonrpc": "2.0",
-            "method": "send_transaction",
-            "params": [s],
-        }
-    );
+fn hex_decode(s: &str) -> Result<Vec<u8>, CkbRpcError> {
+    hex::decode(s).map_err(|e| CkbRpcError::Decode(e.to_string()))
+}
 
-    // NODE RPC send_transaction
-    match surf::post(NODE_RPC_ADDR)
-        .body_json(&rpc_call)
-        .map_err(|_e| ())?
-        .await
-    {
-        Ok(mut res) => match res.body_json::<Value>().await {
-            Ok(value) => Ok(value["result"]
-                .as_array()
-                .unwrap()
-                .iter()
-                .map(|v| v.as_str().unwrap().to_owned())
-                .collect()),
-            Err(err) => {
-                println!("{:?}", err);
-                Err(())
-            }
-        },
-        Err(err) => {
-            println!("{:?}", err);
-            Err(())
-        }
-    }
+/// the molecule value of the hex, verified.
+pub fn decode<T: Entity>(s: &str) -> Result<T, CkbRpcError> {
+    T::from_slice(&hex_decode(s)?).map_err(|e| CkbRpcError::Decode(format!("{:?}", e)))
+}
+
+/// the hash of the script's hex, used as the token's id in the upks cell.
+pub fn script_hash(script: &str) -> Result<[u8; 32], CkbRpcError> {
+    let script = decode::<Script>(script)?;
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(script.calc_script_hash().as_slice());
+    Ok(hash)
+}
+
+/// the args of the pinned rollup lock.
+pub fn lock_args(rollup_lock: &String) -> Result<LockArgs, CkbRpcError> {
+    let rollup_lock = decode::<Script>(rollup_lock)?;
+    let args: Bytes = rollup_lock.args().unpack();
+    LockArgs::from_bytes(&args).map_err(|_| CkbRpcError::Decode(String::from("the lock args")))
+}
+
+/// the since of an escape withdraw's commit cell input, users can escape when the
+/// commit cell is not updated in `escape_blocks`, every escape waits again.
+pub fn escape_since(escape_blocks: u64) -> u64 {
+    SINCE_RELATIVE_BLOCK | escape_blocks
 }
 
 //
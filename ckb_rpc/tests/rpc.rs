@@ -0,0 +1,315 @@
+//! ckb_rpc against a local server answering the recorded CKB node & indexer responses.
+
+use async_std::task;
+use serde_json::{json, Value};
+use std::net::TcpListener;
+use std::time::Duration;
+use tide::{Body, Request, Response};
+
+use asvc_rollup::block::{Block, CellCommit};
+use ckb_rpc::{tx_fee, Cell, CkbClient, CkbRpcError, CommitCell, RollupTxBuilder};
+use ckb_tool::ckb_types::{core::TransactionView, packed::*, prelude::*};
+use ckb_zkp::curve::bn_256::Bn_256;
+
+/// serve the fixtures after the entries, an entry without params answers any params.
+async fn serve(entries: Vec<Value>) -> CkbClient {
+    let mut fixtures = entries;
+    let recorded: Vec<Value> = serde_json::from_str(include_str!("fixtures/ckb.json")).unwrap();
+    fixtures.extend(recorded);
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let mut app = tide::with_state(fixtures);
+    app.at("/").post(|mut req: Request<Vec<Value>>| async move {
+        let body: Value = req.body_json().await?;
+        let entry = req.state().iter().find(|entry| {
+            entry["method"] == body["method"]
+                && (entry.get("params").is_none() || entry["params"] == body["params"])
+        });
+
+        let response = match entry {
+            Some(entry) if entry.get("error").is_some() => {
+                json!({"jsonrpc": "2.0", "id": body["id"], "error": entry["error"]})
+            }
+            entry => {
+                let result = entry.map(|entry| entry["result"].clone());
+                json!({"jsonrpc": "2.0", "id": body["id"], "result": result})
+            }
+        };
+
+        let mut res = Response::new(200);
+        res.set_body(Body::from_json(&response)?);
+        Ok(res)
+    });
+    task::spawn(app.listen(format!("127.0.0.1:{}", port)));
+    task::sleep(Duration::from_millis(200)).await;
+
+    let mut client = CkbClient::ckb(&format!("http://127.0.0.1:{}", port));
+    client.retry_interval = Duration::from_millis(100);
+    client
+}
+
+// the molecule hex of the scripts & cell deps in fixtures/ckb.json.
+// rollup lock: code hash 0xaa.., type, args 0xbb * 40.
+const ROLLUP_LOCK: &str = "5d000000100000003000000031000000aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa0128000000bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+// commit type: code hash 0xcc.., type, args 0xdd * 32.
+const COMMIT_TYPE: &str = "55000000100000003000000031000000cccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccc0120000000dddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddd";
+// the vault's UDT type: code hash 0xee.., data, no args.
+const UDT_TYPE: &str = "35000000100000003000000031000000eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee0000000000";
+// the operator's lock: code hash 0x11.., data, args 0x22 * 20.
+const FUNDING_LOCK: &str = "49000000100000003000000031000000111111111111111111111111111111111111111111111111111111111111111100140000002222222222222222222222222222222222222222";
+// the code cells of the rollup lock & commit type, out point (0xf1.., 0) & (0xf2.., 0).
+const ROLLUP_DEP: &str =
+    "f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f1f10000000000";
+const COMMIT_TYPE_DEP: &str =
+    "f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f2f20000000000";
+
+/// the hex of the out point whose tx hash is 32 bytes of the tag.
+fn out_point(tag: u8, index: u32) -> String {
+    let mut out_point = vec![tag; 32];
+    out_point.extend_from_slice(&index.to_le_bytes());
+    hex::encode(out_point)
+}
+
+fn header(op: u8, payload: u8) -> Vec<u8> {
+    let mut data = b"CLVR".to_vec();
+    data.extend_from_slice(&[4, 0, 0, 0, 0, 2, 0, 0, 0, op, payload]);
+    data
+}
+
+fn rollup_cell(out_point: String, data: Vec<u8>, type_: &str) -> Cell {
+    Cell {
+        lock: ROLLUP_LOCK.to_owned(),
+        out_point,
+        data,
+        type_: type_.to_owned(),
+    }
+}
+
+/// an empty block after the init tx of the fixtures.
+fn post_block() -> TransactionView {
+    let builder = RollupTxBuilder::from_hex(
+        ROLLUP_LOCK,
+        ROLLUP_DEP,
+        COMMIT_TYPE,
+        COMMIT_TYPE_DEP,
+        &out_point(0xa1, 2),
+        2,
+    )
+    .unwrap();
+    let commit = CommitCell::<Bn_256> {
+        out_point: ckb_rpc::decode(&out_point(0xa2, 0)).unwrap(),
+        commit: CellCommit {
+            block_height: 0,
+            commits: vec![],
+            user_count: 0,
+        },
+    };
+    let block = Block {
+        block_height: 1,
+        fee_recipient: 0,
+        shards: vec![],
+        txs: vec![],
+        aggregate_sign: None,
+    };
+
+    builder.post_block(&commit, &block).unwrap()
+}
+
+#[async_std::test]
+async fn listen_blocks() {
+    let client = serve(vec![]).await;
+    let blocks = client
+        .listen_blocks(1, &ROLLUP_LOCK.to_owned(), &COMMIT_TYPE.to_owned())
+        .await
+        .unwrap();
+
+    assert_eq!(blocks.len(), 2);
+    // init has no L2 block.
+    assert_eq!(blocks[0], (vec![], 1, format!("0x{}", "11".repeat(32))));
+    // the other tx in the block is not the rollup's.
+    assert_eq!(
+        blocks[1],
+        (
+            vec![(vec![1, 2, 3, 4], out_point(0xa2, 0), None)],
+            2,
+            format!("0x{}", "12".repeat(32))
+        )
+    );
+}
+
+#[async_std::test]
+async fn listen_genesis_by_indexer() {
+    let client = serve(vec![]).await;
+    let (height, lock, outputs, cell_deps) = client
+        .listen_genesis(&COMMIT_TYPE.to_owned())
+        .await
+        .unwrap();
+
+    assert_eq!(height, 1);
+    assert_eq!(lock, ROLLUP_LOCK);
+    assert_eq!(
+        outputs,
+        vec![
+            rollup_cell(out_point(0xa1, 0), header(0, 0xaa), COMMIT_TYPE),
+            rollup_cell(out_point(0xa1, 1), vec![0; 16], UDT_TYPE),
+            rollup_cell(out_point(0xa1, 2), header(0xff, 0xbb), ""),
+        ]
+    );
+    assert_eq!(cell_deps, vec![ROLLUP_DEP, COMMIT_TYPE_DEP]);
+}
+
+#[async_std::test]
+async fn live_cells_and_block_hash() {
+    let client = serve(vec![]).await;
+
+    let commit = out_point(0xa2, 0);
+    assert_eq!(
+        client.get_live_cell(&commit).await.unwrap(),
+        Some(rollup_cell(commit.clone(), header(3, 0xcc), COMMIT_TYPE))
+    );
+    let spent = out_point(0xa1, 0);
+    assert_eq!(client.get_live_cell(&spent).await.unwrap(), None);
+
+    let cells = client.rollup_cells(&ROLLUP_LOCK.to_owned()).await.unwrap();
+    assert_eq!(cells.len(), 3);
+    assert_eq!(cells[2].0.out_point, commit);
+    assert_eq!(cells[2].1, 2);
+
+    assert_eq!(
+        client.get_block_hash(1).await.unwrap(),
+        Some(format!("0x{}", "11".repeat(32)))
+    );
+    assert_eq!(client.get_block_hash(9).await.unwrap(), None);
+}
+
+#[async_std::test]
+async fn send_block_by_json_tx() {
+    let client = serve(vec![]).await;
+    let (out_points, _) = client.send_transaction(post_block()).await.unwrap();
+
+    // the out points are of the node's tx hash.
+    assert_eq!(out_points, vec![out_point(0xa4, 0)]);
+}
+
+#[async_std::test]
+async fn send_block_rejected() {
+    let client = serve(vec![json!({
+        "method": "send_transaction",
+        "error": {"code": -302, "message": "TransactionFailedToVerify: ValidationFailure(5)"},
+    })])
+    .await;
+    let result = client.send_transaction(post_block()).await;
+
+    assert_eq!(
+        result,
+        Err(CkbRpcError::Rejected(String::from(
+            "TransactionFailedToVerify: ValidationFailure(5)"
+        )))
+    );
+}
+
+#[async_std::test]
+async fn fund_by_indexer() {
+    let funding_lock = json!({
+        "code_hash": format!("0x{}", "11".repeat(32)),
+        "hash_type": "data",
+        "args": format!("0x{}", "22".repeat(20)),
+    });
+    let search_key = json!({"script": funding_lock, "script_type": "lock"});
+    let funding_cell = |tx_hash: &str, type_: Value, data: &str| {
+        json!({
+            "output": {"capacity": "0x174876e800", "lock": funding_lock, "type": type_},
+            "output_data": data,
+            "out_point": {"tx_hash": format!("0x{}", tx_hash.repeat(32)), "index": "0x0"},
+            "block_number": "0x2",
+            "tx_index": "0x1",
+        })
+    };
+    let client = serve(vec![
+        // the commit cell has 100 CKB, less than its new output.
+        json!({
+            "method": "get_live_cell",
+            "params": [{"tx_hash": format!("0x{}", "a2".repeat(32)), "index": "0x0"}, false],
+            "result": {"cell": {"output": {"capacity": "0x2540be400"}}, "status": "live"},
+        }),
+        // only the cell without type and data funds.
+        json!({
+            "method": "get_cells",
+            "params": [search_key, "asc", "0x64", null],
+            "result": {
+                "objects": [
+                    funding_cell("b2", json!({
+                        "code_hash": format!("0x{}", "ee".repeat(32)),
+                        "hash_type": "data",
+                        "args": "0x",
+                    }), "0x"),
+                    funding_cell("b3", Value::Null, "0x00"),
+                    funding_cell("b1", Value::Null, "0x"),
+                ],
+                "last_cursor": "0x02",
+            },
+        }),
+        json!({
+            "method": "get_cells",
+            "params": [search_key, "asc", "0x64", "0x02"],
+            "result": {"objects": [], "last_cursor": "0x"},
+        }),
+    ])
+    .await;
+    let lock: Script = ckb_rpc::decode(FUNDING_LOCK).unwrap();
+    let tx = client.fund_transaction(post_block(), &lock).await.unwrap();
+
+    let inputs: Vec<String> = tx
+        .input_pts_iter()
+        .map(|out_point| hex::encode(out_point.as_slice()))
+        .collect();
+    assert_eq!(inputs, vec![out_point(0xa2, 0), out_point(0xb1, 0)]);
+    assert_eq!(tx.outputs().len(), 2);
+    let change = tx.outputs().get(1).unwrap();
+    assert_eq!(change.lock(), lock);
+
+    // 100 CKB + 1000 CKB pays the commit cell, the change and the fee.
+    let outputs: u64 = tx.outputs_capacity().unwrap().as_u64();
+    assert_eq!(1_100 * 100_000_000 - outputs, tx_fee(&tx, client.fee_rate));
+}
+
+#[async_std::test]
+async fn typed_errors() {
+    let client = serve(vec![json!({
+        "method": "get_block_hash",
+        "params": ["0x3"],
+        "error": {"code": -32602, "message": "Invalid params"},
+    })])
+    .await;
+    assert_eq!(
+        client.get_block_hash(3).await,
+        Err(CkbRpcError::Rpc {
+            code: -32602,
+            message: String::from("Invalid params")
+        })
+    );
+
+    // not hex of an out point.
+    match client.get_live_cell(&String::from("0xzz")).await {
+        Err(CkbRpcError::Decode(_)) => {}
+        other => panic!("not a decode error: {:?}", other),
+    }
+
+    // nothing listens on the port.
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut offline = CkbClient::ckb(&format!("http://127.0.0.1:{}", port));
+    offline.retries = 1;
+    offline.retry_interval = Duration::from_millis(10);
+    match offline.get_block_hash(1).await {
+        Err(CkbRpcError::Transport(_)) => {}
+        other => panic!("not a transport error: {:?}", other),
+    }
+}
@@ -0,0 +1,82 @@
+use core::result::Result;
+
+use blake2b_rs::Blake2bBuilder;
+use ckb_std::{
+    ckb_constants::Source,
+    ckb_types::{bytes::Bytes, prelude::*},
+    debug,
+    error::SysError,
+    high_level::{load_cell_type_hash, load_input, load_script, load_script_hash},
+};
+
+use crate::error::Error;
+
+const TYPE_ID_LEN: usize = 32;
+
+/// type id style type script of the commit cell. the commit cell is created only once,
+/// and every update must consume the only live one, so no fake commit cell.
+pub fn main() -> Result<(), Error> {
+    let inputs = count_cells(Source::GroupInput)?;
+    let outputs = count_cells(Source::GroupOutput)?;
+    debug!("commit cells: inputs {}, outputs {}", inputs, outputs);
+
+    match (inputs, outputs) {
+        (i, o) if i > 1 || o > 1 => Err(Error::MultipleCells),
+        // CREATE: the args must be the type id of this tx.
+        (0, 1) => check_type_id(),
+        // UPDATE: one in, one out.
+        (1, 1) => Ok(()),
+        // the commit cell can never be destroyed.
+        _ => Err(Error::Destroyed),
+    }
+}
+
+fn count_cells(source: Source) -> Result<usize, Error> {
+    let mut count = 0;
+    loop {
+        match load_cell_type_hash(count, source) {
+            Ok(_) => count += 1,
+            Err(SysError::IndexOutOfBound) => return Ok(count),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// type id = blake2b(first input || output index), same as CKB's type id.
+fn check_type_id() -> Result<(), Error> {
+    let args: Bytes = load_script()?.args().unpack();
+    if args.len() != TYPE_ID_LEN {
+        return Err(Error::Encoding);
+    }
+
+    let self_script_hash = load_script_hash()?;
+    let mut index = None;
+    for i in 0.. {
+        match load_cell_type_hash(i, Source::Output) {
+            Ok(Some(hash)) if hash == self_script_hash => {
+                index = Some(i as u64);
+                break;
+            }
+            Ok(_) => {}
+            Err(SysError::IndexOutOfBound) => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    let index = index.ok_or(Error::ItemMissing)?;
+
+    let first_input = load_input(0, Source::Input)?;
+
+    let mut hasher = Blake2bBuilder::new(TYPE_ID_LEN)
+        .personal(b"ckb-default-hash")
+        .build();
+    hasher.update(first_input.as_slice());
+    hasher.update(&index.to_le_bytes());
+    let mut type_id = [0u8; TYPE_ID_LEN];
+    hasher.finalize(&mut type_id);
+
+    if type_id[..] != args[..] {
+        return Err(Error::InvalidTypeId);
+    }
+
+    Ok(())
+}
@@ -1,14 +1,21 @@
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use core::result::Result;
 
 use ckb_std::{
     ckb_constants::Source,
+    ckb_types::{bytes::Bytes, prelude::*},
     debug,
     error::SysError,
-    high_level::{load_cell_data, load_cell_lock_hash, load_cell_type_hash, load_script_hash},
+    high_level::{
+        load_cell_data, load_cell_data_hash, load_cell_lock_hash, load_cell_type_hash,
+        load_input_since, load_script, load_script_hash, load_witness_args,
+    },
 };
 
-use asvc_rollup::block::{Block, CellUpks};
+use asvc_rollup::block::{Block, CellCommit, CellUpks};
+use asvc_rollup::format::{Header, LockArgs, OP_DEPOSIT, OP_ESCAPE, OP_POST_BLOCK, OP_WITHDRAW};
+use asvc_rollup::transaction::TxType;
 use ckb_zkp::curve::bn_256::Bn_256;
 
 use crate::error::Error;
@@ -16,7 +23,12 @@ use crate::error::Error;
 // use simple UDT length
 const UDT_LEN: usize = 16; // u128
 const BLOCK_CELL: usize = 0;
-const UPK_CELL: usize = 1;
+const UDT_CELL: usize = 1;
+
+// since: relative flag and block number metric.
+const SINCE_FLAGS_MASK: u64 = 0xff00_0000_0000_0000;
+const SINCE_VALUE_MASK: u64 = 0x00ff_ffff_ffff_ffff;
+const SINCE_RELATIVE_BLOCK: u64 = 0x8000_0000_0000_0000;
 
 pub fn main() -> Result<(), Error> {
     // load now commit
@@ -31,57 +43,55 @@ pub fn main() -> Result<(), Error> {
         return Err(Error::LengthNotEnough);
     }
 
-    let now_upk = match load_cell_data(UPK_CELL, Source::Output) {
-        Ok(data) => data,
-        Err(err) => return Err(err.into()),
-    };
-    let now_upk_lock = load_cell_lock_hash(UPK_CELL, Source::Output)?;
-
     let pre_commit = match load_cell_data(BLOCK_CELL, Source::Input) {
         Ok(data) => data,
         Err(err) => return Err(err.into()),
     };
     let pre_com_lock = load_cell_lock_hash(BLOCK_CELL, Source::Input)?;
 
-    let pre_upk = match load_cell_data(UPK_CELL, Source::Input) {
-        Ok(data) => data,
-        Err(err) => return Err(err.into()),
-    };
-    let pre_upk_lock = load_cell_lock_hash(UPK_CELL, Source::Input)?;
+    let lock_args: Bytes = load_script()?.args().unpack();
+    let lock_args = LockArgs::from_bytes(&lock_args[..]).map_err(|_| Error::Encoding)?;
 
-    if now_upk != pre_upk {
-        return Err(Error::Upk);
-    }
+    let upk = load_upks(&lock_args.upks_hash)?;
 
     let self_script_hash = load_script_hash().unwrap();
 
     if self_script_hash != now_com_lock {
         return Err(Error::Verify);
     }
-    if (now_com_lock != now_upk_lock)
-        || (now_com_lock != pre_com_lock)
-        || (now_com_lock != pre_upk_lock)
+    if now_com_lock != pre_com_lock {
+        return Err(Error::Verify);
+    }
+
+    // the commit cell's type id keeps it the only live commit cell,
+    // the lock is pinned to it, so a cell of another type can't stand in.
+    let commit_type = Some(lock_args.commit_type_hash);
+    if load_cell_type_hash(BLOCK_CELL, Source::Input)? != commit_type
+        || load_cell_type_hash(BLOCK_CELL, Source::Output)? != commit_type
     {
         return Err(Error::Verify);
     }
 
-    let op = now_commit[0];
-    match op {
-        1u8 => {
+    let (header, _) = Header::open(&now_commit).map_err(|_| Error::Encoding)?;
+    if header.check::<Bn_256>().is_err() {
+        return Err(Error::Format);
+    }
+
+    match header.op {
+        OP_DEPOSIT => {
             // DEPOSIT
             //
             // input0 => pre_commit
-            // input1 => upk
-            // input2 => pre_udt_pool
-            // input3..n-1 => udt_unspend
+            // input1 => pre_udt_pool (of the deposit token)
+            // input2..n-1 => udt_unspend
             // output0 => now_commit
-            // output1 => upk
-            // output2 => now_udt_pool
-            // output3..n-1 => udt_change
+            // output1 => now_udt_pool
+            // output2..n-1 => udt_change
+            // cell_dep => upk
 
             // 2. pre udt amount in pool.
             debug!("DEPOSIT");
-            let pre_amount = match load_cell_data(2, Source::Input) {
+            let pre_amount = match load_cell_data(UDT_CELL, Source::Input) {
                 Ok(data) => {
                     let mut buf = [0u8; UDT_LEN];
                     if data.len() != UDT_LEN {
@@ -93,14 +103,15 @@ pub fn main() -> Result<(), Error> {
                 }
                 Err(err) => return Err(err.into()),
             };
-            let pre_amount_lock = load_cell_lock_hash(2, Source::Input)?;
-            let pre_amount_type = load_cell_type_hash(2, Source::Input)?;
+            let pre_amount_lock = load_cell_lock_hash(UDT_CELL, Source::Input)?;
+            let pre_amount_type = load_cell_type_hash(UDT_CELL, Source::Input)?;
+            check_unlocked(2, Source::Input, &self_script_hash)?;
 
             // 3. inputs udt deposit.
             let mut deposit_amount: u128 = 0;
             let mut deposit_buf = [0u8; UDT_LEN];
 
-            for i in 3.. {
+            for i in 2.. {
                 let data = match load_cell_data(i, Source::Input) {
                     Ok(data) => data,
                     Err(SysError::IndexOutOfBound) => break,
@@ -122,7 +133,7 @@ pub fn main() -> Result<(), Error> {
             }
 
             // 4. output udt amount.
-            let now_amount = match load_cell_data(2, Source::Output) {
+            let now_amount = match load_cell_data(UDT_CELL, Source::Output) {
                 Ok(data) => {
                     let mut buf = [0u8; UDT_LEN];
                     if data.len() != UDT_LEN {
@@ -134,8 +145,8 @@ pub fn main() -> Result<(), Error> {
                 }
                 Err(err) => return Err(err.into()),
             };
-            let now_amount_lock = load_cell_lock_hash(2, Source::Output)?;
-            let now_amount_type = load_cell_type_hash(2, Source::Input)?;
+            let now_amount_lock = load_cell_lock_hash(UDT_CELL, Source::Output)?;
+            let now_amount_type = load_cell_type_hash(UDT_CELL, Source::Output)?;
 
             if (pre_amount_lock != now_amount_lock) || (pre_amount_type != now_amount_type) {
                 return Err(Error::Amount);
@@ -148,7 +159,7 @@ pub fn main() -> Result<(), Error> {
             let mut change_amount: u128 = 0;
             let mut change_buf = [0u8; UDT_LEN];
 
-            for i in 3.. {
+            for i in 2.. {
                 let data = match load_cell_data(i, Source::Output) {
                     Ok(data) => data,
                     Err(SysError::IndexOutOfBound) => break,
@@ -182,26 +193,37 @@ pub fn main() -> Result<(), Error> {
             verify(
                 pre_commit,
                 now_commit,
-                now_upk,
+                upk,
+                now_amount_type,
                 deposit_amount - change_amount,
                 true,
+                &BTreeMap::new(),
             )
         }
-        2u8 => {
+        OP_WITHDRAW | OP_ESCAPE => {
             // WITHDRAW
             //
+            // ESCAPE is same as WITHDRAW, but signed by users only, when the commit
+            // cell is not updated in `escape_blocks`.
+            //
             // input0 => pre_commit
-            // input1 => upk
-            // input2 => pre_udt_pool
+            // input1 => pre_udt_pool (of the withdraw token)
+            // input2..n-1 => not of the rollup lock
             // output0 => now_commit
-            // output1 => upk
-            // output2 => now_udt_pool
-            // output3..n-1 => udt_unspend
+            // output1 => now_udt_pool
+            // output2..n-1 => udt_unspend
+            // cell_dep => upk
+
+            // 1. escape only when L2 service is offline.
+            if header.op == OP_ESCAPE {
+                debug!("ESCAPE");
+                check_escape(lock_args.escape_blocks)?;
+            }
 
             // 2. pre udt amount in pool.
             debug!("WITHDRAW");
 
-            let pre_amount = match load_cell_data(2, Source::Input) {
+            let pre_amount = match load_cell_data(UDT_CELL, Source::Input) {
                 Ok(data) => {
                     let mut buf = [0u8; UDT_LEN];
                     if data.len() != UDT_LEN {
@@ -213,11 +235,12 @@ pub fn main() -> Result<(), Error> {
                 }
                 Err(err) => return Err(err.into()),
             };
-            let pre_amount_lock = load_cell_lock_hash(2, Source::Input)?;
-            let pre_amount_type = load_cell_type_hash(2, Source::Input)?;
+            let pre_amount_lock = load_cell_lock_hash(UDT_CELL, Source::Input)?;
+            let pre_amount_type = load_cell_type_hash(UDT_CELL, Source::Input)?;
+            check_unlocked(2, Source::Input, &self_script_hash)?;
 
             // 3. now udt pool amount.
-            let now_amount = match load_cell_data(2, Source::Output) {
+            let now_amount = match load_cell_data(UDT_CELL, Source::Output) {
                 Ok(data) => {
                     let mut buf = [0u8; UDT_LEN];
                     if data.len() != UDT_LEN {
@@ -229,8 +252,8 @@ pub fn main() -> Result<(), Error> {
                 }
                 Err(err) => return Err(err.into()),
             };
-            let now_amount_lock = load_cell_lock_hash(2, Source::Output)?;
-            let now_amount_type = load_cell_type_hash(2, Source::Input)?;
+            let now_amount_lock = load_cell_lock_hash(UDT_CELL, Source::Output)?;
+            let now_amount_type = load_cell_type_hash(UDT_CELL, Source::Output)?;
 
             if (pre_amount_lock != now_amount_lock) || (pre_amount_type != now_amount_type) {
                 return Err(Error::Amount);
@@ -240,11 +263,12 @@ pub fn main() -> Result<(), Error> {
                 return Err(Error::Verify);
             }
 
-            // 4. outputs udt.
+            // 4. outputs udt, and the amount to every recipient's lock.
             let mut withdraw_amount: u128 = 0;
             let mut withdraw_buf = [0u8; UDT_LEN];
+            let mut recipients = BTreeMap::new();
 
-            for i in 3.. {
+            for i in 2.. {
                 let data = match load_cell_data(i, Source::Output) {
                     Ok(data) => data,
                     Err(SysError::IndexOutOfBound) => break,
@@ -262,7 +286,13 @@ pub fn main() -> Result<(), Error> {
                 }
 
                 withdraw_buf.copy_from_slice(&data);
-                withdraw_amount += u128::from_le_bytes(withdraw_buf);
+                let amount = u128::from_le_bytes(withdraw_buf);
+                withdraw_amount += amount;
+
+                let recipient = recipients
+                    .entry(load_cell_lock_hash(i, Source::Output)?)
+                    .or_insert(0u128);
+                *recipient = recipient.checked_add(amount).ok_or(Error::Amount)?;
             }
 
             // 5. check amount.
@@ -271,52 +301,114 @@ pub fn main() -> Result<(), Error> {
             }
 
             // 6. verify commit.
-            verify(pre_commit, now_commit, now_upk, withdraw_amount, false)
+            verify(
+                pre_commit,
+                now_commit,
+                upk,
+                now_amount_type,
+                withdraw_amount,
+                false,
+                &recipients,
+            )
         }
-        3u8 => {
+        OP_POST_BLOCK => {
             // POST BLOCK
             //
             // input0 => pre_commit
-            // input1 => upk
             // output0 => now_commit
-            // output1 => upk
+            // cell_dep => upk
 
             debug!("POST BLOCK");
             // presence of any other cells with the same lock script
             // (to be precise, udt pool cells) is illegal.
-            for i in 2.. {
-                match load_cell_lock_hash(i, Source::Input) {
-                    Ok(lock) => {
-                        if lock == self_script_hash {
-                            return Err(Error::Amount);
-                        }
-                    }
-                    Err(SysError::IndexOutOfBound) => break,
-                    Err(err) => return Err(err.into()),
+            check_unlocked(1, Source::Input, &self_script_hash)?;
+            check_unlocked(1, Source::Output, &self_script_hash)?;
+            // post block proof
+            verify(
+                pre_commit,
+                now_commit,
+                upk,
+                None,
+                0,
+                false,
+                &BTreeMap::new(),
+            )
+        }
+        _ => Err(Error::Encoding),
+    }
+}
+
+/// the cell_upks is in a cell_dep, its data hash is pinned in the lock args.
+fn load_upks(upks_hash: &[u8; 32]) -> Result<Vec<u8>, Error> {
+    // the upk cell has the rollup lock too, but it can never be spent.
+    for i in 0.. {
+        match load_cell_data_hash(i, Source::GroupInput) {
+            Ok(hash) => {
+                if &hash == upks_hash {
+                    return Err(Error::Upk);
                 }
-                match load_cell_lock_hash(i, Source::Output) {
-                    Ok(lock) => {
-                        if lock == self_script_hash {
-                            return Err(Error::Amount);
-                        }
-                    }
-                    Err(SysError::IndexOutOfBound) => break,
-                    Err(err) => return Err(err.into()),
+            }
+            Err(SysError::IndexOutOfBound) => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    for i in 0.. {
+        match load_cell_data_hash(i, Source::CellDep) {
+            Ok(hash) => {
+                if &hash == upks_hash {
+                    return Ok(load_cell_data(i, Source::CellDep)?);
                 }
             }
-            // post block proof
-            verify(pre_commit, now_commit, now_upk, 0, false)
+            Err(SysError::IndexOutOfBound) => break,
+            Err(err) => return Err(err.into()),
         }
-        _ => Err(Error::Encoding),
     }
+
+    Err(Error::Upk)
 }
 
+/// only the commit cell and the op's udt pool can have the rollup lock, the cells of
+/// `source` from `start` on must not, or other tokens' pools could be taken.
+fn check_unlocked(start: usize, source: Source, lock: &[u8; 32]) -> Result<(), Error> {
+    for i in start.. {
+        match load_cell_lock_hash(i, source) {
+            Ok(hash) => {
+                if &hash == lock {
+                    return Err(Error::Amount);
+                }
+            }
+            Err(SysError::IndexOutOfBound) => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// the L2 service is offline when the commit cell input waits `escape_blocks` L1 blocks
+/// since it was created. every escape waits again, an escape doesn't let the next ones
+/// through at once.
+fn check_escape(escape_blocks: u64) -> Result<(), Error> {
+    let since = load_input_since(BLOCK_CELL, Source::Input)?;
+    if since & SINCE_FLAGS_MASK != SINCE_RELATIVE_BLOCK || since & SINCE_VALUE_MASK < escape_blocks
+    {
+        return Err(Error::Escape);
+    }
+
+    Ok(())
+}
+
+/// the change is of the udt pool with `pool_type`, other tokens' pools are not changed.
+/// `recipients` are the withdrawn amounts of the pool's token by the output locks.
 fn verify(
-    mut pre: Vec<u8>,
-    mut now: Vec<u8>,
+    pre: Vec<u8>,
+    now: Vec<u8>,
     upk: Vec<u8>,
+    pool_type: Option<[u8; 32]>,
     change: u128,
     is_add: bool,
+    recipients: &BTreeMap<[u8; 32], u128>,
 ) -> Result<(), Error> {
     debug!(
         "on-chain udt pool change: {}{}",
@@ -324,35 +416,99 @@ fn verify(
         change
     );
 
-    pre.remove(0);
-    now.remove(0);
+    // the data's account size must be the pinned upks cell's.
+    let cell_upks = CellUpks::<Bn_256>::from_cell_data(&upk[..]).map_err(|_| Error::Format)?;
+    let account_size = cell_upks.account_size;
+    let (_, pre_commit) =
+        CellCommit::<Bn_256>::from_cell_data(&pre[..], account_size).map_err(|_| Error::Format)?;
+    let (op, now_commit) =
+        CellCommit::<Bn_256>::from_cell_data(&now[..], account_size).map_err(|_| Error::Format)?;
+
+    // the block body is in the witness of the commit cell input.
+    let witness_args = load_witness_args(0, Source::GroupInput)?;
+    let witness: Bytes = witness_args
+        .lock()
+        .to_opt()
+        .ok_or(Error::ItemMissing)?
+        .unpack();
+    let (block_op, now_block) =
+        Block::<Bn_256>::from_witness(&witness[..], account_size).map_err(|_| Error::Format)?;
+    if block_op != op {
+        return Err(Error::Encoding);
+    }
 
-    let pre_block = Block::<Bn_256>::from_bytes(&pre[..]).unwrap();
-    let now_block = Block::<Bn_256>::from_bytes(&now[..]).unwrap();
+    // users can only withdraw their own balance in escape.
+    if op == OP_ESCAPE
+        && !now_block.txs.iter().all(|tx| match tx.tx_type {
+            TxType::Withdraw(..) => true,
+            _ => false,
+        })
+    {
+        return Err(Error::Escape);
+    }
+
+    debug!("pre & now commit and block deserialization ok");
+    if cell_upks.check().is_err() || pre_commit.commits.len() != cell_upks.shards() {
+        return Err(Error::Format);
+    }
 
-    debug!("pre & now block deserialization ok");
-    if pre_block.new_commit != now_block.commit {
+    // the block's shards must start from the pre commit cell's shards.
+    if now_block
+        .shards
+        .iter()
+        .any(|shard| pre_commit.commits.get(shard.shard as usize) != Some(&shard.commit))
+        || now_block.block_height != pre_commit.block_height + 1
+        || now_block.cell_commit(&pre_commit) != Ok(now_commit)
+    {
         return Err(Error::Commit);
     }
-    debug!("pre & now block is eq!");
-
-    let cell_upks = CellUpks::<Bn_256>::from_bytes(&upk[..]).unwrap();
-
-    match now_block.verify(&cell_upks) {
-        Ok((income, outcome)) => {
-            debug!(
-                "on-chain udt pool change: {}{}, block income: {}, outcome: {}",
-                if is_add { "+" } else { "-" },
-                change,
-                income,
-                outcome
-            );
-            if (is_add && income >= outcome && income - outcome == change)
-                || ((!is_add) && outcome >= income && outcome - income == change)
-            {
-                return Ok(());
+    debug!("pre & now commit is eq!");
+
+    // withdraw rule 9: every tx must be signed by the pubkey in its addr.
+    if let Err(err) = now_block.verify_signatures(&cell_upks) {
+        debug!("block signature failure: {}", err);
+        return Err(Error::Signature);
+    }
+    debug!("block signatures ok");
+
+    // the withdrawn UDT cells must be of the locks signed in the withdraws.
+    let mut signed = BTreeMap::new();
+    for tx in &now_block.txs {
+        if let TxType::Withdraw(_, token, amount, lock_hash) = tx.tx_type {
+            if cell_upks.tokens.get(token as usize).copied() != pool_type {
+                continue;
+            }
+            let recipient = signed.entry(lock_hash).or_insert(0u128);
+            *recipient = recipient.checked_add(amount).ok_or(Error::Amount)?;
+        }
+    }
+    if &signed != recipients {
+        return Err(Error::Amount);
+    }
+
+    match now_block.verify_state(&cell_upks) {
+        Ok(changes) => {
+            for (token, (income, outcome)) in changes.into_iter().enumerate() {
+                let change = if Some(cell_upks.tokens[token]) == pool_type {
+                    change
+                } else {
+                    0
+                };
+                debug!(
+                    "on-chain udt pool {} change: {}{}, block income: {}, outcome: {}",
+                    token,
+                    if is_add { "+" } else { "-" },
+                    change,
+                    income,
+                    outcome
+                );
+                if !((is_add && income >= outcome && income - outcome == change)
+                    || ((!is_add) && outcome >= income && outcome - income == change))
+                {
+                    return Err(Error::Amount);
+                }
             }
-            return Err(Error::Amount);
+            return Ok(());
         }
         _ => {
             debug!("block.verify failure");
@@ -12,6 +12,9 @@ pub enum Error {
     Verify,
     Upk,
     Commit,
+    Signature,
+    Format,
+    Escape,
 }
 
 impl From<SysError> for Error {
@@ -8,11 +8,18 @@ use std::time::Duration;
 use tide::{Body, Error, Request, Response};
 
 use ckb_testtool::{builtin::ALWAYS_SUCCESS, context::Context};
+use ckb_tool::ckb_hash::new_blake2b;
 use ckb_tool::ckb_types::{bytes::Bytes, core::Capacity, packed::*, prelude::*};
 
 // 1 is error
 const MAX_CYCLES: u64 = 5_000_000_000;
 
+/// mock UDTs, same as the L2's TOKEN_SIZE.
+const TOKEN_SIZE: u8 = 2;
+
+/// CKB of the deployer's funding cell, pays the rollup cells' capacity & the fees.
+const FUNDING_CKB: usize = 10_000_000;
+
 fn jsonrpc(params: Value) -> Value {
     json!(
         {
@@ -23,6 +30,17 @@ fn jsonrpc(params: Value) -> Value {
     )
 }
 
+/// the JSON-RPC error object, the codes are CKB's.
+fn jsonrpc_error(code: i64, message: String) -> Value {
+    json!(
+        {
+            "id": 0,
+            "jsonrpc": "2.0",
+            "error": { "code": code, "message": message },
+        }
+    )
+}
+
 /// Miner task.
 async fn miner(blockchain: Arc<RwLock<Blockchain>>) -> Result<(), std::io::Error> {
     loop {
@@ -38,6 +56,7 @@ async fn miner(blockchain: Arc<RwLock<Blockchain>>) -> Result<(), std::io::Error
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 struct DeployReq {
     pub contract: String,
+    pub type_contract: String,
 }
 
 async fn deploy(mut req: Request<Arc<RwLock<Blockchain>>>) -> Result<Response, Error> {
@@ -46,6 +65,9 @@ async fn deploy(mut req: Request<Arc<RwLock<Blockchain>>>) -> Result<Response, E
     let rollup_bin: Bytes = std::fs::read(format!("./build/release/{}", rpc.contract))
         .expect("binary")
         .into();
+    let commit_type_bin: Bytes = std::fs::read(format!("./build/release/{}", rpc.type_contract))
+        .expect("binary")
+        .into();
 
     let mut blockchain = req.state().write().await;
 
@@ -56,29 +78,71 @@ async fn deploy(mut req: Request<Arc<RwLock<Blockchain>>>) -> Result<Response, E
         .expect("script");
 
     let rollup_point = blockchain.context.deploy_cell(rollup_bin);
-    let rollup_script_args: Bytes = [0u8; 1].to_vec().into();
+    // placeholder args, the init tx pins it to the upks cell data hash.
+    let rollup_script_args: Bytes = [0u8; 32].to_vec().into();
     let rollup_lock_script = blockchain
         .context
         .build_script(&rollup_point, rollup_script_args)
         .expect("script");
     let rollup_lock_script_dep = CellDep::new_builder().out_point(rollup_point).build();
 
+    let commit_type_point = blockchain.context.deploy_cell(commit_type_bin);
+    // placeholder args, the init tx sets it to the type id.
+    let commit_type_args: Bytes = [0u8; 32].to_vec().into();
+    let commit_type_script = blockchain
+        .context
+        .build_script(&commit_type_point, commit_type_args)
+        .expect("script");
+    let commit_type_script_dep = CellDep::new_builder().out_point(commit_type_point).build();
+
     let input_ckb = Capacity::bytes(1000).unwrap().as_u64();
-    // init UDT 100,000
-    let my_udt_point = blockchain.context.create_cell(
+    // mock UDT types are always success with the token id as args, init UDT 100,000 of each.
+    let mut udt_type_scripts = vec![];
+    let mut my_udt_points = vec![];
+    for token in 0..TOKEN_SIZE {
+        let udt_type_script = blockchain
+            .context
+            .build_script(&success_point, vec![token].into())
+            .expect("script");
+        let my_udt_point = blockchain.create_cell(
+            CellOutput::new_builder()
+                .capacity(input_ckb.pack())
+                .lock(success_lock_script.clone())
+                .type_(Some(udt_type_script.clone()).pack())
+                .build(),
+            100000u128.to_le_bytes().to_vec().into(),
+        );
+        udt_type_scripts.push(hex::encode(udt_type_script.as_slice()));
+        my_udt_points.push(hex::encode(my_udt_point.as_slice()));
+    }
+    // the first input of init state, so the commit cell's type id is unique.
+    let seed_point = blockchain.create_cell(
         CellOutput::new_builder()
             .capacity(input_ckb.pack())
             .lock(success_lock_script.clone())
             .build(),
-        100000u128.to_le_bytes().to_vec().into(),
+        Bytes::new(),
+    );
+    // the deployer's CKB, the rollup finds it by the lock.
+    let funding_ckb = Capacity::bytes(FUNDING_CKB).unwrap().as_u64();
+    blockchain.create_cell(
+        CellOutput::new_builder()
+            .capacity(funding_ckb.pack())
+            .lock(success_lock_script.clone())
+            .build(),
+        Bytes::new(),
     );
 
     let mut res = Response::new(200);
-    res.set_body(Body::from_json(&jsonrpc(json!(vec![
+    res.set_body(Body::from_json(&jsonrpc(json!([
         hex::encode(rollup_lock_script.as_slice()),
         hex::encode(rollup_lock_script_dep.as_slice()),
         hex::encode(success_lock_script.as_slice()),
-        hex::encode(my_udt_point.as_slice())
+        my_udt_points,
+        hex::encode(commit_type_script.as_slice()),
+        hex::encode(commit_type_script_dep.as_slice()),
+        hex::encode(seed_point.as_slice()),
+        udt_type_scripts
     ])))?);
 
     Ok(res)
@@ -98,9 +162,15 @@ async fn rpc(mut req: Request<Arc<RwLock<Blockchain>>>) -> Result<Response, Erro
 
     let results = match method {
         "get_tip_block_number" => json!(req.state().read().await.get_block_height()),
+        "get_block_hash" => json!(req
+            .state()
+            .read()
+            .await
+            .get_block_hash(params[0].parse().unwrap())
+            .map(|hash| hex::encode(hash))),
         "get_block" => {
-            let mut txs: Vec<Vec<Vec<String>>> = vec![];
-            for (_tx_hash, outptus) in req
+            let mut txs: Vec<Value> = vec![];
+            for (_tx_hash, (outptus, witnesses, cell_deps)) in req
                 .state()
                 .read()
                 .await
@@ -108,18 +178,62 @@ async fn rpc(mut req: Request<Arc<RwLock<Blockchain>>>) -> Result<Response, Erro
             {
                 let mut outs = vec![];
                 for out in outptus {
-                    let (s, p, d) = out;
+                    let (s, t, p, d) = out;
                     outs.push(vec![
                         hex::encode(s.as_slice()),
                         hex::encode(p.as_slice()),
                         hex::encode(d),
+                        t.map(|t| hex::encode(t.as_slice())).unwrap_or_default(),
                     ]);
                 }
-                txs.push(outs);
+                let witnesses: Vec<String> = witnesses.iter().map(|w| hex::encode(w)).collect();
+                let cell_deps: Vec<String> = cell_deps
+                    .iter()
+                    .map(|d| hex::encode(d.as_slice()))
+                    .collect();
+                txs.push(
+                    json!({ "outputs": outs, "witnesses": witnesses, "cell_deps": cell_deps }),
+                );
             }
 
             json!(txs)
         }
+        "get_live_cell" => {
+            let out_point = OutPoint::from_slice(&hex::decode(&params[0]).unwrap()).unwrap();
+            // the capacity, null when the cell is spent or unknown.
+            let capacity: Option<u64> = req
+                .state()
+                .read()
+                .await
+                .live
+                .get(&out_point)
+                .map(|(output, _)| output.capacity().unpack());
+            json!(capacity)
+        }
+        "get_cells" => {
+            let lock = Script::from_slice(&hex::decode(&params[0]).unwrap()).unwrap();
+            let blockchain = req.state().read().await;
+            let cells: Vec<Value> = blockchain
+                .live
+                .iter()
+                .filter(|(_, (output, _))| output.lock() == lock)
+                .map(|(out_point, (output, data))| {
+                    let capacity: u64 = output.capacity().unpack();
+                    json!({
+                        "out_point": hex::encode(out_point.as_slice()),
+                        "capacity": capacity,
+                        "data": hex::encode(data),
+                        "type": output
+                            .type_()
+                            .to_opt()
+                            .map(|t| hex::encode(t.as_slice()))
+                            .unwrap_or_default(),
+                    })
+                })
+                .collect();
+
+            json!(cells)
+        }
         "send_transaction" => {
             let tx_bytes = hex::decode(&params[0]).unwrap();
             let tx = TransactionView::new_unchecked(tx_bytes.into());
@@ -134,15 +248,28 @@ async fn rpc(mut req: Request<Arc<RwLock<Blockchain>>>) -> Result<Response, Erro
             let new_tx = blockchain.context.complete_tx(tx.unpack());
 
             println!("start verify tx...");
-            let cycles = blockchain
-                .context
-                .verify_tx(&new_tx, MAX_CYCLES)
-                .expect("pass verification");
+            let cycles = match blockchain.context.verify_tx(&new_tx, MAX_CYCLES) {
+                Ok(cycles) => cycles,
+                Err(err) => {
+                    println!("Tx verification failure: {:?}", err);
+                    // CKB's TransactionFailedToVerify.
+                    let mut res = Response::new(200);
+                    res.set_body(Body::from_json(&jsonrpc_error(
+                        -302,
+                        format!("TransactionFailedToVerify: {:?}", err),
+                    ))?);
+                    return Ok(res);
+                }
+            };
             println!("Tx cycles: {}...", cycles);
 
+            for input in tx.data().raw().inputs() {
+                blockchain.live.remove(&input.previous_output());
+            }
+
             // MOCK: context create_ouput_cell for next call.
             let mut results = vec![];
-            let mut mock_tx = vec![];
+            let mut mock_outputs = vec![];
 
             for i in 0..new_tx.outputs().len() {
                 let output = new_tx.outputs().get(i).unwrap();
@@ -154,15 +281,22 @@ async fn rpc(mut req: Request<Arc<RwLock<Blockchain>>>) -> Result<Response, Erro
                     data.pack().len()
                 );
                 let lock = output.lock();
-                let out_point = blockchain.context.create_cell(output, data.clone());
+                let type_ = output.type_().to_opt();
+                let out_point = blockchain.create_cell(output, data.clone());
 
                 results.push(hex::encode(out_point.as_slice()));
-                mock_tx.push((lock, out_point, data));
+                mock_outputs.push((lock, type_, out_point, data));
             }
 
+            let witnesses: Vec<Bytes> =
+                new_tx.witnesses().into_iter().map(|w| w.unpack()).collect();
+            let cell_deps: Vec<CellDep> = tx.data().raw().cell_deps().into_iter().collect();
+
             let tx_hash = tx.hash();
 
-            blockchain.pool.insert(tx_hash.clone(), mock_tx);
+            blockchain
+                .pool
+                .insert(tx_hash.clone(), (mock_outputs, witnesses, cell_deps));
 
             json!(results)
         }
@@ -174,16 +308,31 @@ async fn rpc(mut req: Request<Arc<RwLock<Blockchain>>>) -> Result<Response, Erro
     Ok(res)
 }
 
-/// lock script, outpoint, data
-type MockTx = Vec<(Script, OutPoint, Bytes)>;
+/// outputs (lock script, type script, outpoint, data), witnesses, cell deps
+type MockTx = (
+    Vec<(Script, Option<Script>, OutPoint, Bytes)>,
+    Vec<Bytes>,
+    Vec<CellDep>,
+);
 
 struct Blockchain {
     context: Context,
+    /// the cells created by deploy & txs, and not spent.
+    live: HashMap<OutPoint, (CellOutput, Bytes)>,
     pool: HashMap<Byte32, MockTx>,
     blocks: HashMap<u32, HashMap<Byte32, MockTx>>,
+    /// block hash = blake2b(parent hash || sorted tx hashes).
+    hashes: HashMap<u32, [u8; 32]>,
 }
 
 impl Blockchain {
+    /// create the cell in the context, it is live until a tx spends it.
+    fn create_cell(&mut self, output: CellOutput, data: Bytes) -> OutPoint {
+        let out_point = self.context.create_cell(output.clone(), data.clone());
+        self.live.insert(out_point.clone(), (output, data));
+        out_point
+    }
+
     fn miner_block(&mut self) {
         let mut block = HashMap::new();
         for (hash, data) in self.pool.drain() {
@@ -192,6 +341,20 @@ impl Blockchain {
 
         let current_height = self.blocks.len() as u32;
         println!("Miner new block: {}, txs: {}", current_height, block.len());
+
+        let mut tx_hashes: Vec<&Byte32> = block.keys().collect();
+        tx_hashes.sort_by_key(|hash| hash.as_slice());
+        let mut hasher = new_blake2b();
+        if current_height > 0 {
+            hasher.update(&self.hashes[&(current_height - 1)]);
+        }
+        for tx_hash in tx_hashes {
+            hasher.update(tx_hash.as_slice());
+        }
+        let mut hash = [0u8; 32];
+        hasher.finalize(&mut hash);
+
+        self.hashes.insert(current_height, hash);
         self.blocks.insert(current_height, block);
     }
 
@@ -199,6 +362,10 @@ impl Blockchain {
         self.blocks.len() as u32
     }
 
+    fn get_block_hash(&self, height: u32) -> Option<[u8; 32]> {
+        self.hashes.get(&height).cloned()
+    }
+
     fn get_block(&self, height: u32) -> HashMap<Byte32, MockTx> {
         if let Some(block) = self.blocks.get(&height) {
             block.clone()
@@ -212,8 +379,10 @@ impl Default for Blockchain {
     fn default() -> Self {
         Self {
             context: Context::default(),
+            live: HashMap::new(),
             pool: HashMap::new(),
             blocks: HashMap::new(),
+            hashes: HashMap::new(),
         }
     }
 }
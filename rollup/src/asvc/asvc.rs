@@ -1,4 +1,4 @@
-use ckb_zkp::math::{fft::EvaluationDomain, PairingEngine, Zero};
+use ckb_zkp::math::{fft::EvaluationDomain, FromBytes, PairingEngine, ToBytes, Zero};
 use ckb_zkp::scheme::asvc::{
     commit, key_gen, prove_pos, update_proof, Commitment, Parameters, Proof, UpdateKey,
 };
@@ -6,14 +6,20 @@ use ckb_zkp::scheme::r1cs::SynthesisError;
 use rand::thread_rng;
 use std::collections::HashMap;
 
-use asvc_rollup::transaction::FullPubKey;
+use asvc_rollup::block::CellUpks;
+use asvc_rollup::transaction::{position, FullPubKey, TOKEN_SIZE};
 
+use super::db::{read_vec, write_vec};
+
+/// every account has TOKEN_SIZE slots in the vector,
+/// the accounts are split into shards of shard_accounts, every shard has a commitment.
 pub fn initialize_asvc<E>(
-    n: usize,
+    accounts: usize,
+    shard_accounts: usize,
 ) -> Result<
     (
         Parameters<E>,
-        Commitment<E>,
+        Vec<Commitment<E>>,
         Vec<Proof<E>>,
         Vec<FullPubKey<E>>,
     ),
@@ -23,23 +29,28 @@ where
     E: PairingEngine,
 {
     let rng = &mut thread_rng();
+    let n = shard_accounts * TOKEN_SIZE;
     println!("start to initialize params...");
-    if !n.is_power_of_two() {
+    if !n.is_power_of_two() || shard_accounts == 0 || accounts % shard_accounts != 0 {
         return Err(SynthesisError::Unsatisfiable);
     }
+    let shards = accounts / shard_accounts;
     let params = key_gen::<E, _>(n, rng)?;
     println!("initialize params...ok");
 
     println!("start to initialize commit...");
     let values = vec![E::Fr::zero(); n];
     let commit = commit::<E>(&params.proving_key, values)?;
+    // all shards start with the same empty vector.
+    let commits = vec![commit; shards];
     println!("initialize commit...ok");
 
     let mut full_pubkeys = vec![];
-    for i in 0..n {
+    for i in 0..accounts {
         full_pubkeys.push(FullPubKey::default(
             i as u32,
-            params.proving_key.update_keys[i].clone(),
+            params.proving_key.update_keys[position((i % shard_accounts) as u32, 0) as usize]
+                .clone(),
         ));
     }
 
@@ -49,16 +60,77 @@ where
         let proof = prove_pos::<E>(&params.proving_key, vec![E::Fr::zero()], vec![i as u32])?;
         proofs.push(proof);
     }
+    let proofs = (0..shards).flat_map(|_| proofs.clone()).collect();
 
     println!("initialize proofs...ok");
 
-    Ok((params, commit, proofs, full_pubkeys))
+    Ok((params, commits, proofs, full_pubkeys))
+}
+
+/// save the params file: the upks cell's data, the initial commitments and all positions'
+/// proofs. the rest of the proving key is only used to make the commitments & proofs.
+pub fn save_params<E: PairingEngine>(
+    path: &str,
+    cell_upks: &CellUpks<E>,
+    commits: &[Commitment<E>],
+    proofs: &[Proof<E>],
+) -> Result<(), String> {
+    let mut bytes = Vec::new();
+    // every part is length prefixed.
+    for part in &[
+        cell_upks.to_cell_data(),
+        write_vec(commits),
+        write_vec(proofs),
+    ] {
+        (part.len() as u32).write(&mut bytes).unwrap();
+        bytes.extend_from_slice(part);
+    }
+
+    std::fs::write(path, bytes).map_err(|e| format!("write params {} failure: {}", path, e))
+}
+
+/// load the params file saved by `save_params`.
+pub fn load_params<E: PairingEngine>(
+    path: &str,
+) -> Result<(CellUpks<E>, Vec<Commitment<E>>, Vec<Proof<E>>), String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("read params {} failure: {}", path, e))?;
+    let mut s = &bytes[..];
+
+    let mut parts = vec![];
+    for _ in 0..3 {
+        let len = u32::read(&mut s).map_err(|e| e.to_string())? as usize;
+        if s.len() < len {
+            return Err(String::from("params is truncated"));
+        }
+        parts.push(&s[..len]);
+        s = &s[len..];
+    }
+
+    let cell_upks =
+        CellUpks::from_cell_data(parts[0]).map_err(|_| String::from("params upks is invalid"))?;
+    cell_upks.check()?;
+    let commits: Vec<Commitment<E>> = read_vec(parts[1])?;
+    let proofs: Vec<Proof<E>> = read_vec(parts[2])?;
+    if commits.len() != cell_upks.shards() || proofs.len() != cell_upks.vector_size() {
+        return Err(String::from("params size is not match"));
+    }
+
+    Ok((cell_upks, commits, proofs))
+}
+
+/// the proofs of the all zero vector are the zero point, the rollup's genesis proofs.
+pub fn zero_proofs<E: PairingEngine>(n: usize) -> Vec<Proof<E>> {
+    // a proof is serialized as its point.
+    let mut bytes = vec![];
+    E::G1Affine::zero().write(&mut bytes).unwrap();
+    let proof = Proof::<E>::read(&bytes[..]).unwrap();
+    vec![proof; n]
 }
 
 pub fn update_proofs<E>(
     upks: &Vec<UpdateKey<E>>,
     _commit: &Commitment<E>,
-    proofs: &mut Vec<Proof<E>>,
+    proofs: &mut [Proof<E>],
     cvalues: &HashMap<u32, E::Fr>,
     n: usize,
 ) -> Result<(), SynthesisError>
@@ -0,0 +1,114 @@
+use ckb_zkp::math::{FromBytes, ToBytes};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// key-value store of the node's state.
+pub trait Store: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String>;
+
+    /// write all the items atomically, after return they survive a crash.
+    fn write_batch(&self, items: Vec<(String, Vec<u8>)>) -> Result<(), String>;
+}
+
+/// keep the state in memory only, when the node has no `--db`.
+#[derive(Default)]
+pub struct MemoryStore(Mutex<HashMap<String, Vec<u8>>>);
+
+impl Store for MemoryStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.0.lock().unwrap().get(key).cloned())
+    }
+
+    fn write_batch(&self, items: Vec<(String, Vec<u8>)>) -> Result<(), String> {
+        let mut map = self.0.lock().unwrap();
+        for (key, value) in items {
+            map.insert(key, value);
+        }
+        Ok(())
+    }
+}
+
+/// sled database in a directory.
+pub struct SledStore(sled::Db);
+
+impl SledStore {
+    pub fn open(path: &str) -> Result<Self, String> {
+        sled::open(path)
+            .map(SledStore)
+            .map_err(|e| format!("open db {} failure: {}", path, e))
+    }
+}
+
+impl Store for SledStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        self.0
+            .get(key)
+            .map(|v| v.map(|v| v.to_vec()))
+            .map_err(|e| e.to_string())
+    }
+
+    fn write_batch(&self, items: Vec<(String, Vec<u8>)>) -> Result<(), String> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in items {
+            batch.insert(key.as_bytes(), value);
+        }
+        self.0.apply_batch(batch).map_err(|e| e.to_string())?;
+        self.0.flush().map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+pub fn write_vec<T: ToBytes>(items: &[T]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    (items.len() as u32).write(&mut bytes).unwrap();
+    for item in items {
+        item.write(&mut bytes).unwrap();
+    }
+    bytes
+}
+
+pub fn read_vec<T: FromBytes>(mut bytes: &[u8]) -> Result<Vec<T>, String> {
+    let n = u32::read(&mut bytes).map_err(|e| e.to_string())?;
+    let mut items = Vec::new();
+    for _ in 0..n {
+        items.push(T::read(&mut bytes).map_err(|e| e.to_string())?);
+    }
+    Ok(items)
+}
+
+/// the items are prefixed with their lengths.
+pub fn write_bytes(items: &[Vec<u8>]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    (items.len() as u32).write(&mut bytes).unwrap();
+    for item in items {
+        (item.len() as u32).write(&mut bytes).unwrap();
+        bytes.extend_from_slice(item);
+    }
+    bytes
+}
+
+pub fn read_bytes(mut bytes: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let n = u32::read(&mut bytes).map_err(|e| e.to_string())?;
+    let mut items = Vec::new();
+    for _ in 0..n {
+        let len = u32::read(&mut bytes).map_err(|e| e.to_string())? as usize;
+        if bytes.len() < len {
+            return Err(String::from("bytes are truncated"));
+        }
+        items.push(bytes[..len].to_vec());
+        bytes = &bytes[len..];
+    }
+    Ok(items)
+}
+
+pub fn write_strings(items: &[String]) -> Vec<u8> {
+    let items: Vec<Vec<u8>> = items.iter().map(|s| s.as_bytes().to_vec()).collect();
+    write_bytes(&items)
+}
+
+pub fn read_strings(bytes: &[u8]) -> Result<Vec<String>, String> {
+    read_bytes(bytes)?
+        .into_iter()
+        .map(|s| String::from_utf8(s).map_err(|e| e.to_string()))
+        .collect()
+}
@@ -3,7 +3,9 @@ use async_std::{
     task,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
 use std::time::Duration;
 use tide::{Error, Request, StatusCode};
 
@@ -11,119 +13,305 @@ use ckb_zkp::curve::bn_256::Bn_256;
 use ckb_zkp::math::PairingEngine;
 
 mod asvc;
+mod db;
 mod storage;
 
-use asvc::initialize_asvc;
+use asvc::{initialize_asvc, load_params, save_params};
+use db::{MemoryStore, SledStore, Store};
 use storage::Storage;
 
-use asvc_rollup::block::Block;
-use asvc_rollup::transaction::{PublicKey, SecretKey, ACCOUNT_SIZE};
+use asvc_rollup::block::{Block, CellCommit, CellUpks};
+use asvc_rollup::format::{OP_ESCAPE, OP_WITHDRAW};
+use asvc_rollup::transaction::{PublicKey, SecretKey, DEFAULT_ACCOUNT_SIZE, TOKEN_SIZE};
 use ckb_rpc::{
-    deploy_contract, init_state, listen_blocks, send_block, send_deposit, send_withdraw,
+    decode, escape_since, lock_args, script_hash, CkbClient, CkbRpcError, Deployment, Dialect,
+    FundingSigner, RollupTxBuilder, DEFAULT_NODE_URL,
+};
+use ckb_tool::ckb_types::{
+    packed::{OutPoint, Script},
+    prelude::*,
 };
 
 /// listening task.
 async fn listen_contracts<E: PairingEngine>(
     storage: Arc<RwLock<Storage<E>>>,
 ) -> Result<(), std::io::Error> {
-    let mut l1_block_height = 0;
-
     loop {
         // 10s to read lastest block to check if block has deposit tx.
         task::sleep(Duration::from_secs(10)).await;
-        println!(
-            "Listen Task: start read block's txs. Current block height: {}",
-            l1_block_height
-        );
+        sync_blocks(&storage).await;
+    }
+}
+
+/// handle the L2 blocks committed on L1 since the listened L1 height.
+async fn sync_blocks<E: PairingEngine>(storage: &Arc<RwLock<Storage<E>>>) {
+    // the node's own blocks are handled after sent, not here.
+    let sending = storage.read().await.l1_sending.clone();
+    let _sending = sending.lock().await;
+    let ckb = storage.read().await.ckb.clone();
+
+    // L1 reorgs, roll back to the newest handled L1 block still on chain.
+    let l1_hashes = storage.read().await.l1_hashes.clone();
+    let reorged = match find_fork(&ckb, &l1_hashes).await {
+        Ok(Some(fork)) => {
+            println!("Listen Task: L1 reorg, roll back to L1 block: {}", fork);
+            let mut write_storage = storage.write().await;
+            if let Err(error) = write_storage.rollback(fork) {
+                println!("Listen Task: roll back failure: {}", error);
+                return;
+            }
+            persist(&write_storage);
+            drop(write_storage);
+            true
+        }
+        Ok(None) => false,
+        Err(error) => {
+            println!("Listen Task: check L1 reorg failure: {}", error);
+            return;
+        }
+    };
 
-        let read_storage = storage.read().await;
-        let rollup_lock = &read_storage.rollup_lock;
-
-        if let Ok(blocks) = listen_blocks(l1_block_height, rollup_lock).await {
-            drop(read_storage);
-            for (block, new_height) in blocks.iter() {
-                for (bytes, new_commit, new_upk, is_new_udt) in block {
-                    if let Ok(block) = Block::from_bytes(&bytes[..]) {
-                        let mut write_storage = storage.write().await;
-
-                        block
-                            .verify(&write_storage.cell_upks)
-                            .expect("BLOCK VERIFY ERROR");
-
-                        write_storage.handle_block(block);
-
-                        write_storage.commit_cell = new_commit.clone();
-                        write_storage.upk_cell = new_upk.clone();
-                        if let Some((new_udt, amount)) = is_new_udt {
-                            write_storage.udt_cell = new_udt.clone();
-                            write_storage.total_udt_amount = *amount;
-                        }
-                        drop(write_storage);
-                    }
+    let read_storage = storage.read().await;
+    let l1_block_height = read_storage.l1_block_height;
+    let account_size = read_storage.cell_upks.account_size;
+    let rollup_lock = read_storage.rollup_lock.clone();
+    let commit_type = read_storage.commit_type.clone();
+    drop(read_storage);
+    println!(
+        "Listen Task: start read block's txs. Current block height: {}",
+        l1_block_height
+    );
+
+    let blocks = ckb
+        .listen_blocks(l1_block_height, &rollup_lock, &commit_type)
+        .await;
+    if let Err(error) = &blocks {
+        println!("Listen Task: read blocks failure: {}", error);
+    }
+
+    if let Ok(blocks) = blocks {
+        for (block, new_height, _hash) in blocks.iter() {
+            // the txs of a L1 block are not ordered, handle the L2 blocks by height.
+            let mut l2_blocks: Vec<_> = block
+                .iter()
+                .filter_map(|(bytes, new_commit, is_new_udt)| {
+                    Block::<E>::from_witness(&bytes[..], account_size)
+                        .ok()
+                        .map(|(_, block)| (block, new_commit, is_new_udt))
+                })
+                .collect();
+            l2_blocks.sort_by_key(|(block, _, _)| block.block_height);
+
+            for (block, new_commit, is_new_udt) in l2_blocks {
+                let mut write_storage = storage.write().await;
+
+                // the miner's own blocks, or handled before the node restarted.
+                if block.block_height <= write_storage.block_height {
+                    write_storage.confirm_block(block.block_height, *new_height);
+                    continue;
                 }
 
-                l1_block_height = *new_height;
+                // the L1 block is not confirmed, and handled again in the next round.
+                if let Err(error) = block.verify(&write_storage.cell_upks) {
+                    println!(
+                        "Listen Task: block {} verify failure: {}",
+                        block.block_height, error
+                    );
+                    return;
+                }
+                // deposit & withdraw block has only one tx.
+                let token = block.txs.get(0).map(|tx| tx.token()).unwrap_or(0) as usize;
+                let block_height = block.block_height;
+
+                if let Err(error) = write_storage.handle_block(block) {
+                    println!(
+                        "Listen Task: block {} handle failure: {}",
+                        block_height, error
+                    );
+                    return;
+                }
+                write_storage.confirm_block(block_height, *new_height);
+
+                write_storage.commit_cell = new_commit.clone();
+                if let Some((new_udt, amount)) = is_new_udt {
+                    write_storage.udt_cells[token] = new_udt.clone();
+                    write_storage.total_udt_amounts[token] = *amount;
+                }
+                persist(&write_storage);
+                drop(write_storage);
             }
         }
 
-        println!(
-            "Listen Task: end read block's txs. Current block height: {}",
-            l1_block_height
-        );
+        if !blocks.is_empty() {
+            let mut write_storage = storage.write().await;
+            for (_, new_height, hash) in blocks {
+                write_storage.confirm_l1_block(new_height, hash);
+            }
+            // the pool's txs are on the state before the blocks handled again.
+            if reorged {
+                write_storage.rebuild_pools();
+            }
+            persist(&write_storage);
+            drop(write_storage);
+        }
     }
+
+    println!(
+        "Listen Task: end read block's txs. Current block height: {}",
+        storage.read().await.l1_block_height
+    );
+}
+
+/// the newest handled L1 block still on chain when L1 reorgs, none without a reorg.
+/// a reorg deeper than the kept hashes can not be rolled back, and is an error.
+async fn find_fork(
+    ckb: &CkbClient,
+    l1_hashes: &BTreeMap<u64, String>,
+) -> Result<Option<u64>, String> {
+    for (i, (height, hash)) in l1_hashes.iter().rev().enumerate() {
+        let now_hash = ckb
+            .get_block_hash(*height)
+            .await
+            .map_err(|error| error.to_string())?;
+        if now_hash.as_ref() == Some(hash) {
+            return Ok(if i == 0 { None } else { Some(*height) });
+        }
+    }
+
+    if l1_hashes.is_empty() {
+        Ok(None)
+    } else {
+        Err(String::from(
+            "the L1 reorg is deeper than the confirmations",
+        ))
+    }
+}
+
+/// a new node follows the rollup on L1, from the init tx found by the commit type.
+/// the deploy's wallet (my udts & udt lock) is not on L1, so it can not deposit.
+async fn sync_genesis<E: PairingEngine>(
+    ckb: &CkbClient,
+    commit_type: &String,
+    db: Box<dyn Store>,
+) -> Result<Storage<E>, String> {
+    let (l1_block_height, rollup_lock, outputs, cell_deps) = ckb
+        .listen_genesis(commit_type)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    // init tx: outputs are commit, vaults..., upks. cell deps are rollup lock & commit type.
+    if outputs.len() < 2 || cell_deps.len() < 2 {
+        return Err(String::from("the rollup's init tx is invalid"));
+    }
+    let commit_cell = &outputs[0];
+    let upk_cell = &outputs[outputs.len() - 1];
+    let vaults = &outputs[1..outputs.len() - 1];
+
+    let cell_upks = CellUpks::<E>::from_cell_data(&upk_cell.data)
+        .map_err(|_| String::from("the upks cell is invalid"))?;
+    let (_, cell_commit) =
+        CellCommit::<E>::from_cell_data(&commit_cell.data, cell_upks.account_size)
+            .map_err(|_| String::from("the commit cell is invalid"))?;
+    cell_upks.check()?;
+    if cell_commit.commits.len() != cell_upks.shards() || vaults.len() != TOKEN_SIZE {
+        return Err(String::from("the rollup's init tx is invalid"));
+    }
+    let lock_args =
+        lock_args(&rollup_lock).map_err(|_| String::from("the rollup lock is invalid"))?;
+
+    let mut storage = Storage::genesis(cell_upks, cell_commit.commits, db);
+    storage.ckb = ckb.clone();
+    storage.escape_blocks = lock_args.escape_blocks;
+    storage.l1_block_height = l1_block_height;
+    storage.rollup_lock = rollup_lock;
+    storage.rollup_dep = cell_deps[0].clone();
+    storage.commit_type = commit_type.clone();
+    storage.commit_type_dep = cell_deps[1].clone();
+    storage.commit_cell = commit_cell.out_point.clone();
+    storage.upk_cell = upk_cell.out_point.clone();
+    storage.udt_types = vaults.iter().map(|vault| vault.type_.clone()).collect();
+    storage.udt_cells = vaults.iter().map(|vault| vault.out_point.clone()).collect();
+
+    Ok(storage)
 }
 
 /// Miner task.
 async fn miner<E: PairingEngine>(storage: Arc<RwLock<Storage<E>>>) -> Result<(), std::io::Error> {
-    let read_storage = storage.write().await;
-    let cell_upks = read_storage.cell_upks.clone();
-    drop(read_storage);
+    let sending = storage.read().await.l1_sending.clone();
 
     loop {
         // 10s to miner a block. (mock consensus)
         task::sleep(Duration::from_secs(10)).await;
 
+        let _sending = sending.lock().await;
         let mut write_storage = storage.write().await;
 
-        if let Some(block) = write_storage.create_block() {
-            println!("SUCCESS MINER A BLOCK tx is: {}", block.txs.len());
-
-            let verify_res = block.verify(&cell_upks);
-            println!("Block verify is: {:?}", verify_res);
-
-            let rollup_hash: &String = &write_storage.rollup_lock;
-            let rollup_dep_hash: &String = &write_storage.rollup_dep;
-            let pre_commit_hash: &String = &write_storage.commit_cell;
-            let pre_upk_hash: &String = &write_storage.upk_cell;
-            let block_bytes: Vec<u8> = block.to_bytes();
-
-            if let Ok((new_commit_cell, new_upk_cell, tx_id)) = send_block(
-                rollup_hash,
-                rollup_dep_hash,
-                pre_commit_hash,
-                pre_upk_hash,
-                block_bytes,
-                cell_upks.to_bytes(),
-            )
-            .await
-            {
-                write_storage.commit_cell = new_commit_cell;
-                write_storage.upk_cell = new_upk_cell;
+        let block = match write_storage.create_block() {
+            Ok(Some(block)) => block,
+            Ok(None) => continue,
+            Err(error) => {
+                println!("miner block failure: {}", error);
+                continue;
+            }
+        };
+        println!("SUCCESS MINER A BLOCK tx is: {}", block.txs.len());
+
+        if let Err(error) = block.verify(&write_storage.cell_upks) {
+            println!("Block verify is failure: {}, txs are dropped.", error);
+            write_storage.drop_block();
+            persist_pools(&write_storage);
+            continue;
+        }
+
+        let tx = write_storage
+            .tx_builder()
+            .and_then(|builder| builder.post_block(&write_storage.live_commit()?, &block))
+            .and_then(|tx| Ok((tx, write_storage.l1_sender()?)));
+        // the txs still go to the pool while sending.
+        drop(write_storage);
+        let sent = match tx {
+            Ok((tx, sender)) => sender.send(tx).await,
+            Err(error) => Err(error),
+        };
 
+        let mut write_storage = storage.write().await;
+        match sent {
+            Ok((out_points, tx_id)) => {
                 println!("block send L1 is success: tx: {}", tx_id);
-                write_storage.handle_block(block);
-            } else {
+                // the listener handles the block from L1 when it fails here.
+                if let Err(error) = write_storage.handle_block(block) {
+                    println!("block handle failure: {}", error);
+                    continue;
+                }
+                write_storage.commit_cell = out_points[0].clone();
+                persist(&write_storage);
+            }
+            Err(error) => {
+                println!("block send L1 is failure: {}, txs back to the pool.", error);
                 write_storage.revert_block(block);
+                persist_pools(&write_storage);
             }
         }
+    }
+}
 
-        drop(write_storage);
+/// the persist failure is logged, the state is persisted again with the next change.
+fn persist<E: PairingEngine>(storage: &Storage<E>) {
+    if let Err(error) = storage.persist() {
+        println!("PERSIST STORAGE FAILURE: {}", error);
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+fn persist_pools<E: PairingEngine>(storage: &Storage<E>) {
+    if let Err(error) = storage.persist_pools() {
+        println!("PERSIST STORAGE FAILURE: {}", error);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct RegisterRequest {
-    pub pubkey: String,
+    #[serde(default)]
+    pub pubkey: Option<String>,
     pub psk: String,
 }
 
@@ -131,16 +319,29 @@ async fn register<E: PairingEngine>(
     mut req: Request<Arc<RwLock<Storage<E>>>>,
 ) -> Result<String, Error> {
     let params: RegisterRequest = req.body_json().await?;
-    println!("new next user: {:?}", params);
 
-    let (pubkey, sk) = (
-        PublicKey::from_hex(&params.pubkey).unwrap(),
-        SecretKey::from_hex(&params.psk).unwrap(),
-    );
+    let sk = secret_key(&params.psk)?;
+    let pubkey = sk.public_key::<E>();
+
+    // pubkey is optional, when given it must be the psk's pubkey.
+    if let Some(hex_pubkey) = &params.pubkey {
+        if PublicKey::from_hex(hex_pubkey) != Ok(pubkey.clone()) {
+            return Err(Error::from_str(
+                StatusCode::BadRequest,
+                "the pubkey is not match psk",
+            ));
+        }
+    }
 
     let read_storage = req.state().read().await;
 
     let account = read_storage.next_user();
+    if account >= read_storage.cell_upks.account_size {
+        return Err(Error::from_str(
+            StatusCode::BadRequest,
+            "the accounts are full",
+        ));
+    }
     let tx = read_storage.new_register(account, pubkey, &sk);
 
     drop(read_storage);
@@ -150,6 +351,7 @@ async fn register<E: PairingEngine>(
     let mut write_storage = req.state().write().await;
 
     if write_storage.try_insert_tx(tx) {
+        persist_pools(&write_storage);
         drop(write_storage);
         Ok(tx_id)
     } else {
@@ -158,16 +360,30 @@ async fn register<E: PairingEngine>(
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct DepositRequest {
+    pub to: String,
+    #[serde(default)]
+    pub token: Option<String>,
+    pub amount: String,
+    pub psk: String,
+}
+
 /// wallet deposit api. build tx and send to ckb.
 async fn deposit<E: PairingEngine>(
     mut req: Request<Arc<RwLock<Storage<E>>>>,
 ) -> Result<String, Error> {
-    let params: Value = req.body_json().await?;
-
-    let from: u32 = params["to"].as_str().unwrap().parse().unwrap();
-    let amount: u128 = params["amount"].as_str().unwrap().parse().unwrap();
-    let sk = SecretKey::from_hex(params["psk"].as_str().unwrap()).unwrap();
-    println!("[deposit] from={}, amount={}", from, amount);
+    let params: DepositRequest = req.body_json().await?;
+    let (from, token, amount, sk) = (
+        parse(&params.to, "to")?,
+        parse_or(params.token, "token", 0u32)?,
+        parse(&params.amount, "amount")?,
+        secret_key(&params.psk)?,
+    );
+    println!(
+        "[deposit] from={}, token={}, amount={}",
+        from, token, amount
+    );
 
     let read_storage = req.state().read().await;
 
@@ -178,80 +394,94 @@ async fn deposit<E: PairingEngine>(
         ));
     }
 
-    let tx = read_storage.new_deposit(from, amount, &sk);
+    if token as usize >= TOKEN_SIZE {
+        return Err(Error::from_str(
+            StatusCode::BadRequest,
+            "the token is invalid",
+        ));
+    }
 
-    drop(read_storage);
+    if amount > read_storage.my_udt_amounts[token as usize] {
+        return Err(Error::from_str(
+            StatusCode::BadRequest,
+            "the wallet's UDT is not enough",
+        ));
+    }
 
-    let mut write_storage = req.state().write().await;
+    let tx = read_storage.new_deposit(from, token, amount, &sk);
 
-    if let Some(block) = write_storage.build_block_by_user(tx) {
-        let res = block.verify(&write_storage.cell_upks);
-        //.expect("DEPOSIT BLOCK VERIFY FAILURE");
-
-        println!("=========BLOCK DEPOSIT: {:?}", res);
-
-        let rollup_hash: &String = &write_storage.rollup_lock;
-        let rollup_dep_hash: &String = &write_storage.rollup_dep;
-        let success_hash: &String = &write_storage.udt_lock;
-        let my_udt_hash: &String = &write_storage.my_udt;
-        let pre_commit_hash: &String = &write_storage.commit_cell;
-        let pre_upk_hash: &String = &write_storage.upk_cell;
-        let pre_udt_hash: &String = &write_storage.udt_cell;
-        let block: Vec<u8> = block.to_bytes();
-        let udt_amount: u128 = write_storage.total_udt_amount + amount;
-        let my_udt_amount: u128 = write_storage.my_udt_amount - amount;
-
-        let cell_upks = write_storage.cell_upks.to_bytes();
-
-        if let Ok((new_commit_cell, new_upk_cell, new_udt_cell, new_my_udt, tx_id)) = send_deposit(
-            rollup_hash,
-            rollup_dep_hash,
-            success_hash,
-            my_udt_hash,
-            pre_commit_hash,
-            pre_upk_hash,
-            pre_udt_hash,
-            block,
-            cell_upks,
-            udt_amount,
-            my_udt_amount,
-        )
-        .await
-        {
-            write_storage.commit_cell = new_commit_cell;
-            write_storage.upk_cell = new_upk_cell;
-            write_storage.udt_cell = new_udt_cell;
-            write_storage.my_udt = new_my_udt;
-            write_storage.my_udt_amount -= amount;
+    drop(read_storage);
 
-            drop(write_storage);
-            Ok(tx_id)
-        } else {
-            drop(write_storage);
-            Ok("Send Tx Failure".to_owned())
-        }
-    } else {
-        drop(write_storage);
-        Ok("Invalid Tx".to_owned())
-    }
+    let sending = req.state().read().await.l1_sending.clone();
+    let _sending = sending.lock().await;
+    let read_storage = req.state().read().await;
+    let block = read_storage.build_block_by_user(tx).map_err(block_error)?;
+    block.verify(&read_storage.cell_upks).map_err(block_error)?;
+
+    let l1_tx = read_storage
+        .tx_builder()
+        .and_then(|builder| {
+            builder.deposit(
+                &read_storage.live_commit()?,
+                &block,
+                &read_storage.vault(token as usize)?,
+                &read_storage.wallet(token as usize)?,
+                &decode(&read_storage.udt_lock)?,
+                amount,
+            )
+        })
+        .map_err(l1_error)?;
+    let sender = read_storage.l1_sender().map_err(l1_error)?;
+    drop(read_storage);
+    let (out_points, tx_id) = sender.send(l1_tx).await.map_err(l1_error)?;
+
+    let mut write_storage = req.state().write().await;
+    write_storage.commit_cell = out_points[0].clone();
+    write_storage.udt_cells[token as usize] = out_points[1].clone();
+    write_storage.my_udts[token as usize] = out_points[2].clone();
+    write_storage.my_udt_amounts[token as usize] -= amount;
+    persist(&write_storage);
+
+    drop(write_storage);
+    Ok(tx_id)
 }
 
 #[derive(Serialize, Deserialize)]
 struct WithdrawRequest {
     pub from: String,
+    #[serde(default)]
+    pub token: Option<String>,
     pub amount: String,
     pub psk: String,
+    /// the recipient's lock script, hex of the molecule bytes,
+    /// default is the UDT lock of the mock chain's wallet.
+    #[serde(default)]
+    pub lock: Option<String>,
 }
 
 /// wallet withdraw api. build tx and send to ckb.
 async fn withdraw<E: PairingEngine>(
+    req: Request<Arc<RwLock<Storage<E>>>>,
+) -> Result<String, Error> {
+    withdraw_by(req, OP_WITHDRAW).await
+}
+
+/// user escape api when L2 service is offline, same as withdraw but wait
+/// `escape_blocks` L1 blocks. run by user's own node which follows L1.
+async fn escape<E: PairingEngine>(req: Request<Arc<RwLock<Storage<E>>>>) -> Result<String, Error> {
+    withdraw_by(req, OP_ESCAPE).await
+}
+
+async fn withdraw_by<E: PairingEngine>(
     mut req: Request<Arc<RwLock<Storage<E>>>>,
+    op: u8,
 ) -> Result<String, Error> {
     let params: WithdrawRequest = req.body_json().await?;
-    let (from, amount, sk) = (
-        params.from.parse().unwrap(),
-        params.amount.parse().unwrap(),
-        SecretKey::from_hex(&params.psk).unwrap(),
+    let (from, token, amount, sk) = (
+        parse(&params.from, "from")?,
+        parse_or(params.token, "token", 0u32)?,
+        parse(&params.amount, "amount")?,
+        secret_key(&params.psk)?,
     );
 
     let read_storage = req.state().read().await;
@@ -263,7 +493,21 @@ async fn withdraw<E: PairingEngine>(
         ));
     }
 
-    let balance = read_storage.pool_balance(from);
+    if token as usize >= TOKEN_SIZE {
+        return Err(Error::from_str(
+            StatusCode::BadRequest,
+            "the token is invalid",
+        ));
+    }
+
+    // the lock is signed in the withdraw, the contract pays only to it.
+    // a follower node has no UDT lock, the recipient must give one.
+    let lock: Script = decode(params.lock.as_ref().unwrap_or(&read_storage.udt_lock))
+        .map_err(|_| Error::from_str(StatusCode::BadRequest, "the lock is invalid"))?;
+    let mut lock_hash = [0u8; 32];
+    lock_hash.copy_from_slice(lock.calc_script_hash().as_slice());
+
+    let balance = read_storage.pool_balance(from, token);
 
     if amount > balance {
         return Err(Error::from_str(
@@ -272,63 +516,56 @@ async fn withdraw<E: PairingEngine>(
         ));
     }
 
-    let tx = read_storage.new_withdraw(from, amount, &sk);
+    let tx = read_storage.new_withdraw(from, token, amount, lock_hash, &sk);
     drop(read_storage);
 
-    let mut write_storage = req.state().write().await;
-
-    if let Some(block) = write_storage.build_block_by_user(tx) {
-        block
-            .verify(&write_storage.cell_upks)
-            .expect("WITHDRAW BLOCK VERIFY FAILURE");
-
-        let rollup_hash: &String = &write_storage.rollup_lock;
-        let rollup_dep_hash: &String = &write_storage.rollup_dep;
-        let success_hash: &String = &write_storage.udt_lock;
-        let pre_commit_hash: &String = &write_storage.commit_cell;
-        let pre_upk_hash: &String = &write_storage.upk_cell;
-        let pre_udt_hash: &String = &write_storage.udt_cell;
-        let block: Vec<u8> = block.to_bytes();
-        let udt_amount: u128 = write_storage.total_udt_amount - amount;
-        let my_udt_amount: u128 = amount;
-
-        let cell_upks = write_storage.cell_upks.to_bytes();
-
-        if let Ok((new_commit_cell, new_upk_cell, new_udt_cell, tx_id)) = send_withdraw(
-            rollup_hash,
-            rollup_dep_hash,
-            success_hash,
-            pre_commit_hash,
-            pre_upk_hash,
-            pre_udt_hash,
-            block,
-            cell_upks,
-            udt_amount,
-            my_udt_amount,
-        )
-        .await
-        {
-            write_storage.commit_cell = new_commit_cell;
-            write_storage.upk_cell = new_upk_cell;
-            write_storage.udt_cell = new_udt_cell;
+    let sending = req.state().read().await.l1_sending.clone();
+    let _sending = sending.lock().await;
+    let read_storage = req.state().read().await;
+    let block = read_storage.build_block_by_user(tx).map_err(block_error)?;
+    block.verify(&read_storage.cell_upks).map_err(block_error)?;
 
-            drop(write_storage);
-            Ok(tx_id)
-        } else {
-            drop(write_storage);
-            Ok("Send Tx Failure".to_owned())
-        }
+    let escape = if op == OP_ESCAPE {
+        Some(escape_since(read_storage.escape_blocks))
     } else {
-        drop(write_storage);
-        Ok("Invalid Tx".to_owned())
-    }
+        None
+    };
+    let l1_tx = read_storage
+        .tx_builder()
+        .and_then(|builder| {
+            builder.withdraw(
+                &read_storage.live_commit()?,
+                &block,
+                &read_storage.vault(token as usize)?,
+                &lock,
+                amount,
+                escape,
+            )
+        })
+        .map_err(l1_error)?;
+    let sender = read_storage.l1_sender().map_err(l1_error)?;
+    drop(read_storage);
+    let (out_points, tx_id) = sender.send(l1_tx).await.map_err(l1_error)?;
+
+    let mut write_storage = req.state().write().await;
+    write_storage.commit_cell = out_points[0].clone();
+    write_storage.udt_cells[token as usize] = out_points[1].clone();
+    persist(&write_storage);
+
+    drop(write_storage);
+    Ok(tx_id)
 }
 
 #[derive(Serialize, Deserialize)]
 struct TransferRequest {
     pub from: String,
     pub to: String,
+    #[serde(default)]
+    pub token: Option<String>,
     pub amount: String,
+    /// paid to the operator, higher fee is packed first.
+    #[serde(default)]
+    pub fee: Option<String>,
     pub psk: String,
 }
 
@@ -337,11 +574,13 @@ async fn transfer<E: PairingEngine>(
     mut req: Request<Arc<RwLock<Storage<E>>>>,
 ) -> Result<String, Error> {
     let params: TransferRequest = req.body_json().await?;
-    let (from, to, amount, sk) = (
-        params.from.parse().unwrap(),
-        params.to.parse().unwrap(),
-        params.amount.parse().unwrap(),
-        SecretKey::from_hex(&params.psk).unwrap(),
+    let (from, to, token, amount, fee, sk) = (
+        parse(&params.from, "from")?,
+        parse(&params.to, "to")?,
+        parse_or(params.token, "token", 0u32)?,
+        parse(&params.amount, "amount")?,
+        parse_or(params.fee, "fee", 0u128)?,
+        secret_key(&params.psk)?,
     );
 
     let read_storage = req.state().read().await;
@@ -353,27 +592,45 @@ async fn transfer<E: PairingEngine>(
         ));
     }
 
-    let balance = read_storage.pool_balance(from);
+    if token as usize >= TOKEN_SIZE {
+        return Err(Error::from_str(
+            StatusCode::BadRequest,
+            "the token is invalid",
+        ));
+    }
+
+    let balance = read_storage.pool_balance(from, token);
 
     println!(
-        "transfer balance: from balance: {}, from: {}, to: {}, amount {}",
-        balance, from, to, amount
+        "transfer balance: from balance: {}, from: {}, to: {}, token: {}, amount {}, fee {}",
+        balance, from, to, token, amount, fee
     );
 
-    if amount > balance {
+    let pay = match amount.checked_add(fee) {
+        Some(pay) => pay,
+        None => {
+            return Err(Error::from_str(
+                StatusCode::BadRequest,
+                "the amount and fee overflow",
+            ))
+        }
+    };
+
+    if pay > balance {
         return Err(Error::from_str(
             StatusCode::BadRequest,
             "the user balance not enough",
         ));
     }
 
-    let tx = read_storage.new_transfer(from, to, amount, &sk);
+    let tx = read_storage.new_transfer(from, to, token, amount, fee, &sk);
 
     let tx_hash_id = tx.id();
     drop(read_storage);
 
     let mut write_storage = req.state().write().await;
     if write_storage.try_insert_tx(tx) {
+        persist_pools(&write_storage);
         drop(write_storage);
         Ok(tx_hash_id)
     } else {
@@ -385,56 +642,295 @@ async fn transfer<E: PairingEngine>(
 /// wallet transfer api. build tx and send to ckb.
 async fn setup<E: PairingEngine>(req: Request<Arc<RwLock<Storage<E>>>>) -> Result<String, Error> {
     //let from_fpk = req.state().read().await.user_fpk(from);
-    let (rollup_lock, rollup_dep, udt_lock, my_udt) =
-        deploy_contract("asvc_verifier").await.unwrap();
+    let ckb = req.state().read().await.ckb.clone();
+    let Deployment {
+        rollup_lock,
+        rollup_dep,
+        udt_lock,
+        my_udts,
+        commit_type,
+        commit_type_dep,
+        seed_cell,
+        udt_types,
+    } = ckb
+        .deploy_contract("asvc_verifier", "asvc_commit_type")
+        .await
+        .map_err(l1_error)?;
 
     println!("ASVC rollup lock: {}", rollup_lock);
     println!("ASVC rollup lock dep: {}", rollup_dep);
     println!("ASVC udt lock: {}", udt_lock);
-    println!("ASVC my udt ouput: {}", my_udt);
+    println!("ASVC my udt ouputs: {:?}", my_udts);
+    println!("ASVC udt types: {:?}", udt_types);
 
+    let sending = req.state().read().await.l1_sending.clone();
+    let _sending = sending.lock().await;
     let mut storage = req.state().write().await;
 
-    storage.rollup_lock = rollup_lock.clone();
     storage.rollup_dep = rollup_dep.clone();
+    storage.commit_type_dep = commit_type_dep.clone();
     storage.udt_lock = udt_lock;
-    storage.my_udt = my_udt;
-    storage.my_udt_amount = 100000;
-
-    let block = Block {
-        block_height: 0,
-        commit: storage.commit.clone(),
-        proof: storage.proofs[0].clone(),
-        new_commit: storage.commit.clone(),
-        txs: vec![],
+    storage.my_udts = my_udts;
+    storage.my_udt_amounts = vec![100000; TOKEN_SIZE];
+    // the vault of token i has the UDT type i.
+    storage.cell_upks.tokens = udt_types
+        .iter()
+        .map(|udt_type| script_hash(udt_type))
+        .collect::<Result<_, _>>()
+        .map_err(l1_error)?;
+    storage.udt_types = udt_types;
+
+    // send init state to chain.
+    let init = || -> Result<_, CkbRpcError> {
+        let builder = RollupTxBuilder::new(
+            decode(&rollup_lock)?,
+            decode(&rollup_dep)?,
+            decode(&commit_type)?,
+            decode(&commit_type_dep)?,
+            // the upks cell is created by the init tx.
+            OutPoint::default(),
+            storage.cell_upks.account_size,
+        );
+        let udt_types = storage
+            .udt_types
+            .iter()
+            .map(|udt_type| decode(udt_type))
+            .collect::<Result<Vec<_>, _>>()?;
+        builder.init(
+            decode(&seed_cell)?,
+            &udt_types,
+            &storage.cell_commit(),
+            &storage.cell_upks,
+            storage.escape_blocks,
+        )
     };
+    let (builder, l1_tx) = init().map_err(l1_error)?;
+    let sender = storage.l1_sender().map_err(l1_error)?;
+    drop(storage);
+    let (mut out_points, tx_id) = sender.send(l1_tx).await.map_err(l1_error)?;
 
-    let cell_upks = storage.cell_upks.to_bytes();
+    let mut storage = req.state().write().await;
 
-    // send init state to chain.
-    if let Ok((commit_cell, upk_cell, udt_cell, tx_id)) =
-        init_state(rollup_lock, rollup_dep, block.to_bytes(), cell_upks).await
-    {
-        storage.commit_cell = commit_cell;
-        storage.upk_cell = upk_cell;
-        storage.udt_cell = udt_cell;
+    // the rollup lock's args is the upks' data hash.
+    let pinned_lock = hex::encode(builder.rollup_lock.as_slice());
+    println!("ASVC pinned rollup lock: {}", pinned_lock);
+    storage.rollup_lock = pinned_lock;
+    let commit_type = hex::encode(builder.commit_type.as_slice());
+    println!("ASVC commit type: {}", commit_type);
+    storage.commit_type = commit_type;
+    // init tx: outputs are commit, vaults..., upks, change.
+    out_points.pop();
+    storage.commit_cell = out_points.remove(0);
+    storage.upk_cell = out_points.pop().unwrap_or_default();
+    storage.udt_cells = out_points;
+    persist(&storage);
+
+    Ok(tx_id)
+}
 
-        Ok(tx_id)
-    } else {
-        Ok("Init Failure".to_owned())
-    }
+/// the request's number, 400 when it is not a number.
+fn parse<T: FromStr>(value: &str, field: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| Error::from_str(StatusCode::BadRequest, format!("the {} is invalid", field)))
+}
+
+/// the request's optional number, the default when it is not given.
+fn parse_or<T: FromStr>(value: Option<String>, field: &str, default: T) -> Result<T, Error> {
+    value.map_or(Ok(default), |value| parse(&value, field))
+}
+
+fn secret_key(psk: &str) -> Result<SecretKey, Error> {
+    SecretKey::from_hex(psk)
+        .map_err(|_| Error::from_str(StatusCode::BadRequest, "the psk is invalid"))
+}
+
+/// the node can not build or verify its block, it is not sent to L1.
+fn block_error(error: String) -> Error {
+    println!("block failure: {}", error);
+    Error::from_str(StatusCode::InternalServerError, error)
+}
+
+/// the L1 failure for the API clients, the rollup state is not changed.
+fn l1_error(error: CkbRpcError) -> Error {
+    let status = match error {
+        CkbRpcError::Transport(_) => StatusCode::ServiceUnavailable,
+        CkbRpcError::Rpc { .. } | CkbRpcError::Malformed(_) => StatusCode::BadGateway,
+        CkbRpcError::Decode(_) => StatusCode::InternalServerError,
+        // the rollup's cells are spent by others, the node is not synced.
+        CkbRpcError::CellNotFound(_) => StatusCode::Conflict,
+        CkbRpcError::Rejected(_) => StatusCode::UnprocessableEntity,
+        // the operator's funding cells can not pay the tx.
+        CkbRpcError::InsufficientCapacity { .. } => StatusCode::ServiceUnavailable,
+        // the node's block is not on its commit cell.
+        CkbRpcError::InvalidBlock(_) => StatusCode::InternalServerError,
+        // the L1 UDT cells are not the node's balances, the node is not synced.
+        CkbRpcError::InsufficientAmount { .. } => StatusCode::Conflict,
+        CkbRpcError::Overflow(_) => StatusCode::UnprocessableEntity,
+        CkbRpcError::Sign(_) => StatusCode::InternalServerError,
+    };
+    println!("L1 failure: {}", error);
+    Error::from_str(status, error.to_string())
 }
 
 fn main() {
-    let (params, commit, proofs, full_pubkeys) = match initialize_asvc::<Bn_256>(ACCOUNT_SIZE) {
-        Ok(result) => result,
-        Err(error) => panic!("Problem initializing asvc: {:?}", error),
+    let args: Vec<String> = std::env::args().collect();
+
+    // --accounts N: max accounts of the deployment, must be a power of two.
+    let account_size = match args.iter().position(|arg| arg == "--accounts") {
+        Some(i) => args
+            .get(i + 1)
+            .and_then(|n| n.parse().ok())
+            .expect("--accounts needs a number"),
+        None => DEFAULT_ACCOUNT_SIZE,
+    };
+    // --shard-accounts N: accounts of a shard, a power of two divides the accounts,
+    // default is one shard of all accounts.
+    let shard_accounts = match args.iter().position(|arg| arg == "--shard-accounts") {
+        Some(i) => args
+            .get(i + 1)
+            .and_then(|n| n.parse().ok())
+            .expect("--shard-accounts needs a number"),
+        None => account_size,
     };
 
-    // mock storage
-    let storage = Storage::<Bn_256>::init(params, commit, proofs, full_pubkeys);
+    // --db PATH: persist the state in the directory, and restore it when restart.
+    let db: Box<dyn Store> = match args.iter().position(|arg| arg == "--db") {
+        Some(i) => Box::new(
+            SledStore::open(args.get(i + 1).expect("--db needs a path")).expect("OPEN DB FAILURE"),
+        ),
+        None => Box::new(MemoryStore::default()),
+    };
+
+    // --node URL: the CKB node's RPC, default is the local mock chain.
+    let url = match args.iter().position(|arg| arg == "--node") {
+        Some(i) => args.get(i + 1).expect("--node needs an url").as_str(),
+        None => DEFAULT_NODE_URL,
+    };
+    // --ckb: the node is a CKB node with the standard JSON-RPC, not the mock chain.
+    let mut ckb = if args.iter().any(|arg| arg == "--ckb") {
+        CkbClient::ckb(url)
+    } else {
+        CkbClient::new(url)
+    };
+    // --indexer URL: the indexer's RPC, default is the node's.
+    if let Some(i) = args.iter().position(|arg| arg == "--indexer") {
+        ckb.indexer_url = args.get(i + 1).expect("--indexer needs an url").clone();
+    }
+    // --fee-rate N: shannons per 1000 bytes of the L1 txs.
+    if let Some(i) = args.iter().position(|arg| arg == "--fee-rate") {
+        ckb.fee_rate = args
+            .get(i + 1)
+            .and_then(|n| n.parse().ok())
+            .expect("--fee-rate needs a number");
+    }
+    // --funding-key KEY --funding-dep DEP: the secp256k1 key of the funding lock and
+    // the lock's cell dep, they sign the funding inputs. a CKB node needs them.
+    if let Some(i) = args.iter().position(|arg| arg == "--funding-key") {
+        let key = args.get(i + 1).expect("--funding-key needs a key");
+        let dep = args
+            .iter()
+            .position(|arg| arg == "--funding-dep")
+            .and_then(|i| args.get(i + 1))
+            .expect("--funding-key needs --funding-dep");
+        ckb.signer = match FundingSigner::from_hex(key, dep) {
+            Ok(signer) => Some(signer),
+            Err(error) => panic!("Problem reading the funding key: {}", error),
+        };
+    }
+    if ckb.dialect == Dialect::Ckb && ckb.signer.is_none() {
+        panic!("--ckb needs --funding-key and --funding-dep to sign the L1 txs");
+    }
+
+    let persisted = Storage::<Bn_256>::persisted(&*db).expect("READ DB FAILURE");
+    let mut storage = if persisted {
+        let storage = match Storage::<Bn_256>::load(db) {
+            Ok(storage) => storage,
+            Err(error) => panic!("Problem restoring storage: {}", error),
+        };
+        println!("restore storage at block: {}", storage.block_height);
+        storage
+    } else if let Some(i) = args.iter().position(|arg| arg == "--sync") {
+        // --sync COMMIT_TYPE: rebuild the state from L1, then serve.
+        let commit_type = args.get(i + 1).expect("--sync needs the commit type");
+        match task::block_on(sync_genesis::<Bn_256>(&ckb, commit_type, db)) {
+            Ok(storage) => storage,
+            Err(error) => panic!("Problem syncing genesis: {}", error),
+        }
+    } else {
+        // a new db, the params & escape blocks are only set here.
+        // --params PATH: load the aSVC params from the file, or generate and save to it.
+        let params_path = args
+            .iter()
+            .position(|arg| arg == "--params")
+            .map(|i| args.get(i + 1).expect("--params needs a path"));
+
+        let mut storage = match params_path {
+            Some(path) if Path::new(path).exists() => {
+                let (cell_upks, commits, proofs) = match load_params::<Bn_256>(path) {
+                    Ok(result) => result,
+                    Err(error) => panic!("Problem loading params: {}", error),
+                };
+                Storage::<Bn_256>::from_params(cell_upks, commits, proofs, db)
+            }
+            _ => {
+                let (params, commits, proofs, full_pubkeys) =
+                    match initialize_asvc::<Bn_256>(account_size, shard_accounts) {
+                        Ok(result) => result,
+                        Err(error) => panic!("Problem initializing asvc: {:?}", error),
+                    };
+
+                let storage = Storage::<Bn_256>::init(params, commits, proofs, full_pubkeys, db);
+                if let Some(path) = params_path {
+                    save_params(path, &storage.cell_upks, &storage.commits, &storage.proofs)
+                        .expect("SAVE PARAMS FAILURE");
+                }
+                storage
+            }
+        };
+
+        // --escape-blocks N: users can escape after N L1 blocks without commit update.
+        if let Some(i) = args.iter().position(|arg| arg == "--escape-blocks") {
+            storage.escape_blocks = args
+                .get(i + 1)
+                .and_then(|n| n.parse().ok())
+                .expect("--escape-blocks needs a number");
+        }
+        storage
+    };
+
+    storage.ckb = ckb;
+    // --aggregate-signs: miner block use one BLS aggregate sign,
+    // saved with the state, a restored node keeps its mode.
+    if !persisted {
+        storage.aggregate_signs = args.iter().any(|arg| arg == "--aggregate-signs");
+    }
+    // --fee-recipient N: the operator's account which receives the transfers' fees.
+    if let Some(i) = args.iter().position(|arg| arg == "--fee-recipient") {
+        storage.fee_recipient = args
+            .get(i + 1)
+            .and_then(|n| n.parse().ok())
+            .expect("--fee-recipient needs an account");
+    }
+    // --confirmations N: L1 reorgs in the last N L1 blocks are rolled back.
+    if let Some(i) = args.iter().position(|arg| arg == "--confirmations") {
+        storage.confirmations = args
+            .get(i + 1)
+            .and_then(|n| n.parse().ok())
+            .expect("--confirmations needs a number");
+    }
+    // --funding-lock LOCK: the hex of the lock script whose CKB cells fund the L1 txs.
+    if let Some(i) = args.iter().position(|arg| arg == "--funding-lock") {
+        storage.funding_lock = args
+            .get(i + 1)
+            .expect("--funding-lock needs a lock script")
+            .clone();
+    }
     let s = Arc::new(RwLock::new(storage));
 
+    // catch up the L2 blocks on L1 before serving.
+    task::block_on(sync_blocks(&s));
+
     // Running Tasks.
     task::spawn(listen_contracts(s.clone()));
     task::spawn(miner(s.clone()));
@@ -448,6 +944,7 @@ fn main() {
     app.at("/deposit").post(deposit);
     app.at("/withdraw").post(withdraw);
     app.at("/transfer").post(transfer);
+    app.at("/escape").post(escape);
 
     // L2 service
     app.at("/setup").post(setup);
@@ -1,31 +1,46 @@
-use ckb_zkp::math::{fft::EvaluationDomain, PairingEngine};
+use async_std::sync::{Arc, Mutex};
+use ckb_zkp::math::{fft::EvaluationDomain, FromBytes, PairingEngine, ToBytes};
 use ckb_zkp::scheme::asvc::{
     aggregate_proofs, update_commit, Commitment, Parameters, Proof, UpdateKey,
 };
 use ckb_zkp::scheme::r1cs::SynthesisError;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use asvc_rollup::block::{Block, CellUpks};
+use asvc_rollup::block::{Block, BlockShard, CellCommit, CellUpks};
 use asvc_rollup::transaction::{
-    FullPubKey, PublicKey, SecretKey, Transaction, TxHash, TxType, ACCOUNT_SIZE,
+    position, u128_to_fr, FullPubKey, PublicKey, SecretKey, Transaction, TxHash, TxType, TOKEN_SIZE,
 };
+use ckb_rpc::{decode, CkbClient, CkbRpcError, CommitCell, RollupTxBuilder, UdtCell};
+use ckb_tool::ckb_types::{core::TransactionView, packed::Script};
 use indexmap::IndexMap;
 
-use super::asvc::update_proofs;
+use super::asvc::{update_proofs, zero_proofs};
+use super::db::{read_bytes, read_strings, read_vec, write_bytes, write_strings, write_vec, Store};
+
+/// default L1 blocks to wait before users can escape.
+const DEFAULT_ESCAPE_BLOCKS: u64 = 100;
+
+/// default operator account, receives the transfers' fees.
+const DEFAULT_FEE_RECIPIENT: u32 = 0;
+
+/// default L1 blocks before a handled L1 block is final.
+const DEFAULT_CONFIRMATIONS: u64 = 24;
 
 pub struct Storage<E: PairingEngine> {
     pub block_height: u32,
     pub tmp_block_height: u32,
     pub blocks: Vec<Block<E>>,
     pub pools: IndexMap<TxHash, Transaction<E>>,
+    /// the txs of the block sending to L1, with their signs when aggregated.
+    pub pending: Vec<Transaction<E>>,
 
     /// const params
     pub omega: E::Fr,
-    pub params: Parameters<E>,
     pub cell_upks: CellUpks<E>,
 
-    /// all accounts current proof.
-    pub commit: Commitment<E>,
+    /// every shard's commitment, indexed by shard.
+    pub commits: Vec<Commitment<E>>,
+    /// all accounts' token slots current proof in its shard, indexed by position.
     pub proofs: Vec<Proof<E>>,
 
     pub full_pubkeys: Vec<FullPubKey<E>>,
@@ -33,31 +48,67 @@ pub struct Storage<E: PairingEngine> {
     pub next_user: u32,
     pub tmp_next_user: u32,
 
+    /// balances & nonces are indexed by position(account, token).
     pub balances: Vec<u128>,
     pub tmp_balances: Vec<u128>,
 
     pub nonces: Vec<u32>,
     pub tmp_nonces: Vec<u32>,
 
+    /// use one BLS aggregate sign for the miner's block.
+    pub aggregate_signs: bool,
+    /// L1 blocks without commit update, after that users can escape.
+    pub escape_blocks: u64,
+    /// the L1 height the listener has handled.
+    pub l1_block_height: u64,
+    /// L1 blocks before a handled L1 block is final, a deeper reorg can not roll back.
+    pub confirmations: u64,
+    /// the hashes of the handled L1 blocks not final, by L1 height.
+    pub l1_hashes: BTreeMap<u64, String>,
+    /// the undo records of the L2 blocks not final, the last is the newest block.
+    pub undos: Vec<BlockUndo<E>>,
+    /// the operator's account, receives the transfers' fees of the miner's blocks.
+    pub fee_recipient: u32,
+    /// the L1 node's client.
+    pub ckb: CkbClient,
+    /// the operator's lock of the CKB cells funding the L1 txs, the deployer's when empty.
+    pub funding_lock: String,
+    /// held from building a block to handling it after sent to L1, so the blocks are
+    /// sent one by one without the storage locked, and the txs still go to the pool.
+    pub l1_sending: Arc<Mutex<()>>,
+
     pub rollup_lock: String,
     pub rollup_dep: String,
+    pub commit_type: String,
+    pub commit_type_dep: String,
     pub udt_lock: String, // use in withdraw
-    pub my_udt: String,   // use in depost
     pub commit_cell: String,
-    pub upk_cell: String,
-    pub udt_cell: String,
-    pub my_udt_amount: u128,
-    pub total_udt_amount: u128,
+    pub upk_cell: String, // cell_dep, never spent
+
+    /// vaults & UDTs are indexed by token.
+    pub udt_types: Vec<String>,
+    pub my_udts: Vec<String>, // use in depost
+    pub udt_cells: Vec<String>,
+    pub my_udt_amounts: Vec<u128>,
+    pub total_udt_amounts: Vec<u128>,
+
+    /// the state is persisted here, and restored when the node restarts.
+    db: Box<dyn Store>,
 }
 
 impl<E: PairingEngine> Storage<E> {
     pub fn init(
         params: Parameters<E>,
-        commit: Commitment<E>,
+        commits: Vec<Commitment<E>>,
         proofs: Vec<Proof<E>>,
         full_pubkeys: Vec<FullPubKey<E>>,
+        db: Box<dyn Store>,
     ) -> Self {
-        let domain = EvaluationDomain::<E::Fr>::new(ACCOUNT_SIZE)
+        // every account has TOKEN_SIZE slots in the vector,
+        // the shards share the update keys of one shard's vector.
+        let n = proofs.len();
+        let shard_n = params.proving_key.update_keys.len();
+        let domain = EvaluationDomain::<E::Fr>::new(shard_n)
             .ok_or(SynthesisError::PolynomialDegreeTooLarge)
             .unwrap();
 
@@ -65,41 +116,274 @@ impl<E: PairingEngine> Storage<E> {
         let cell_upks = CellUpks {
             vk: params.verification_key.clone(),
             omega: omega,
+            account_size: (n / TOKEN_SIZE) as u32,
+            shard_accounts: (shard_n / TOKEN_SIZE) as u32,
             upks: params.proving_key.update_keys.clone(),
+            // set when the UDTs are deployed.
+            tokens: vec![[0u8; 32]; TOKEN_SIZE],
         };
 
+        Self::new(cell_upks, commits, proofs, full_pubkeys, db)
+    }
+
+    /// the genesis state of a rollup on L1, the vector is all zero,
+    /// then the L2 blocks on L1 are handled from the genesis.
+    pub fn genesis(
+        cell_upks: CellUpks<E>,
+        commits: Vec<Commitment<E>>,
+        db: Box<dyn Store>,
+    ) -> Self {
+        let proofs = zero_proofs::<E>(cell_upks.vector_size());
+        Self::from_params(cell_upks, commits, proofs, db)
+    }
+
+    /// the initial state from the params file, or the genesis.
+    pub fn from_params(
+        cell_upks: CellUpks<E>,
+        commits: Vec<Commitment<E>>,
+        proofs: Vec<Proof<E>>,
+        db: Box<dyn Store>,
+    ) -> Self {
+        let shard_accounts = cell_upks.shard_accounts;
+        let full_pubkeys = (0..cell_upks.account_size)
+            .map(|i| {
+                let upk = &cell_upks.upks[position(i % shard_accounts, 0) as usize];
+                FullPubKey::default(i, upk.clone())
+            })
+            .collect();
+
+        Self::new(cell_upks, commits, proofs, full_pubkeys, db)
+    }
+
+    fn new(
+        cell_upks: CellUpks<E>,
+        commits: Vec<Commitment<E>>,
+        proofs: Vec<Proof<E>>,
+        full_pubkeys: Vec<FullPubKey<E>>,
+        db: Box<dyn Store>,
+    ) -> Self {
+        let n = proofs.len();
+
         Self {
             block_height: 0,
             tmp_block_height: 0,
-            omega: omega,
+            omega: cell_upks.omega,
             cell_upks: cell_upks,
             blocks: vec![],
             pools: IndexMap::new(),
+            pending: vec![],
             proofs: proofs,
-            params: params,
-            commit: commit,
+            commits: commits,
             next_user: 0u32,
             tmp_next_user: 0u32,
-            balances: vec![0u128; ACCOUNT_SIZE],
-            tmp_balances: vec![0u128; ACCOUNT_SIZE],
-            nonces: vec![0u32; ACCOUNT_SIZE],
-            tmp_nonces: vec![0u32; ACCOUNT_SIZE],
+            balances: vec![0u128; n],
+            tmp_balances: vec![0u128; n],
+            nonces: vec![0u32; n],
+            tmp_nonces: vec![0u32; n],
             full_pubkeys: full_pubkeys,
+            aggregate_signs: false,
+            escape_blocks: DEFAULT_ESCAPE_BLOCKS,
+            l1_block_height: 0,
+            confirmations: DEFAULT_CONFIRMATIONS,
+            l1_hashes: BTreeMap::new(),
+            undos: vec![],
+            fee_recipient: DEFAULT_FEE_RECIPIENT,
+            ckb: CkbClient::default(),
+            funding_lock: String::new(),
+            l1_sending: Arc::new(Mutex::new(())),
 
             rollup_lock: String::new(),
             rollup_dep: String::new(),
+            commit_type: String::new(),
+            commit_type_dep: String::new(),
             udt_lock: String::new(),
-            my_udt: String::new(),
             commit_cell: String::new(),
             upk_cell: String::new(),
-            udt_cell: String::new(),
-            my_udt_amount: 0,
-            total_udt_amount: 0,
+            udt_types: vec![String::new(); TOKEN_SIZE],
+            my_udts: vec![String::new(); TOKEN_SIZE],
+            udt_cells: vec![String::new(); TOKEN_SIZE],
+            my_udt_amounts: vec![0; TOKEN_SIZE],
+            total_udt_amounts: vec![0; TOKEN_SIZE],
+            db: db,
         }
     }
 
-    pub fn next_nonce(&self, u: u32) -> u32 {
-        self.tmp_nonces[u as usize]
+    /// the db has a persisted state.
+    pub fn persisted(db: &dyn Store) -> Result<bool, String> {
+        Ok(db.get("state")?.is_some())
+    }
+
+    /// restore the state persisted in the db, the mempool's txs are inserted again.
+    pub fn load(db: Box<dyn Store>) -> Result<Self, String> {
+        let get = |key: &str| -> Result<Vec<u8>, String> {
+            db.get(key)?.ok_or(format!("db missing {}", key))
+        };
+
+        let state = get("state")?;
+        let mut s = &state[..];
+        let block_height = u32::read(&mut s).map_err(|e| e.to_string())?;
+        let next_user = u32::read(&mut s).map_err(|e| e.to_string())?;
+        let escape_blocks = u64::read(&mut s).map_err(|e| e.to_string())?;
+        let l1_block_height = u64::read(&mut s).map_err(|e| e.to_string())?;
+        let aggregate_signs = u8::read(&mut s).map_err(|e| e.to_string())? != 0;
+
+        let cell_upks =
+            CellUpks::from_packed(&get("cell_upks")?).map_err(|_| String::from("db cell_upks"))?;
+        let balances: Vec<u128> = read_vec(&get("balances")?)?;
+        let nonces: Vec<u32> = read_vec(&get("nonces")?)?;
+        let cells = read_strings(&get("cells")?)?;
+        if cells.len() != 7 {
+            return Err(String::from("db cells"));
+        }
+
+        let mut blocks = vec![];
+        for height in 1..=block_height {
+            let block = get(&format!("block/{}", height))?;
+            blocks.push(Block::from_packed(&block).map_err(|_| format!("db block {}", height))?);
+        }
+
+        let commits = read_vec(&get("commits")?)?;
+        let proofs = read_vec(&get("proofs")?)?;
+        let full_pubkeys = read_vec(&get("full_pubkeys")?)?;
+        let udt_types = read_strings(&get("udt_types")?)?;
+        let my_udts = read_strings(&get("my_udts")?)?;
+        let udt_cells = read_strings(&get("udt_cells")?)?;
+        let my_udt_amounts = read_vec(&get("my_udt_amounts")?)?;
+        let total_udt_amounts = read_vec(&get("total_udt_amounts")?)?;
+        let txs: Vec<Transaction<E>> = read_vec(&get("pools")?)?;
+
+        let l1_hashes = read_bytes(&get("l1_hashes")?)?;
+        if l1_hashes.len() != 2 {
+            return Err(String::from("db l1_hashes"));
+        }
+        let heights: Vec<u64> = read_vec(&l1_hashes[0])?;
+        let hashes = read_strings(&l1_hashes[1])?;
+        let l1_hashes = heights.into_iter().zip(hashes).collect();
+        let mut undos = vec![];
+        for undo in read_bytes(&get("undos")?)? {
+            undos.push(BlockUndo::from_bytes(&undo)?);
+        }
+
+        let mut storage = Self {
+            block_height,
+            tmp_block_height: block_height,
+            omega: cell_upks.omega,
+            cell_upks,
+            blocks,
+            pools: IndexMap::new(),
+            pending: vec![],
+            commits,
+            proofs,
+            full_pubkeys,
+            next_user,
+            tmp_next_user: next_user,
+            tmp_balances: balances.clone(),
+            balances,
+            tmp_nonces: nonces.clone(),
+            nonces,
+            aggregate_signs,
+            escape_blocks,
+            l1_block_height,
+            confirmations: DEFAULT_CONFIRMATIONS,
+            l1_hashes,
+            undos,
+            fee_recipient: DEFAULT_FEE_RECIPIENT,
+            ckb: CkbClient::default(),
+            funding_lock: String::new(),
+            l1_sending: Arc::new(Mutex::new(())),
+
+            rollup_lock: cells[0].clone(),
+            rollup_dep: cells[1].clone(),
+            commit_type: cells[2].clone(),
+            commit_type_dep: cells[3].clone(),
+            udt_lock: cells[4].clone(),
+            commit_cell: cells[5].clone(),
+            upk_cell: cells[6].clone(),
+            udt_types,
+            my_udts,
+            udt_cells,
+            my_udt_amounts,
+            total_udt_amounts,
+            db,
+        };
+
+        for tx in txs {
+            storage.try_insert_tx(tx);
+        }
+
+        Ok(storage)
+    }
+
+    /// write the whole committed state and the mempool to the db in one batch,
+    /// so a crash never leaves a half written state.
+    pub fn persist(&self) -> Result<(), String> {
+        let mut state = Vec::new();
+        self.block_height.write(&mut state).unwrap();
+        self.next_user.write(&mut state).unwrap();
+        self.escape_blocks.write(&mut state).unwrap();
+        self.l1_block_height.write(&mut state).unwrap();
+        (self.aggregate_signs as u8).write(&mut state).unwrap();
+
+        let cells = [
+            self.rollup_lock.clone(),
+            self.rollup_dep.clone(),
+            self.commit_type.clone(),
+            self.commit_type_dep.clone(),
+            self.udt_lock.clone(),
+            self.commit_cell.clone(),
+            self.upk_cell.clone(),
+        ];
+
+        let heights: Vec<u64> = self.l1_hashes.keys().cloned().collect();
+        let hashes: Vec<String> = self.l1_hashes.values().cloned().collect();
+        let l1_hashes = [write_vec(&heights), write_strings(&hashes)];
+        let undos: Vec<Vec<u8>> = self.undos.iter().map(|undo| undo.to_bytes()).collect();
+
+        let mut items = vec![
+            (String::from("state"), state),
+            (String::from("cell_upks"), self.cell_upks.to_packed()),
+            (String::from("commits"), write_vec(&self.commits)),
+            (String::from("proofs"), write_vec(&self.proofs)),
+            (String::from("full_pubkeys"), write_vec(&self.full_pubkeys)),
+            (String::from("balances"), write_vec(&self.balances)),
+            (String::from("nonces"), write_vec(&self.nonces)),
+            (String::from("cells"), write_strings(&cells)),
+            (String::from("udt_types"), write_strings(&self.udt_types)),
+            (String::from("my_udts"), write_strings(&self.my_udts)),
+            (String::from("udt_cells"), write_strings(&self.udt_cells)),
+            (
+                String::from("my_udt_amounts"),
+                write_vec(&self.my_udt_amounts),
+            ),
+            (
+                String::from("total_udt_amounts"),
+                write_vec(&self.total_udt_amounts),
+            ),
+            (String::from("pools"), self.pools_bytes()),
+            (String::from("l1_hashes"), write_bytes(&l1_hashes)),
+            (String::from("undos"), write_bytes(&undos)),
+        ];
+        // blocks are never changed, only the last one is new.
+        if let Some(block) = self.blocks.last() {
+            items.push((format!("block/{}", block.block_height), block.to_packed()));
+        }
+
+        self.db.write_batch(items)
+    }
+
+    /// write the mempool only, when a tx is inserted.
+    pub fn persist_pools(&self) -> Result<(), String> {
+        self.db
+            .write_batch(vec![(String::from("pools"), self.pools_bytes())])
+    }
+
+    fn pools_bytes(&self) -> Vec<u8> {
+        let txs: Vec<Transaction<E>> = self.pools.values().cloned().collect();
+        write_vec(&txs)
+    }
+
+    pub fn next_nonce(&self, p: u32) -> u32 {
+        self.tmp_nonces[p as usize]
     }
 
     pub fn next_user(&self) -> u32 {
@@ -119,98 +403,277 @@ impl<E: PairingEngine> Storage<E> {
         self.full_pubkeys[u as usize].clone()
     }
 
-    pub fn user_upk(&self, u: u32) -> &UpdateKey<E> {
-        &self.params.proving_key.update_keys[u as usize]
+    /// the update key of the position, in its shard.
+    pub fn user_upk(&self, p: u32) -> &UpdateKey<E> {
+        let (_, p) = self.cell_upks.locate(p);
+        &self.cell_upks.upks[p as usize]
+    }
+
+    /// the commit cell of the current state.
+    pub fn cell_commit(&self) -> CellCommit<E> {
+        CellCommit {
+            block_height: self.block_height,
+            commits: self.commits.clone(),
+            user_count: self.next_user,
+        }
+    }
+
+    /// the L1 txs' builder of the rollup's scripts.
+    pub fn tx_builder(&self) -> Result<RollupTxBuilder, CkbRpcError> {
+        RollupTxBuilder::from_hex(
+            &self.rollup_lock,
+            &self.rollup_dep,
+            &self.commit_type,
+            &self.commit_type_dep,
+            &self.upk_cell,
+            self.cell_upks.account_size,
+        )
+    }
+
+    /// the live commit cell of the current state.
+    pub fn live_commit(&self) -> Result<CommitCell<E>, CkbRpcError> {
+        Ok(CommitCell {
+            out_point: decode(&self.commit_cell)?,
+            commit: self.cell_commit(),
+        })
+    }
+
+    /// the token's UDT pool on L1.
+    pub fn vault(&self, token: usize) -> Result<UdtCell, CkbRpcError> {
+        Ok(UdtCell {
+            out_point: decode(&self.udt_cells[token])?,
+            udt_type: decode(&self.udt_types[token])?,
+            amount: self.total_udt_amounts[token],
+        })
+    }
+
+    /// the deployer's UDT cell of the token, deposits are from it.
+    pub fn wallet(&self, token: usize) -> Result<UdtCell, CkbRpcError> {
+        Ok(UdtCell {
+            out_point: decode(&self.my_udts[token])?,
+            udt_type: decode(&self.udt_types[token])?,
+            amount: self.my_udt_amounts[token],
+        })
+    }
+
+    /// the lock funding the L1 txs and receiving their changes.
+    pub fn funding_lock(&self) -> Result<Script, CkbRpcError> {
+        if self.funding_lock.is_empty() {
+            decode(&self.udt_lock)
+        } else {
+            decode(&self.funding_lock)
+        }
     }
 
-    pub fn user_proof(&self, u: u32) -> Proof<E> {
-        self.proofs[u as usize].clone()
+    /// the sender of the L1 txs, used after the storage lock is released.
+    pub fn l1_sender(&self) -> Result<L1Sender, CkbRpcError> {
+        Ok(L1Sender {
+            ckb: self.ckb.clone(),
+            funding_lock: self.funding_lock()?,
+        })
     }
 
-    pub fn pool_balance(&self, u: u32) -> u128 {
-        self.tmp_balances[u as usize]
+    /// update the position's shard commitment in the block's new commitments.
+    fn update_shard_commit(
+        &self,
+        new_commits: &mut BTreeMap<u32, Commitment<E>>,
+        value: E::Fr,
+        p: u32,
+    ) -> Result<(), SynthesisError> {
+        let (shard, i) = self.cell_upks.locate(p);
+        let commit = new_commits
+            .entry(shard)
+            .or_insert(self.commits[shard as usize].clone());
+        *commit = update_commit::<E>(
+            commit,
+            value,
+            i,
+            self.user_upk(p),
+            self.omega,
+            self.cell_upks.shard_size(),
+        )?;
+        Ok(())
     }
 
-    pub fn new_transfer(&self, from: u32, to: u32, amount: u128, sk: &SecretKey) -> Transaction<E> {
-        Transaction::new_transfer(
+    /// the block's changed shards, froms' proofs are aggregated in their shards.
+    fn block_shards(
+        &self,
+        new_commits: BTreeMap<u32, Commitment<E>>,
+        froms: IndexMap<u32, Proof<E>>,
+    ) -> Result<Vec<BlockShard<E>>, SynthesisError> {
+        let mut proves: BTreeMap<u32, (Vec<u32>, Vec<Proof<E>>)> = BTreeMap::new();
+        for (p, proof) in froms {
+            let (shard, i) = self.cell_upks.locate(p);
+            let prove = proves.entry(shard).or_insert((vec![], vec![]));
+            prove.0.push(i);
+            prove.1.push(proof);
+        }
+
+        let mut shards = vec![];
+        for (shard, new_commit) in new_commits {
+            let proof = match proves.remove(&shard) {
+                Some((points, proofs)) => Some(aggregate_proofs::<E>(points, proofs, self.omega)?),
+                None => None,
+            };
+            shards.push(BlockShard {
+                shard,
+                commit: self.commits[shard as usize].clone(),
+                new_commit,
+                proof,
+            });
+        }
+
+        Ok(shards)
+    }
+
+    /// the proof of the position.
+    pub fn user_proof(&self, p: u32) -> Proof<E> {
+        self.proofs[p as usize].clone()
+    }
+
+    pub fn pool_balance(&self, u: u32, token: u32) -> u128 {
+        self.tmp_balances[position(u, token) as usize]
+    }
+
+    pub fn new_transfer(
+        &self,
+        from: u32,
+        to: u32,
+        token: u32,
+        amount: u128,
+        fee: u128,
+        sk: &SecretKey,
+    ) -> Transaction<E> {
+        let p = position(from, token);
+        let mut tx = Transaction::new_transfer(
             from,
             to,
+            token,
             amount,
+            fee,
             self.user_fpk(from),
-            self.next_nonce(from),
+            self.next_nonce(p),
             // in one block, balance if current block balance, not tmp_balance
-            self.balances[from as usize],
-            self.user_proof(from),
+            self.balances[p as usize],
+            self.user_proof(p),
             &sk,
-        )
+        );
+        if self.aggregate_signs {
+            tx.sign_bls(sk);
+        }
+        tx
     }
 
-    pub fn new_deposit(&self, from: u32, amount: u128, sk: &SecretKey) -> Transaction<E> {
+    pub fn new_deposit(
+        &self,
+        from: u32,
+        token: u32,
+        amount: u128,
+        sk: &SecretKey,
+    ) -> Transaction<E> {
+        let p = position(from, token);
         Transaction::new_deposit(
             from,
+            token,
             amount,
             self.user_fpk(from),
-            // use current block's nonce, not add it.
-            self.nonces[from as usize],
+            // the nonce after the tx, one more than the current block's.
+            self.nonces[p as usize] + 1,
             // in one block, balance if current block balance, not tmp_balance
-            self.balances[from as usize],
-            self.user_proof(from),
+            self.balances[p as usize],
+            self.user_proof(p),
             &sk,
         )
     }
 
-    pub fn new_withdraw(&self, from: u32, amount: u128, sk: &SecretKey) -> Transaction<E> {
+    /// the withdrawn UDT goes to a cell of the lock hash.
+    pub fn new_withdraw(
+        &self,
+        from: u32,
+        token: u32,
+        amount: u128,
+        lock_hash: [u8; 32],
+        sk: &SecretKey,
+    ) -> Transaction<E> {
+        let p = position(from, token);
         Transaction::new_withdraw(
             from,
+            token,
             amount,
+            lock_hash,
             self.user_fpk(from),
-            // use current block's nonce, not add it.
-            self.nonces[from as usize],
+            // the nonce after the tx, one more than the current block's.
+            self.nonces[p as usize] + 1,
             // in one block, balance if current block balance, not tmp_balance
-            self.balances[from as usize],
-            self.user_proof(from),
+            self.balances[p as usize],
+            self.user_proof(p),
             &sk,
         )
     }
 
     pub fn new_register(&self, from: u32, pk: PublicKey, sk: &SecretKey) -> Transaction<E> {
+        // the account's addr is bound to its token 0 slot.
+        let p = position(from, 0);
         let new_fpk = FullPubKey::<E> {
             i: from,
-            update_key: self.user_upk(from).clone(),
+            update_key: self.user_upk(p).clone(),
             tradition_pubkey: pk,
         };
 
-        Transaction::new_register(
+        let mut tx = Transaction::new_register(
             from,
             new_fpk,
             // when register his nonce must eq = 0
             0,
             // in one block, balance if current block balance, not tmp_balance
-            self.balances[from as usize],
-            self.user_proof(from),
+            self.balances[p as usize],
+            self.user_proof(p),
             &sk,
-        )
+        );
+        if self.aggregate_signs {
+            tx.sign_bls(sk);
+        }
+        tx
     }
 
+    /// false when the tx is invalid on the tmp state, it is not inserted.
     pub fn try_insert_tx(&mut self, tx: Transaction<E>) -> bool {
         let tx_hash = tx.hash();
 
         if !self.pools.contains_key(&tx_hash) {
             match tx.tx_type {
-                TxType::Transfer(from, to, amount) => {
+                TxType::Transfer(from, to, token, amount, fee) => {
+                    let (from, to) = (position(from, token), position(to, token));
+                    let fee_to = position(self.fee_recipient, token);
+                    let n = self.tmp_balances.len() as u32;
+                    if token as usize >= TOKEN_SIZE || from >= n || to >= n || fee_to >= n {
+                        return false;
+                    }
+                    match pay(&tx) {
+                        Some(pay) if pay <= self.tmp_balances[from as usize] => {
+                            self.tmp_balances[from as usize] -= pay;
+                        }
+                        _ => return false,
+                    }
                     self.tmp_nonces[from as usize] += 1;
-                    self.tmp_balances[from as usize] -= amount;
                     self.tmp_balances[to as usize] += amount;
+                    self.tmp_balances[fee_to as usize] += fee;
                 }
                 TxType::Register(from) => {
+                    // the accounts are registered in order.
+                    if from != self.tmp_next_user || from >= self.cell_upks.account_size {
+                        return false;
+                    }
                     self.tmp_next_user += 1;
-                    self.tmp_nonces[from as usize] += 1;
+                    for token in 0..TOKEN_SIZE as u32 {
+                        self.tmp_nonces[position(from, token) as usize] += 1;
+                    }
                 }
-                TxType::Deposit(_to, _amount) => {
+                TxType::Deposit(..) => {
                     // not handle deposit
                     return false;
                 }
-                TxType::Withdraw(_from, _amount) => {
+                TxType::Withdraw(..) => {
                     // not handle withdraw
                     return false;
                 }
@@ -222,18 +685,13 @@ impl<E: PairingEngine> Storage<E> {
         true
     }
 
-    /// deposit & withdraw use when operate on L1, need build a block to change.
-    pub fn build_block(&mut self, mut txs: Vec<Transaction<E>>) -> Option<Block<E>> {
-        let n = ACCOUNT_SIZE;
-        let omega = self.omega;
-
-        let mut new_commit = self.commit.clone();
+    /// the miner's block of the pool's transfers & registers.
+    pub fn build_block(&self, mut txs: Vec<Transaction<E>>) -> Result<Block<E>, String> {
+        let mut new_commits = BTreeMap::new();
 
         let mut froms = IndexMap::new();
         let mut txlist: Vec<Transaction<E>> = vec![];
 
-        println!("START BUILD BLOCK.... txs: {}", txs.len());
-
         loop {
             if txs.len() == 0 {
                 break;
@@ -242,228 +700,740 @@ impl<E: PairingEngine> Storage<E> {
             let tx = txs.remove(0);
 
             match tx.tx_type {
-                TxType::Transfer(from, to, _amount) => {
+                TxType::Transfer(from, to, token, _amount, fee) => {
+                    let (from, to) = (position(from, token), position(to, token));
                     let (from_amount, to_amount) = tx.delta_value();
 
                     // UPDATE FROM
-                    new_commit = update_commit::<E>(
-                        &new_commit,
-                        from_amount,
-                        from,
-                        &self.user_upk(from),
-                        omega,
-                        n,
-                    )
-                    .expect("UPDATE TRANSFER FROM COMMIT FAILURE");
+                    self.update_shard_commit(&mut new_commits, from_amount, from)
+                        .map_err(|e| format!("update transfer from commit: {:?}", e))?;
 
                     // UPDATE TO
-                    new_commit = update_commit::<E>(
-                        &new_commit,
-                        to_amount,
-                        to,
-                        &self.user_upk(from),
-                        omega,
-                        n,
-                    )
-                    .expect("UPDATE TRANSFER TO COMMIT FAILURE");
-
-                    if !froms.contains_key(&tx.from()) {
-                        froms.insert(tx.from(), tx.proof.clone());
+                    self.update_shard_commit(&mut new_commits, to_amount, to)
+                        .map_err(|e| format!("update transfer to commit: {:?}", e))?;
+
+                    // UPDATE FEE RECIPIENT
+                    if fee > 0 {
+                        let fee_to = position(self.fee_recipient, token);
+                        self.update_shard_commit(&mut new_commits, u128_to_fr::<E>(fee), fee_to)
+                            .map_err(|e| format!("update transfer fee commit: {:?}", e))?;
+                    }
+
+                    if !froms.contains_key(&from) {
+                        froms.insert(from, tx.proof.clone());
                     }
                 }
                 TxType::Register(account) => {
-                    new_commit = update_commit::<E>(
-                        &new_commit,
-                        tx.delta_value().0,
-                        account,
-                        &self.user_upk(account),
-                        omega,
-                        n,
-                    )
-                    .expect("UPDATE REGISTER COMMIT FAILURE");
-
-                    if !froms.contains_key(&tx.from()) {
-                        froms.insert(tx.from(), tx.proof.clone());
+                    for token in 0..TOKEN_SIZE as u32 {
+                        let p = position(account, token);
+                        self.update_shard_commit(&mut new_commits, tx.delta_value().0, p)
+                            .map_err(|e| format!("update register commit: {:?}", e))?;
+
+                        // every slot of the account is proven empty.
+                        if !froms.contains_key(&p) {
+                            froms.insert(p, self.user_proof(p));
+                        }
                     }
                 }
-                TxType::Deposit(..) => {
-                    panic!("NOOOOOP");
-                }
-                TxType::Withdraw(..) => {
-                    panic!("NOOOOOP");
+                TxType::Deposit(..) | TxType::Withdraw(..) => {
+                    return Err(String::from("deposit & withdraw are not in the pool"));
                 }
             }
 
             txlist.push(tx);
         }
 
-        let proof = aggregate_proofs::<E>(
-            froms.keys().map(|v| *v).collect(),
-            froms.values().map(|v| v.clone()).collect(),
-            omega,
-        )
-        .expect("AGGREGATE ERROR");
+        let shards = self
+            .block_shards(new_commits, froms)
+            .map_err(|e| format!("aggregate proofs: {:?}", e))?;
 
-        let block = Block {
-            proof,
+        let mut block = Block {
             block_height: self.block_height + 1,
-            commit: self.commit.clone(),
-            new_commit: new_commit,
+            fee_recipient: self.fee_recipient,
+            shards,
             txs: txlist,
+            aggregate_sign: None,
         };
 
-        Some(block)
+        if self.aggregate_signs {
+            block.aggregate_signatures()?;
+        }
+
+        Ok(block)
     }
 
     /// deposit & withdraw use when operate on L1, need build a block to change.
-    pub fn build_block_by_user(&mut self, tx: Transaction<E>) -> Option<Block<E>> {
-        let n = ACCOUNT_SIZE;
-        let omega = self.omega;
-
-        let mut new_commit = self.commit.clone();
+    pub fn build_block_by_user(&self, tx: Transaction<E>) -> Result<Block<E>, String> {
+        let mut new_commits = BTreeMap::new();
 
         match tx.tx_type {
             TxType::Transfer(..) | TxType::Register(..) => {
-                panic!("NOOOOP");
-            }
-            TxType::Deposit(from, _amount) => {
-                new_commit = update_commit::<E>(
-                    &new_commit,
-                    tx.delta_value().0,
-                    from,
-                    &self.user_upk(from),
-                    omega,
-                    n,
-                )
-                .expect("UPDATE COMMIT DEPOSIT FAILURE");
+                return Err(String::from("transfer & register go to the pool"));
             }
-            TxType::Withdraw(from, _amount) => {
-                new_commit = update_commit::<E>(
-                    &new_commit,
-                    tx.delta_value().0,
-                    from,
-                    &self.user_upk(from),
-                    omega,
-                    n,
-                )
-                .expect("UPDATE COMMIT DEPOSIT FAILURE");
+            TxType::Deposit(..) | TxType::Withdraw(..) => {
+                self.update_shard_commit(&mut new_commits, tx.delta_value().0, tx.from_position())
+                    .map_err(|e| format!("update user commit: {:?}", e))?;
             }
         }
 
-        let proof = aggregate_proofs::<E>(vec![tx.from()], vec![tx.proof.clone()], omega)
-            .expect("AGGREGATE PROOFS ERROR");
+        let mut froms = IndexMap::new();
+        froms.insert(tx.from_position(), tx.proof.clone());
+        let shards = self
+            .block_shards(new_commits, froms)
+            .map_err(|e| format!("aggregate proofs: {:?}", e))?;
 
         let block = Block {
-            proof,
             block_height: self.block_height + 1,
-            commit: self.commit.clone(),
-            new_commit: new_commit,
+            fee_recipient: self.fee_recipient,
+            shards,
             txs: vec![tx],
+            aggregate_sign: None,
         };
 
-        Some(block)
+        Ok(block)
+    }
+
+    /// miner new block, none when the pool is empty.
+    /// the pool is kept when failed, the txs are tried again in the next block.
+    pub fn create_block(&mut self) -> Result<Option<Block<E>>, String> {
+        if self.pools.is_empty() {
+            return Ok(None);
+        }
+
+        let txs = self.order_by_fee(self.pools.values().cloned().collect())?;
+        let block = self.build_block(txs.clone())?;
+        self.pools.clear();
+        self.pending = txs;
+        Ok(Some(block))
     }
 
-    /// miner new block.
-    pub fn create_block(&mut self) -> Option<Block<E>> {
-        if self.pools.len() == 0 {
-            println!("miner block: no transactions.");
-            return None;
+    /// the mempool's txs ordered by fee, higher fee first.
+    /// registers are always first, and a tx is taken only when its sender's previous
+    /// nonce is taken and the balance is enough, so the block is still valid.
+    /// the pool only has txs valid in the insert order, so a tx not taken is an error.
+    fn order_by_fee(&self, txs: Vec<Transaction<E>>) -> Result<Vec<Transaction<E>>, String> {
+        let (mut ordered, mut txs): (Vec<_>, Vec<_>) =
+            txs.into_iter().partition(|tx| match tx.tx_type {
+                TxType::Register(..) => true,
+                _ => false,
+            });
+        // stable sort, same fee txs keep the insert order.
+        txs.sort_by(|a, b| b.fee().cmp(&a.fee()));
+
+        let mut balances = self.balances.clone();
+        loop {
+            let ready = txs.iter().enumerate().find_map(|(i, tx)| {
+                let p = tx.from_position();
+                let pay = pay(tx).filter(|pay| balances[p as usize] >= *pay)?;
+                if txs
+                    .iter()
+                    .any(|t| t.from_position() == p && t.nonce < tx.nonce)
+                {
+                    None
+                } else {
+                    Some((i, pay))
+                }
+            });
+
+            match ready {
+                Some((i, pay)) => {
+                    let tx = txs.remove(i);
+                    balances[tx.from_position() as usize] -= pay;
+                    if let TxType::Transfer(_, to, token, amount, fee) = tx.tx_type {
+                        balances[position(to, token) as usize] += amount;
+                        balances[position(self.fee_recipient, token) as usize] += fee;
+                    }
+                    ordered.push(tx);
+                }
+                None => break,
+            }
+        }
+        if !txs.is_empty() {
+            return Err(format!("{} txs can not be packed", txs.len()));
         }
 
-        let txs = self.pools.drain(..).map(|(_k, v)| v).collect();
-        self.build_block(txs)
+        Ok(ordered)
     }
 
     /// handle when the block commit to L1.
-    pub fn handle_block(&mut self, block: Block<E>) {
-        let n = ACCOUNT_SIZE;
+    /// the state is not changed when it fails.
+    pub fn handle_block(&mut self, block: Block<E>) -> Result<(), String> {
+        // the proofs only need the changed values, they are updated first.
+        self.update_block_proofs(self.block_cvalues(&block))?;
+
+        // the state before the block, to roll back it when L1 reorgs.
+        self.undos.push(BlockUndo {
+            block: block.clone(),
+            l1_height: None,
+            commits: self.commits.clone(),
+            commit_cell: self.commit_cell.clone(),
+            udt_cells: self.udt_cells.clone(),
+            total_udt_amounts: self.total_udt_amounts.clone(),
+        });
+        self.blocks.push(block.clone());
+        self.pending.clear();
 
         self.block_height = block.block_height;
-        let mut cvalues = HashMap::new();
+        let fee_recipient = block.fee_recipient;
 
         // 1. update balance & fpk
         for tx in block.txs {
             match tx.tx_type {
-                TxType::Deposit(from, amount) => {
+                TxType::Deposit(from, token, amount) => {
+                    let from = position(from, token);
                     self.balances[from as usize] += amount;
                     self.tmp_balances[from as usize] += amount;
-                    let delta = tx.delta_value().0;
-
-                    cvalues
-                        .entry(from)
-                        .and_modify(|f| *f += &delta)
-                        .or_insert(delta);
+                    self.nonces[from as usize] += 1;
+                    self.tmp_nonces[from as usize] += 1;
                 }
-                TxType::Withdraw(from, amount) => {
+                TxType::Withdraw(from, token, amount, _) => {
+                    let from = position(from, token);
                     self.balances[from as usize] -= amount;
                     self.tmp_balances[from as usize] -= amount;
-                    let delta = tx.delta_value().0;
-
-                    cvalues
-                        .entry(from)
-                        .and_modify(|f| *f += &delta)
-                        .or_insert(delta);
+                    self.nonces[from as usize] += 1;
+                    self.tmp_nonces[from as usize] += 1;
                 }
-                TxType::Transfer(from, to, amount) => {
-                    self.balances[from as usize] -= amount;
+                TxType::Transfer(from, to, token, amount, fee) => {
+                    let (from, to) = (position(from, token), position(to, token));
+                    let fee_to = position(fee_recipient, token);
+                    self.balances[from as usize] -= amount + fee;
                     self.balances[to as usize] += amount;
-
-                    let (from_delta, to_delta) = tx.delta_value();
-
-                    cvalues
-                        .entry(from)
-                        .and_modify(|f| *f += &from_delta)
-                        .or_insert(from_delta);
-
-                    cvalues
-                        .entry(to)
-                        .and_modify(|f| *f += &to_delta)
-                        .or_insert(to_delta);
+                    self.balances[fee_to as usize] += fee;
+                    self.nonces[from as usize] += 1;
                 }
                 TxType::Register(account) => {
                     self.full_pubkeys[account as usize] = FullPubKey {
                         i: account,
-                        update_key: self.user_upk(account).clone(),
+                        update_key: self.user_upk(position(account, 0)).clone(),
                         tradition_pubkey: tx.pubkey.clone(),
                     };
                     self.next_user += 1;
 
-                    let delta = tx.delta_value().0;
-
-                    cvalues
-                        .entry(account)
-                        .and_modify(|f| *f += &delta)
-                        .or_insert(delta);
+                    for token in 0..TOKEN_SIZE as u32 {
+                        self.nonces[position(account, token) as usize] += 1;
+                    }
                 }
             }
         }
 
         // 2. UPDATE COMMIT
-        self.commit = block.new_commit;
-        self.block_height = block.block_height;
-
-        update_proofs::<E>(
-            &self.params.proving_key.update_keys,
-            &self.commit,
-            &mut self.proofs,
-            &cvalues,
-            n as usize,
-        )
-        .expect("UPDATE PROOFS FAILURE");
+        for shard in block.shards {
+            self.commits[shard.shard as usize] = shard.new_commit;
+        }
 
         // 3. UPDATE POOL
+        self.refresh_pools();
+        Ok(())
+    }
+
+    /// the changed values of the block's positions.
+    fn block_cvalues(&self, block: &Block<E>) -> HashMap<u32, E::Fr> {
+        let mut cvalues = HashMap::new();
+        let mut change = |p: u32, delta: E::Fr| {
+            cvalues
+                .entry(p)
+                .and_modify(|f| *f += &delta)
+                .or_insert(delta);
+        };
+
+        for tx in &block.txs {
+            match tx.tx_type {
+                TxType::Deposit(from, token, _) | TxType::Withdraw(from, token, ..) => {
+                    change(position(from, token), tx.delta_value().0);
+                }
+                TxType::Transfer(from, to, token, _, fee) => {
+                    let (from_delta, to_delta) = tx.delta_value();
+                    change(position(from, token), from_delta);
+                    change(position(to, token), to_delta);
+                    if fee > 0 {
+                        change(position(block.fee_recipient, token), u128_to_fr::<E>(fee));
+                    }
+                }
+                TxType::Register(account) => {
+                    let delta = tx.delta_value().0;
+                    for token in 0..TOKEN_SIZE as u32 {
+                        change(position(account, token), delta);
+                    }
+                }
+            }
+        }
+
+        cvalues
+    }
+
+    /// update the proofs with the changed values, only the changed shards' proofs need update.
+    /// the proofs are not changed when it fails.
+    fn update_block_proofs(&mut self, cvalues: HashMap<u32, E::Fr>) -> Result<(), String> {
+        let n = self.cell_upks.shard_size();
+        let mut proofs = self.proofs.clone();
+        let mut shard_cvalues: BTreeMap<u32, HashMap<u32, E::Fr>> = BTreeMap::new();
+        for (p, value) in cvalues {
+            let (shard, i) = self.cell_upks.locate(p);
+            shard_cvalues.entry(shard).or_default().insert(i, value);
+        }
+        for (shard, cvalues) in shard_cvalues {
+            let start = shard as usize * n;
+            update_proofs::<E>(
+                &self.cell_upks.upks,
+                &self.commits[shard as usize],
+                &mut proofs[start..start + n],
+                &cvalues,
+                n,
+            )
+            .map_err(|e| format!("update proofs: {:?}", e))?;
+        }
+
+        self.proofs = proofs;
+        Ok(())
+    }
+
+    /// the pool's txs use the current block's balance & proof.
+    fn refresh_pools(&mut self) {
         for (_hash, tx) in self.pools.iter_mut() {
-            let from = tx.from();
+            let from = tx.from_position();
             tx.balance = self.balances[from as usize];
             tx.proof = self.proofs[from as usize].clone();
         }
+    }
 
-        println!("HANDLE BLOCK OVER");
+    /// rebuild the tmp state from the committed state, and insert the txs to the pool.
+    /// the txs not valid on the committed state any more are dropped.
+    fn reset_pools(&mut self, txs: Vec<Transaction<E>>) {
+        self.tmp_next_user = self.next_user;
+        self.tmp_balances = self.balances.clone();
+        self.tmp_nonces = self.nonces.clone();
+
+        for tx in txs {
+            let p = tx.from_position() as usize;
+            let enough = pay(&tx).map_or(false, |pay| self.tmp_balances[p] >= pay);
+            if tx.nonce == self.tmp_nonces[p] && enough {
+                self.try_insert_tx(tx);
+            }
+        }
+        self.refresh_pools();
     }
 
     /// if send to L1 failure, revert the block's txs.
-    pub fn revert_block(&mut self, _block: Block<E>) {
-        todo!()
+    /// the txs go back to the mempool before the txs inserted after the block,
+    /// and the tmp state is rebuilt from the committed state.
+    pub fn revert_block(&mut self, block: Block<E>) {
+        // aggregated block's txs have no signs, use the pending txs.
+        let txs = if self.pending.is_empty() {
+            block.txs
+        } else {
+            std::mem::take(&mut self.pending)
+        };
+        let pools: Vec<Transaction<E>> = self.pools.drain(..).map(|(_k, v)| v).collect();
+
+        self.reset_pools(txs.into_iter().chain(pools).collect());
+    }
+
+    /// the block is found on L1 at the height.
+    pub fn confirm_block(&mut self, block_height: u32, l1_height: u64) {
+        if let Some(undo) = self
+            .undos
+            .iter_mut()
+            .rev()
+            .find(|undo| undo.block.block_height == block_height)
+        {
+            undo.l1_height = Some(l1_height);
+        }
+    }
+
+    /// the L1 block is handled, its hash is kept to find a reorg.
+    /// the undo records & hashes deeper than the confirmations are final, and dropped.
+    pub fn confirm_l1_block(&mut self, l1_height: u64, hash: String) {
+        self.l1_hashes.insert(l1_height, hash);
+        self.l1_block_height = l1_height;
+
+        let finalized = l1_height.saturating_sub(self.confirmations);
+        self.l1_hashes = self.l1_hashes.split_off(&finalized);
+        let n = self
+            .undos
+            .iter()
+            .take_while(|undo| undo.l1_height.map_or(false, |h| h < finalized))
+            .count();
+        self.undos.drain(..n);
+    }
+
+    /// L1 reorgs after the fork height, roll back the L2 blocks not on L1 before it.
+    /// the listener handles the blocks again from the fork, then the pool is rebuilt again.
+    /// when a block fails to roll back, it and the blocks before it are kept.
+    pub fn rollback(&mut self, fork: u64) -> Result<(), String> {
+        while let Some(undo) = self.undos.last() {
+            if undo.l1_height.map_or(false, |h| h <= fork) {
+                break;
+            }
+            self.undo_block(undo.clone())?;
+            self.undos.pop();
+        }
+
+        self.l1_hashes.retain(|height, _| *height <= fork);
+        self.l1_block_height = fork;
+        self.rebuild_pools();
+        Ok(())
+    }
+
+    /// the block is invalid and not sent, its txs are dropped,
+    /// and the tmp state is rebuilt from the committed state.
+    pub fn drop_block(&mut self) {
+        self.pending.clear();
+        self.rebuild_pools();
+    }
+
+    /// rebuild the pool & tmp state on the committed state.
+    pub fn rebuild_pools(&mut self) {
+        let pools: Vec<Transaction<E>> = self.pools.drain(..).map(|(_k, v)| v).collect();
+        self.reset_pools(pools);
+    }
+
+    fn undo_block(&mut self, undo: BlockUndo<E>) -> Result<(), String> {
+        let block = undo.block;
+        let mut cvalues = self.block_cvalues(&block);
+        for value in cvalues.values_mut() {
+            *value = -*value;
+        }
+        // proofs are updated by the negative values, they are linear in the values.
+        self.update_block_proofs(cvalues)?;
+
+        // the later txs may spend what the earlier ones paid, undo them first.
+        for tx in block.txs.iter().rev() {
+            match tx.tx_type {
+                TxType::Deposit(from, token, amount) => {
+                    let from = position(from, token);
+                    self.balances[from as usize] -= amount;
+                    self.nonces[from as usize] -= 1;
+                }
+                TxType::Withdraw(from, token, amount, _) => {
+                    let from = position(from, token);
+                    self.balances[from as usize] += amount;
+                    self.nonces[from as usize] -= 1;
+                }
+                TxType::Transfer(from, to, token, amount, fee) => {
+                    let (from, to) = (position(from, token), position(to, token));
+                    let fee_to = position(block.fee_recipient, token);
+                    self.balances[fee_to as usize] -= fee;
+                    self.balances[to as usize] -= amount;
+                    self.balances[from as usize] += amount + fee;
+                    self.nonces[from as usize] -= 1;
+                }
+                TxType::Register(account) => {
+                    let upk = self.user_upk(position(account, 0)).clone();
+                    self.full_pubkeys[account as usize] = FullPubKey::default(account, upk);
+                    self.next_user -= 1;
+
+                    for token in 0..TOKEN_SIZE as u32 {
+                        self.nonces[position(account, token) as usize] -= 1;
+                    }
+                }
+            }
+        }
+
+        self.commits = undo.commits;
+        self.blocks.pop();
+        self.block_height = block.block_height - 1;
+        self.commit_cell = undo.commit_cell;
+        self.udt_cells = undo.udt_cells;
+        self.total_udt_amounts = undo.total_udt_amounts;
+        Ok(())
+    }
+}
+
+/// the state before a L2 block is handled, to roll back the block when L1 reorgs.
+/// the balances, nonces & proofs are rolled back by the block's txs.
+#[derive(Clone)]
+pub struct BlockUndo<E: PairingEngine> {
+    pub block: Block<E>,
+    /// the L1 height of the block, none before the listener finds the miner's block.
+    pub l1_height: Option<u64>,
+    pub commits: Vec<Commitment<E>>,
+    pub commit_cell: String,
+    pub udt_cells: Vec<String>,
+    pub total_udt_amounts: Vec<u128>,
+}
+
+/// funds the L1 txs by the funding lock's cells and sends them.
+pub struct L1Sender {
+    ckb: CkbClient,
+    funding_lock: Script,
+}
+
+impl L1Sender {
+    /// returns the out points of the tx's outputs, the change is the last, and the tx hash.
+    pub async fn send(&self, tx: TransactionView) -> Result<(Vec<String>, String), CkbRpcError> {
+        let tx = self.ckb.fund_transaction(tx, &self.funding_lock).await?;
+        self.ckb.send_transaction(tx).await
+    }
+}
+
+/// what the tx's sender pays in the pool, none when the amount and fee overflow.
+fn pay<E: PairingEngine>(tx: &Transaction<E>) -> Option<u128> {
+    match tx.tx_type {
+        TxType::Transfer(.., amount, fee) => amount.checked_add(fee),
+        _ => Some(0),
+    }
+}
+
+impl<E: PairingEngine> BlockUndo<E> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self.l1_height {
+            Some(height) => {
+                1u8.write(&mut bytes).unwrap();
+                height.write(&mut bytes).unwrap();
+            }
+            None => 0u8.write(&mut bytes).unwrap(),
+        }
+
+        let mut cells = vec![self.commit_cell.clone()];
+        cells.extend(self.udt_cells.iter().cloned());
+        bytes.extend(write_bytes(&[
+            self.block.to_packed(),
+            write_vec(&self.commits),
+            write_strings(&cells),
+            write_vec(&self.total_udt_amounts),
+        ]));
+        bytes
+    }
+
+    pub fn from_bytes(mut s: &[u8]) -> Result<Self, String> {
+        let l1_height = match u8::read(&mut s).map_err(|e| e.to_string())? {
+            0u8 => None,
+            _ => Some(u64::read(&mut s).map_err(|e| e.to_string())?),
+        };
+
+        let parts = read_bytes(s)?;
+        if parts.len() != 4 {
+            return Err(String::from("undo is invalid"));
+        }
+        let block = Block::from_packed(&parts[0]).map_err(|_| String::from("undo block"))?;
+        let mut cells = read_strings(&parts[2])?;
+        if cells.is_empty() {
+            return Err(String::from("undo cells"));
+        }
+        let commit_cell = cells.remove(0);
+
+        Ok(Self {
+            block,
+            l1_height,
+            commits: read_vec(&parts[1])?,
+            commit_cell,
+            udt_cells: cells,
+            total_udt_amounts: read_vec(&parts[3])?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asvc::initialize_asvc;
+    use crate::db::MemoryStore;
+    use ckb_zkp::curve::bn_256::Bn_256;
+
+    /// register the accounts 0 & 1 in a block each, then deposit 100 of token 0 to 0.
+    fn register_and_deposit(storage: &mut Storage<Bn_256>, sk: &SecretKey) {
+        for account in 0..2 {
+            let tx = storage.new_register(account, sk.public_key::<Bn_256>(), sk);
+            assert!(storage.try_insert_tx(tx));
+            let block = storage.create_block().unwrap().unwrap();
+            storage.handle_block(block).unwrap();
+        }
+
+        // the deposit adds one to the registered nonce.
+        let tx = storage.new_deposit(0, 0, 100, sk);
+        assert_eq!(tx.nonce, 2);
+        let block = storage.build_block_by_user(tx).unwrap();
+        storage.handle_block(block).unwrap();
+        assert_eq!(storage.nonces[position(0, 0) as usize], 2);
+    }
+
+    #[test]
+    fn revert_rejected_blocks() {
+        let (params, commits, proofs, full_pubkeys) = initialize_asvc::<Bn_256>(4, 4).unwrap();
+        let db = Box::new(MemoryStore::default());
+        let mut storage = Storage::init(params, commits, proofs, full_pubkeys, db);
+        let sk = SecretKey(vec![7u8; 32]);
+        register_and_deposit(&mut storage, &sk);
+        let height = storage.block_height;
+        let (balances, nonces) = (storage.balances.clone(), storage.nonces.clone());
+
+        // the amount & fee overflow, never in the pool.
+        let tx = storage.new_transfer(0, 1, 0, 10, u128::max_value(), &sk);
+        assert!(!storage.try_insert_tx(tx));
+        assert!(storage.create_block().unwrap().is_none());
+        assert_eq!(storage.pool_balance(0, 0), 100);
+
+        // L1 rejects the transfer's block.
+        let tx = storage.new_transfer(0, 1, 0, 10, 1, &sk);
+        let hash = tx.hash();
+        assert!(storage.try_insert_tx(tx));
+        let (tmp_balances, tmp_nonces) = (storage.tmp_balances.clone(), storage.tmp_nonces.clone());
+
+        let block = storage.create_block().unwrap().unwrap();
+        assert!(storage.pools.is_empty());
+        storage.revert_block(block);
+
+        assert_eq!(storage.pools.keys().collect::<Vec<_>>(), vec![&hash]);
+        assert!(storage.pending.is_empty());
+        assert_eq!(storage.tmp_balances, tmp_balances);
+        assert_eq!(storage.tmp_nonces, tmp_nonces);
+        assert_eq!(storage.block_height, height);
+        assert_eq!(storage.balances, balances);
+        assert_eq!(storage.nonces, nonces);
+
+        // the txs are mined again in the next block.
+        let block = storage.create_block().unwrap().unwrap();
+        assert_eq!(block.block_height, height + 1);
+        storage.handle_block(block).unwrap();
+        // the fee is paid to the operator, account 0 itself.
+        assert_eq!(storage.pool_balance(0, 0), 90);
+        assert_eq!(storage.pool_balance(1, 0), 10);
+        assert_eq!(storage.balances, storage.tmp_balances);
+        assert_eq!(storage.nonces, storage.tmp_nonces);
+
+        // L1 rejects the register's block.
+        let tx = storage.new_register(2, sk.public_key::<Bn_256>(), &sk);
+        assert!(storage.try_insert_tx(tx));
+        assert_eq!(storage.next_user(), 3);
+        let block = storage.create_block().unwrap().unwrap();
+        storage.revert_block(block);
+
+        assert_eq!(storage.pools.len(), 1);
+        assert_eq!(storage.next_user, 2);
+        assert_eq!(storage.next_user(), 3);
+        assert_eq!(storage.next_nonce(position(2, 0)), 1);
+        assert_eq!(storage.nonces[position(2, 0) as usize], 0);
+    }
+
+    #[test]
+    fn reject_invalid_txs() {
+        let (params, commits, proofs, full_pubkeys) = initialize_asvc::<Bn_256>(4, 4).unwrap();
+        let db = Box::new(MemoryStore::default());
+        let mut storage = Storage::init(params, commits, proofs, full_pubkeys, db);
+        let sk = SecretKey(vec![11u8; 32]);
+        register_and_deposit(&mut storage, &sk);
+
+        // the transfer-to and the fee recipient are out of the accounts.
+        let tx = storage.new_transfer(0, 4, 0, 10, 0, &sk);
+        assert!(!storage.try_insert_tx(tx));
+        storage.fee_recipient = 4;
+        let tx = storage.new_transfer(0, 1, 0, 10, 1, &sk);
+        assert!(!storage.try_insert_tx(tx));
+        storage.fee_recipient = 0;
+
+        // the next user is account 2.
+        let tx = storage.new_register(3, sk.public_key::<Bn_256>(), &sk);
+        assert!(!storage.try_insert_tx(tx));
+        let tx = storage.new_register(1, sk.public_key::<Bn_256>(), &sk);
+        assert!(!storage.try_insert_tx(tx));
+        assert!(storage.pools.is_empty());
+        assert_eq!(storage.next_user(), 2);
+        assert_eq!(storage.pool_balance(0, 0), 100);
+    }
+
+    #[test]
+    fn revert_keeps_later_txs() {
+        let (params, commits, proofs, full_pubkeys) = initialize_asvc::<Bn_256>(4, 4).unwrap();
+        let db = Box::new(MemoryStore::default());
+        let mut storage = Storage::init(params, commits, proofs, full_pubkeys, db);
+        let sk = SecretKey(vec![8u8; 32]);
+        register_and_deposit(&mut storage, &sk);
+        storage.aggregate_signs = true;
+
+        let first = storage.new_transfer(0, 1, 0, 10, 0, &sk);
+        assert!(storage.try_insert_tx(first.clone()));
+        let block = storage.create_block().unwrap().unwrap();
+        assert!(block.txs.iter().all(|tx| tx.sign.is_empty()));
+
+        // inserted while the block is sending to L1.
+        let second = storage.new_transfer(0, 1, 0, 20, 0, &sk);
+        assert_eq!(second.nonce, first.nonce + 1);
+        assert!(storage.try_insert_tx(second.clone()));
+        storage.revert_block(block);
+
+        let hashes: Vec<TxHash> = storage.pools.keys().cloned().collect();
+        assert_eq!(hashes, vec![first.hash(), second.hash()]);
+        assert!(storage.pools.values().all(|tx| !tx.sign.is_empty()));
+        assert_eq!(storage.pool_balance(0, 0), 70);
+        assert_eq!(storage.next_nonce(position(0, 0)), first.nonce + 2);
+    }
+
+    #[test]
+    fn rollback_reorged_blocks() {
+        let (params, commits, proofs, full_pubkeys) = initialize_asvc::<Bn_256>(4, 4).unwrap();
+        let db = Box::new(MemoryStore::default());
+        let mut storage = Storage::init(params, commits, proofs, full_pubkeys, db);
+        let sk = SecretKey(vec![9u8; 32]);
+        register_and_deposit(&mut storage, &sk);
+        // the L2 block i is in the L1 block 10 + i.
+        for height in 1..=3 {
+            storage.confirm_block(height, 10 + height as u64);
+            storage.confirm_l1_block(10 + height as u64, format!("{}", height));
+        }
+        let (balances, nonces) = (storage.balances.clone(), storage.nonces.clone());
+        let (commits, proofs) = (storage.commits.clone(), storage.proofs.clone());
+
+        let tx = storage.new_transfer(0, 1, 0, 10, 1, &sk);
+        assert!(storage.try_insert_tx(tx));
+        let block = storage.create_block().unwrap().unwrap();
+        storage.handle_block(block).unwrap();
+        storage.confirm_block(4, 14);
+        storage.confirm_l1_block(14, String::from("4"));
+
+        // the L1 block 14 is reorged.
+        storage.rollback(13).unwrap();
+        assert_eq!(storage.block_height, 3);
+        assert_eq!(storage.blocks.len(), 3);
+        assert_eq!(storage.undos.len(), 3);
+        assert_eq!(storage.l1_block_height, 13);
+        assert!(!storage.l1_hashes.contains_key(&14));
+        assert_eq!(storage.balances, balances);
+        assert_eq!(storage.nonces, nonces);
+        assert!(storage.commits == commits);
+        assert!(storage.proofs == proofs);
+        assert_eq!(storage.tmp_balances, balances);
+        assert_eq!(storage.tmp_nonces, nonces);
+
+        // the registers & deposit are reorged.
+        storage.rollback(11).unwrap();
+        assert_eq!(storage.block_height, 1);
+        assert_eq!(storage.next_user, 1);
+        assert_eq!(storage.balances[position(0, 0) as usize], 0);
+        assert_eq!(storage.nonces[position(1, 0) as usize], 0);
+    }
+
+    #[test]
+    fn rollback_chained_transfers() {
+        let (params, commits, proofs, full_pubkeys) = initialize_asvc::<Bn_256>(4, 4).unwrap();
+        let db = Box::new(MemoryStore::default());
+        let mut storage = Storage::init(params, commits, proofs, full_pubkeys, db);
+        let sk = SecretKey(vec![10u8; 32]);
+        register_and_deposit(&mut storage, &sk);
+        for height in 1..=3 {
+            storage.confirm_block(height, 10 + height as u64);
+            storage.confirm_l1_block(10 + height as u64, format!("{}", height));
+        }
+        let (balances, nonces) = (storage.balances.clone(), storage.nonces.clone());
+        let (commits, proofs) = (storage.commits.clone(), storage.proofs.clone());
+
+        // account 1 pays what it gets from account 0 in the same block.
+        let tx = storage.new_transfer(0, 1, 0, 10, 0, &sk);
+        assert!(storage.try_insert_tx(tx));
+        let tx = storage.new_transfer(1, 2, 0, 10, 0, &sk);
+        assert!(storage.try_insert_tx(tx));
+        let block = storage.create_block().unwrap().unwrap();
+        assert_eq!(block.txs.len(), 2);
+        storage.handle_block(block).unwrap();
+        assert_eq!(storage.balances[position(1, 0) as usize], 0);
+        storage.confirm_block(4, 14);
+        storage.confirm_l1_block(14, String::from("4"));
+
+        storage.rollback(13).unwrap();
+        assert_eq!(storage.block_height, 3);
+        assert_eq!(storage.balances, balances);
+        assert_eq!(storage.nonces, nonces);
+        assert!(storage.commits == commits);
+        assert!(storage.proofs == proofs);
     }
 }
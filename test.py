@@ -20,7 +20,7 @@ print("Setup ok")
 # register
 print("Start register test, register two account, maybe 10s")
 for _ in range(2):
-    r = requests.post(URL + "register", json={"pubkey": "00", "psk": "00"})
+    r = requests.post(URL + "register", json={"psk": "00"})
     if r.status_code == 200:
         times += 1
         print(times, r.text)
@@ -1,251 +1,437 @@
 use super::*;
+use asvc_rollup::block::{Block, BlockShard, CellCommit, CellUpks};
+use asvc_rollup::format::OP_INIT;
+use asvc_rollup::transaction::{position, FullPubKey, SecretKey, Transaction, TOKEN_SIZE};
+use ckb_rpc::{CommitCell, RollupTxBuilder, UdtCell};
 use ckb_testtool::{builtin::ALWAYS_SUCCESS, context::Context};
+use ckb_tool::ckb_hash::new_blake2b;
 use ckb_tool::ckb_types::{
     bytes::Bytes,
-    core::{Capacity, TransactionBuilder},
+    core::{Capacity, TransactionBuilder, TransactionView},
     packed::*,
     prelude::*,
 };
+use ckb_zkp::curve::bn_256::Bn_256;
+use ckb_zkp::math::{fft::EvaluationDomain, PairingEngine, Zero};
+use ckb_zkp::scheme::asvc::{commit, key_gen, prove_pos, update_commit, Parameters};
+use rand::thread_rng;
 
-const MAX_CYCLES: u64 = 10_000_000;
+type Fr = <Bn_256 as PairingEngine>::Fr;
+
+// the max cycles of a CKB block, the pairings are heavy.
+const MAX_CYCLES: u64 = 3_500_000_000;
+
+// asvc_verifier's errors.
+const ERROR_AMOUNT: i8 = 5;
+const ERROR_VERIFY: i8 = 6;
+
+// asvc_commit_type's errors.
+const ERROR_INVALID_TYPE_ID: i8 = 5;
+const ERROR_MULTIPLE_CELLS: i8 = 6;
+const ERROR_DESTROYED: i8 = 7;
+
+const ESCAPE_BLOCKS: u64 = 10;
+
+fn assert_script_error(err: String, code: i8) {
+    assert!(
+        err.contains(&format!("ValidationFailure({})", code)),
+        "expect error {}, got {}",
+        code,
+        err
+    );
+}
+
+fn output(lock: &Script, type_: Option<Script>) -> CellOutput {
+    CellOutput::new_builder()
+        .capacity(Capacity::bytes(1000).unwrap().pack())
+        .lock(lock.clone())
+        .type_(type_.pack())
+        .build()
+}
+
+fn input(out_point: OutPoint) -> CellInput {
+    CellInput::new_builder().previous_output(out_point).build()
+}
+
+/// type id = blake2b(first input || output index), same as CKB's type id.
+fn type_id(first_input: &CellInput, index: u64) -> Bytes {
+    let mut hasher = new_blake2b();
+    hasher.update(first_input.as_slice());
+    hasher.update(&index.to_le_bytes());
+    let mut id = [0u8; 32];
+    hasher.finalize(&mut id);
+    Bytes::from(id.to_vec())
+}
+
+/// the script of the contract, and its cell dep.
+fn deploy(context: &mut Context, contract: Bytes) -> (Script, CellDep) {
+    let point = context.deploy_cell(contract);
+    let script = context.build_script(&point, Bytes::new()).expect("script");
+    (script, CellDep::new_builder().out_point(point).build())
+}
+
+/// the commit type without args, and the always success lock.
+fn deploy_commit_type(context: &mut Context) -> (Script, Script) {
+    let (commit_type, _) = deploy(context, Loader::default().load_binary("asvc_commit_type"));
+    let (always_success, _) = deploy(context, ALWAYS_SUCCESS.clone());
+    (commit_type, always_success)
+}
 
 #[test]
-fn test_asvc() {
-    // deploy contract
+fn commit_type_create() {
     let mut context = Context::default();
+    let (commit_type, lock) = deploy_commit_type(&mut context);
+    let seed = input(context.create_cell(output(&lock, None), Bytes::new()));
 
-    println!("start deploy contract...");
-    let success_point = context.deploy_cell(ALWAYS_SUCCESS.clone());
-    let success_lock_script = context
-        .build_script(&success_point, Default::default())
-        .expect("script");
-    let _success_lock_script_dep = CellDep::new_builder().out_point(success_point).build();
-
-    let rollup_bin: Bytes = Loader::default().load_binary("asvc_rollup");
-    let rollup_point = context.deploy_cell(rollup_bin);
-    let rollup_script_args: Bytes = [0u8; 1].to_vec().into();
-    let rollup_lock_script = context
-        .build_script(&rollup_point, rollup_script_args)
-        .expect("script");
-    let rollup_lock_script_dep = CellDep::new_builder().out_point(rollup_point).build();
-    println!("over deploy contract...");
-
-    let input_ckb = Capacity::bytes(1000).unwrap().as_u64();
-
-    // ----- Init State -------
-    println!("start init state...");
-    let init_output_commit = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock_script.clone())
-        .build();
-    let init_upk = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock_script.clone())
-        .build();
-    let init_udt = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock_script.clone())
+    let create = |args: Bytes| {
+        let commit_type = commit_type.clone().as_builder().args(args.pack()).build();
+        TransactionBuilder::default()
+            .input(seed.clone())
+            .output(output(&lock, Some(commit_type)))
+            .output_data(Bytes::new().pack())
+            .build()
+    };
+
+    let tx = context.complete_tx(create(type_id(&seed, 0)));
+    context.verify_tx(&tx, MAX_CYCLES).expect("create");
+
+    // the type id of another output index.
+    let tx = context.complete_tx(create(type_id(&seed, 1)));
+    let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
+    assert_script_error(format!("{:?}", err), ERROR_INVALID_TYPE_ID);
+}
+
+#[test]
+fn commit_type_update_and_destroy() {
+    let mut context = Context::default();
+    let (commit_type, lock) = deploy_commit_type(&mut context);
+    let commit_type = commit_type
+        .as_builder()
+        .args(Bytes::from(vec![1u8; 32]).pack())
         .build();
-    let init_outputs_data: Vec<Bytes> = vec![
-        vec![0u8, 2u8, 3u8, 4u8, 5u8, 6u8].into(), // commits & txs
-        vec![8u8, 8u8, 8u8].into(),                // upk
-        0u128.to_le_bytes().to_vec().into(),
-    ];
+    let commit_cell = output(&lock, Some(commit_type));
+    let mut live = || input(context.create_cell(commit_cell.clone(), Bytes::new()));
+    let (update, destroy, split) = (live(), live(), live());
 
     let tx = TransactionBuilder::default()
-        .inputs(vec![])
-        .outputs(vec![
-            init_output_commit.clone(),
-            init_upk.clone(),
-            init_udt.clone(),
-        ])
-        .outputs_data(init_outputs_data.pack())
-        .cell_dep(rollup_lock_script_dep.clone())
+        .input(update)
+        .output(commit_cell.clone())
+        .output_data(Bytes::new().pack())
         .build();
     let tx = context.complete_tx(tx);
+    context.verify_tx(&tx, MAX_CYCLES).expect("update");
 
-    let cycles = context
-        .verify_tx(&tx, MAX_CYCLES)
-        .expect("pass verification");
-    println!("over init state: {}...", cycles);
-    // ----- End Init State ---
-
-    // ----- Deposit ----------
-    println!("start deposit...");
-    // inputs udt demo
-    let udt_input_out_point = context.create_cell(
-        CellOutput::new_builder()
-            .capacity(input_ckb.pack())
-            .lock(success_lock_script.clone())
-            .build(),
-        //.type_(Some(sudt_script.clone()).pack()) Type will use UDT
-        100u128.to_le_bytes().to_vec().into(),
-    );
-    let udt_input = CellInput::new_builder()
-        .previous_output(udt_input_out_point)
+    let tx = TransactionBuilder::default()
+        .input(destroy)
+        .output(output(&lock, None))
+        .output_data(Bytes::new().pack())
         .build();
+    let tx = context.complete_tx(tx);
+    let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
+    assert_script_error(format!("{:?}", err), ERROR_DESTROYED);
 
-    let deposit_commit_input = CellInput::new_builder()
-        .previous_output(context.create_cell(
-            init_output_commit,
-            vec![0u8, 2u8, 3u8, 4u8, 5u8, 6u8].into(),
-        ))
-        .build();
-    let deposit_upk_input = CellInput::new_builder()
-        .previous_output(context.create_cell(init_upk, vec![8u8, 8u8, 8u8].into()))
-        .build();
-    let deposit_udt_input = CellInput::new_builder()
-        .previous_output(context.create_cell(init_udt, 0u128.to_le_bytes().to_vec().into()))
+    let tx = TransactionBuilder::default()
+        .input(split)
+        .outputs(vec![commit_cell.clone(), commit_cell])
+        .outputs_data(vec![Bytes::new(); 2].pack())
         .build();
+    let tx = context.complete_tx(tx);
+    let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
+    assert_script_error(format!("{:?}", err), ERROR_MULTIPLE_CELLS);
+}
 
-    let deposit_commit = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock_script.clone())
-        .build();
-    let deposit_upk = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock_script.clone())
-        .build();
-    let deposit_udt = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock_script.clone())
-        .build();
+/// a rollup of 2 shards with 2 accounts each, after the init tx.
+/// the UDTs and the users' lock are always success.
+struct Rollup {
+    context: Context,
+    builder: RollupTxBuilder,
+    commit: CommitCell<Bn_256>,
+    vaults: Vec<UdtCell>,
+    lock: Script,
+    params: Parameters<Bn_256>,
+    upks: CellUpks<Bn_256>,
+}
 
-    let deposit_outputs_data: Vec<Bytes> = vec![
-        vec![1u8, 2u8, 3u8, 4u8, 5u8, 6u8].into(), // commits & txs
-        vec![8u8, 8u8, 8u8].into(),                // upk
-        100u128.to_le_bytes().to_vec().into(),     // new amount
-    ];
+impl Rollup {
+    fn init() -> Self {
+        let mut context = Context::default();
+        let loader = Loader::default();
+        let (verifier, verifier_dep) = deploy(&mut context, loader.load_binary("asvc_verifier"));
+        let (commit_type, commit_type_dep) =
+            deploy(&mut context, loader.load_binary("asvc_commit_type"));
+        let (lock, _) = deploy(&mut context, ALWAYS_SUCCESS.clone());
+        let builder = RollupTxBuilder::new(
+            verifier,
+            verifier_dep,
+            commit_type,
+            commit_type_dep,
+            // set after init.
+            OutPoint::default(),
+            0,
+        );
 
-    let tx = TransactionBuilder::default()
-        .inputs(vec![
-            deposit_commit_input,
-            deposit_upk_input,
-            deposit_udt_input,
-            udt_input,
-        ])
-        .outputs(vec![
-            deposit_commit.clone(),
-            deposit_upk.clone(),
-            deposit_udt.clone(),
-        ])
-        .outputs_data(deposit_outputs_data.pack())
-        .cell_dep(rollup_lock_script_dep.clone())
-        .build();
-    let tx = context.complete_tx(tx);
+        // the UDTs are always success with the token as args.
+        let udt_types: Vec<Script> = (0..TOKEN_SIZE as u8)
+            .map(|token| {
+                lock.clone()
+                    .as_builder()
+                    .args(Bytes::from(vec![token]).pack())
+                    .build()
+            })
+            .collect();
+        let tokens = udt_types
+            .iter()
+            .map(|udt_type| {
+                let mut hash = [0u8; 32];
+                hash.copy_from_slice(udt_type.calc_script_hash().as_slice());
+                hash
+            })
+            .collect();
 
-    let cycles = context
-        .verify_tx(&tx, MAX_CYCLES)
-        .expect("pass verification");
-    println!("over deposit: {}...", cycles);
-    // ----- End Deposit -------
-
-    // ----- POST block -------
-    println!("start post block...");
-    let block_commit_input = CellInput::new_builder()
-        .previous_output(
-            context.create_cell(deposit_commit, vec![1u8, 2u8, 3u8, 4u8, 5u8, 6u8].into()),
-        )
-        .build();
-    let block_upk_input = CellInput::new_builder()
-        .previous_output(context.create_cell(deposit_upk, vec![8u8, 8u8, 8u8].into()))
-        .build();
-    let block_commit = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock_script.clone())
-        .build();
-    let block_upk = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock_script.clone())
-        .build();
+        let n = 2 * TOKEN_SIZE;
+        let params = key_gen::<Bn_256, _>(n, &mut thread_rng()).unwrap();
+        let upks = CellUpks {
+            vk: params.verification_key.clone(),
+            omega: EvaluationDomain::<Fr>::new(n).unwrap().group_gen,
+            account_size: 4,
+            shard_accounts: 2,
+            upks: params.proving_key.update_keys.clone(),
+            tokens,
+        };
+        let zero = commit::<Bn_256>(&params.proving_key, vec![Fr::zero(); n]).unwrap();
+        let cell_commit = CellCommit {
+            block_height: 0,
+            commits: vec![zero; upks.shards()],
+            user_count: 0,
+        };
 
-    let block_outputs_data: Vec<Bytes> = vec![
-        vec![3u8, 2u8, 3u8, 4u8, 5u8, 6u8].into(), // commits & txs
-        vec![8u8, 8u8, 8u8].into(),                // upk
-    ];
+        let seed = context.create_cell(output(&lock, None), Bytes::new());
+        let (builder, tx) = builder
+            .init(seed, &udt_types, &cell_commit, &upks, ESCAPE_BLOCKS)
+            .unwrap();
+        let tx = context.complete_tx(tx);
+        context.verify_tx(&tx, MAX_CYCLES).expect("init");
 
-    let tx = TransactionBuilder::default()
-        .inputs(vec![block_commit_input, block_upk_input])
-        .outputs(vec![block_commit.clone(), block_upk.clone()])
-        .outputs_data(block_outputs_data.pack())
-        .cell_dep(rollup_lock_script_dep.clone())
-        .build();
-    let tx = context.complete_tx(tx);
+        let cells: Vec<OutPoint> = tx
+            .outputs_with_data_iter()
+            .map(|(output, data)| context.create_cell(output, data))
+            .collect();
+        let builder = RollupTxBuilder {
+            upks_cell: cells[cells.len() - 1].clone(),
+            ..builder
+        };
+        let commit = CommitCell {
+            out_point: cells[0].clone(),
+            commit: cell_commit,
+        };
+        let vaults = udt_types
+            .into_iter()
+            .zip(&cells[1..])
+            .map(|(udt_type, out_point)| UdtCell {
+                out_point: out_point.clone(),
+                udt_type,
+                amount: 0,
+            })
+            .collect();
 
-    let cycles = context
-        .verify_tx(&tx, MAX_CYCLES)
-        .expect("pass verification");
-    println!("over post block: {}...", cycles);
-    // ----- End POST block ---
-
-    // ----- Withdraw ---------
-    println!("start withdraw...");
-    let withdraw_commit_input = CellInput::new_builder()
-        .previous_output(
-            context.create_cell(block_commit, vec![3u8, 2u8, 3u8, 4u8, 5u8, 6u8].into()),
-        )
-        .build();
-    let withdraw_upk_input = CellInput::new_builder()
-        .previous_output(context.create_cell(block_upk, vec![8u8, 8u8, 8u8].into()))
-        .build();
-    let withdraw_udt_input = CellInput::new_builder()
-        .previous_output(context.create_cell(deposit_udt, 100u128.to_le_bytes().to_vec().into()))
-        .build();
+        Self {
+            context,
+            builder,
+            commit,
+            vaults,
+            lock,
+            params,
+            upks,
+        }
+    }
 
-    let withdraw_commit = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock_script.clone())
-        .build();
-    let withdraw_upk = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock_script.clone())
-        .build();
-    let withdraw_udt = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(rollup_lock_script.clone())
-        .build();
+    /// the next block registers the account, its shard is updated by the register.
+    fn register_block(&self, account: u32, sk: &SecretKey) -> Block<Bn_256> {
+        let (shard, p) = self.upks.locate(position(account, 0));
+        let fpk = FullPubKey {
+            i: account,
+            update_key: self.upks.upks[p as usize].clone(),
+            tradition_pubkey: sk.public_key::<Bn_256>(),
+        };
+        // every token slot of the account is proven empty.
+        let points = (0..TOKEN_SIZE as u32)
+            .map(|token| self.upks.locate(position(account, token)).1)
+            .collect();
+        let proof =
+            prove_pos::<Bn_256>(&self.params.proving_key, vec![Fr::zero()], points).unwrap();
+        let tx = Transaction::new_register(account, fpk, 0, 0, proof.clone(), sk);
 
-    let withdraw_udt_1 = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(success_lock_script.clone())
-        .build();
+        let commit = self.commit.commit.commits[shard as usize].clone();
+        let mut new_commit = commit.clone();
+        for token in 0..TOKEN_SIZE as u32 {
+            let (_, i) = self.upks.locate(position(account, token));
+            new_commit = update_commit::<Bn_256>(
+                &new_commit,
+                tx.delta_value().0,
+                i,
+                &self.upks.upks[i as usize],
+                self.upks.omega,
+                self.upks.shard_size(),
+            )
+            .unwrap();
+        }
+
+        Block {
+            block_height: self.commit.commit.block_height + 1,
+            fee_recipient: 0,
+            shards: vec![BlockShard {
+                shard,
+                commit,
+                new_commit,
+                proof: Some(proof),
+            }],
+            txs: vec![tx],
+            aggregate_sign: None,
+        }
+    }
+
+    fn verify(&mut self, tx: TransactionView) -> Result<u64, String> {
+        let tx = self.context.complete_tx(tx);
+        self.context
+            .verify_tx(&tx, MAX_CYCLES)
+            .map_err(|err| format!("{:?}", err))
+    }
+}
+
+fn empty_block() -> Block<Bn_256> {
+    Block {
+        block_height: 1,
+        fee_recipient: 0,
+        shards: vec![],
+        txs: vec![],
+        aggregate_sign: None,
+    }
+}
+
+#[test]
+fn post_empty_block() {
+    let mut rollup = Rollup::init();
+    let tx = rollup
+        .builder
+        .post_block(&rollup.commit, &empty_block())
+        .unwrap();
+    let cycles = rollup.verify(tx).expect("post block");
+    println!("post block cycles: {}", cycles);
+}
+
+#[test]
+fn reject_commit_cell_of_other_type() {
+    let mut rollup = Rollup::init();
 
-    let withdraw_udt_2 = CellOutput::new_builder()
-        .capacity(input_ckb.pack())
-        .lock(success_lock_script.clone())
+    // a commit cell with the rollup lock, but not the pinned type id.
+    let other_type = rollup
+        .builder
+        .commit_type
+        .clone()
+        .as_builder()
+        .args(Bytes::from(vec![9u8; 32]).pack())
         .build();
+    let other = output(&rollup.builder.rollup_lock, Some(other_type.clone()));
+    let commit = CommitCell {
+        out_point: rollup.context.create_cell(
+            other,
+            rollup
+                .commit
+                .commit
+                .to_cell_data(rollup.upks.account_size, OP_INIT)
+                .into(),
+        ),
+        ..rollup.commit.clone()
+    };
+    let builder = RollupTxBuilder {
+        commit_type: other_type,
+        ..rollup.builder.clone()
+    };
 
-    let withdraw_outputs_data: Vec<Bytes> = vec![
-        vec![2u8, 2u8, 3u8, 4u8, 5u8, 6u8].into(), // commits & txs
-        vec![8u8, 8u8, 8u8].into(),                // upk
-        40u128.to_le_bytes().to_vec().into(),      // new amount
-        50u128.to_le_bytes().to_vec().into(),      // new amount
-        10u128.to_le_bytes().to_vec().into(),      // new amount
-    ];
+    let tx = builder.post_block(&commit, &empty_block()).unwrap();
+    let err = rollup.verify(tx).unwrap_err();
+    assert_script_error(err, ERROR_VERIFY);
+}
 
-    let tx = TransactionBuilder::default()
-        .inputs(vec![
-            withdraw_commit_input,
-            withdraw_upk_input,
-            withdraw_udt_input,
-        ])
-        .outputs(vec![
-            withdraw_commit,
-            withdraw_upk,
-            withdraw_udt,
-            withdraw_udt_1,
-            withdraw_udt_2,
-        ])
-        .outputs_data(withdraw_outputs_data.pack())
-        .cell_dep(rollup_lock_script_dep)
+#[test]
+fn post_register_block() {
+    let mut rollup = Rollup::init();
+    let block = rollup.register_block(2, &SecretKey(vec![5u8; 32]));
+    let tx = rollup.builder.post_block(&rollup.commit, &block).unwrap();
+    let cycles = rollup.verify(tx).expect("post block");
+    println!("register block cycles: {}", cycles);
+}
+
+#[test]
+fn reject_forged_new_commit() {
+    let mut rollup = Rollup::init();
+    let mut block = rollup.register_block(2, &SecretKey(vec![5u8; 32]));
+    // the shard's new commit misses the register.
+    block.shards[0].new_commit = block.shards[0].commit.clone();
+
+    let tx = rollup.builder.post_block(&rollup.commit, &block).unwrap();
+    let err = rollup.verify(tx).unwrap_err();
+    assert_script_error(err, ERROR_VERIFY);
+}
+
+#[test]
+fn reject_spending_other_vault() {
+    let mut rollup = Rollup::init();
+    let other_type = rollup.vaults[1].udt_type.clone();
+    let stolen = Bytes::from(100u128.to_le_bytes().to_vec());
+    let other_vault = rollup.context.create_cell(
+        output(&rollup.builder.rollup_lock, Some(other_type.clone())),
+        stolen.clone(),
+    );
+    let wallet = UdtCell {
+        out_point: rollup.context.create_cell(
+            output(&rollup.lock, Some(rollup.vaults[0].udt_type.clone())),
+            Bytes::from(0u128.to_le_bytes().to_vec()),
+        ),
+        ..rollup.vaults[0].clone()
+    };
+
+    let tx = rollup
+        .builder
+        .deposit(
+            &rollup.commit,
+            &empty_block(),
+            &rollup.vaults[0],
+            &wallet,
+            &rollup.lock,
+            0,
+        )
+        .unwrap();
+    rollup.verify(tx.clone()).expect("empty deposit");
+
+    // the deposit of token 0 also takes the pool of token 1.
+    let tx = tx
+        .as_advanced_builder()
+        .input(input(other_vault))
+        .output(output(&rollup.lock, Some(other_type)))
+        .output_data(stolen.pack())
         .build();
-    let tx = context.complete_tx(tx);
+    let err = rollup.verify(tx).unwrap_err();
+    assert_script_error(err, ERROR_AMOUNT);
+}
 
-    let cycles = context
-        .verify_tx(&tx, MAX_CYCLES)
-        .expect("pass verification");
-    println!("over withdraw: {}...", cycles);
-    // ----- End Withdraw -----
+#[test]
+fn reject_spending_vault_in_post_block() {
+    let mut rollup = Rollup::init();
+    let other = rollup
+        .context
+        .create_cell(output(&rollup.lock, None), Bytes::new());
+    let tx = rollup
+        .builder
+        .post_block(&rollup.commit, &empty_block())
+        .unwrap();
 
-    println!("all over.");
+    // more inputs than outputs, the pool is after the last output.
+    let tx = tx
+        .as_advanced_builder()
+        .input(input(other))
+        .input(input(rollup.vaults[0].out_point.clone()))
+        .build();
+    let err = rollup.verify(tx).unwrap_err();
+    assert_script_error(err, ERROR_AMOUNT);
 }